@@ -0,0 +1,65 @@
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+
+/// Alignment satisfying O_DIRECT on any backing device with logical blocks of up to 4KiB.
+const ALIGN: usize = 4096;
+
+/// A zeroed byte buffer whose start is aligned for O_DIRECT I/O, which a `Vec<u8>` can't
+/// guarantee. Only the memory is aligned: the offset and length of the I/O must be aligned by
+/// the caller, or the backing filesystem rejects it with EINVAL.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// The buffer owns its memory outright, like a Vec.
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn zeroed(len: usize) -> Self {
+        if len == 0 {
+            // Nothing is allocated for an empty buffer, but the pointer is still aligned.
+            let ptr = NonNull::new(ALIGN as *mut u8).unwrap();
+            return Self { ptr, len };
+        }
+        let layout = Self::layout(len);
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    /// A buffer holding a copy of `data`.
+    pub fn copy_of(data: &[u8]) -> Self {
+        let mut buffer = Self::zeroed(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, ALIGN).unwrap()
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) };
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
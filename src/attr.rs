@@ -0,0 +1,87 @@
+use fuse_mt::FileType;
+
+/// The type of file with mode `mode`, or None for a type FUSE has no name for.
+pub fn filetype_from_mode(mode: libc::mode_t) -> Option<FileType> {
+    match mode & libc::S_IFMT {
+        libc::S_IFDIR => Some(FileType::Directory),
+        libc::S_IFREG => Some(FileType::RegularFile),
+        libc::S_IFLNK => Some(FileType::Symlink),
+        libc::S_IFBLK => Some(FileType::BlockDevice),
+        libc::S_IFCHR => Some(FileType::CharDevice),
+        libc::S_IFIFO => Some(FileType::NamedPipe),
+        libc::S_IFSOCK => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
+/// What a file of type `kind` is, for messages.
+pub fn describe(kind: FileType) -> &'static str {
+    match kind {
+        FileType::Directory => "a directory",
+        FileType::RegularFile => "a regular file",
+        FileType::Symlink => "a symlink",
+        FileType::BlockDevice => "a block device",
+        FileType::CharDevice => "a character device",
+        FileType::NamedPipe => "a FIFO",
+        FileType::Socket => "a socket",
+    }
+}
+
+/// The type of file a directory entry's `d_type` describes, or None where the backing
+/// filesystem didn't say (`DT_UNKNOWN`) and the entry must be statted instead.
+pub fn filetype_from_dirent(d_type: u8) -> Option<FileType> {
+    match d_type {
+        libc::DT_DIR => Some(FileType::Directory),
+        libc::DT_REG => Some(FileType::RegularFile),
+        libc::DT_LNK => Some(FileType::Symlink),
+        libc::DT_BLK => Some(FileType::BlockDevice),
+        libc::DT_CHR => Some(FileType::CharDevice),
+        libc::DT_FIFO => Some(FileType::NamedPipe),
+        libc::DT_SOCK => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [(libc::mode_t, u8, FileType); 7] = [
+        (libc::S_IFDIR, libc::DT_DIR, FileType::Directory),
+        (libc::S_IFREG, libc::DT_REG, FileType::RegularFile),
+        (libc::S_IFLNK, libc::DT_LNK, FileType::Symlink),
+        (libc::S_IFBLK, libc::DT_BLK, FileType::BlockDevice),
+        (libc::S_IFCHR, libc::DT_CHR, FileType::CharDevice),
+        (libc::S_IFIFO, libc::DT_FIFO, FileType::NamedPipe),
+        (libc::S_IFSOCK, libc::DT_SOCK, FileType::Socket),
+    ];
+
+    #[test]
+    fn every_mode_type_has_its_file_type() {
+        for (mode, _, kind) in TYPES {
+            assert_eq!(filetype_from_mode(mode), Some(kind), "{:#o}", mode);
+            // The permission bits play no part.
+            assert_eq!(filetype_from_mode(mode | 0o7777), Some(kind), "{:#o}", mode);
+        }
+    }
+
+    #[test]
+    fn unknown_mode_types_have_none() {
+        assert_eq!(filetype_from_mode(0o170000 & !libc::S_IFMT), None);
+        assert_eq!(filetype_from_mode(0o644), None);
+        assert_eq!(filetype_from_mode(libc::S_IFMT), None);
+    }
+
+    #[test]
+    fn every_dirent_type_has_its_file_type() {
+        for (_, d_type, kind) in TYPES {
+            assert_eq!(filetype_from_dirent(d_type), Some(kind), "{}", d_type);
+        }
+    }
+
+    #[test]
+    fn unknown_dirent_types_have_none() {
+        assert_eq!(filetype_from_dirent(libc::DT_UNKNOWN), None);
+        assert_eq!(filetype_from_dirent(0xff), None);
+    }
+}
@@ -0,0 +1,615 @@
+use glob::Pattern;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use crate::deco::{AuditReply, DecoFS};
+use crate::transform::{ContentTransform, Transform};
+
+/// Every option of a `DecoFS` in one place, checked for conflicts as a whole when it is built.
+/// Each method corresponds to one of `DecoFS`'s `with_` methods, whose documentation describes
+/// the option in full.
+#[derive(Default)]
+pub struct DecoFSBuilder {
+    source: Option<PathBuf>,
+    union_roots: Vec<PathBuf>,
+    prefix: Option<PathBuf>,
+    upper: Option<PathBuf>,
+    ttl: Option<Duration>,
+    read_only: bool,
+    transform: Option<Box<dyn ContentTransform>>,
+    extension_transforms: Vec<(String, Transform)>,
+    hide_patterns: Vec<Pattern>,
+    stat_cache: Option<Duration>,
+    content_cache: Option<(usize, u64)>,
+    write_buffer: Option<usize>,
+    case_insensitive: bool,
+    follow_symlinks: bool,
+    relative_links: bool,
+    sort_entries: bool,
+    dir_cache: bool,
+    watch: bool,
+    direct_io: bool,
+    handle_limit: Option<u64>,
+    read_limit: Option<u64>,
+    readahead: Option<usize>,
+    read_chunk: Option<usize>,
+    fsync_batch: Option<Duration>,
+    op_timeout: Option<Duration>,
+    missing_root_errno: Option<i32>,
+    force_file_mode: Option<u32>,
+    force_dir_mode: Option<u32>,
+    umask: Option<u32>,
+    trash_dir: Option<PathBuf>,
+    uid_map: HashMap<u32, u32>,
+    gid_map: HashMap<u32, u32>,
+    audit: Option<AuditReply>,
+    stats_file: bool,
+    list_info: bool,
+    control_socket: Option<PathBuf>,
+    threads: Option<usize>,
+}
+
+fn conflict(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl DecoFSBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The directory to expose. Required.
+    pub fn source(self, root: PathBuf) -> Self {
+        Self {
+            source: Some(root),
+            ..self
+        }
+    }
+
+    /// A further directory to merge beneath the source; see `DecoFS::with_union`.
+    pub fn union(mut self, root: PathBuf) -> Self {
+        self.union_roots.push(root);
+        self
+    }
+
+    pub fn prefix(self, prefix: PathBuf) -> Self {
+        Self {
+            prefix: Some(prefix),
+            ..self
+        }
+    }
+
+    pub fn upper(self, upper: PathBuf) -> Self {
+        Self {
+            upper: Some(upper),
+            ..self
+        }
+    }
+
+    pub fn ttl(self, ttl: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    pub fn read_only(self, read_only: bool) -> Self {
+        Self { read_only, ..self }
+    }
+
+    pub fn transform(self, transform: Box<dyn ContentTransform>) -> Self {
+        Self {
+            transform: Some(transform),
+            ..self
+        }
+    }
+
+    /// Decode files whose names end `.extension` with `transform`, as well as any already given.
+    pub fn extension_transform(mut self, extension: &str, transform: Transform) -> Self {
+        self.extension_transforms
+            .push((extension.to_string(), transform));
+        self
+    }
+
+    /// Hide entries whose names match `pattern`, as well as any already given.
+    pub fn hide(mut self, pattern: Pattern) -> Self {
+        self.hide_patterns.push(pattern);
+        self
+    }
+
+    pub fn stat_cache(self, ttl: Duration) -> Self {
+        Self {
+            stat_cache: Some(ttl),
+            ..self
+        }
+    }
+
+    pub fn content_cache(self, capacity: usize, max_file: u64) -> Self {
+        Self {
+            content_cache: Some((capacity, max_file)),
+            ..self
+        }
+    }
+
+    pub fn write_buffer(self, limit: usize) -> Self {
+        Self {
+            write_buffer: Some(limit),
+            ..self
+        }
+    }
+
+    pub fn case_insensitive(self, case_insensitive: bool) -> Self {
+        Self {
+            case_insensitive,
+            ..self
+        }
+    }
+
+    pub fn follow_symlinks(self, follow_symlinks: bool) -> Self {
+        Self {
+            follow_symlinks,
+            ..self
+        }
+    }
+
+    pub fn relative_links(self, relative_links: bool) -> Self {
+        Self {
+            relative_links,
+            ..self
+        }
+    }
+
+    pub fn sort_entries(self, sort_entries: bool) -> Self {
+        Self {
+            sort_entries,
+            ..self
+        }
+    }
+
+    pub fn dir_cache(self, dir_cache: bool) -> Self {
+        Self { dir_cache, ..self }
+    }
+
+    pub fn watch(self, watch: bool) -> Self {
+        Self { watch, ..self }
+    }
+
+    pub fn direct_io(self, direct_io: bool) -> Self {
+        Self { direct_io, ..self }
+    }
+
+    pub fn handle_limit(self, limit: u64) -> Self {
+        Self {
+            handle_limit: Some(limit),
+            ..self
+        }
+    }
+
+    pub fn read_limit(self, bytes_per_sec: u64) -> Self {
+        Self {
+            read_limit: Some(bytes_per_sec),
+            ..self
+        }
+    }
+
+    pub fn readahead(self, window: usize) -> Self {
+        Self {
+            readahead: Some(window),
+            ..self
+        }
+    }
+
+    pub fn parallel_reads(self, chunk: usize) -> Self {
+        Self {
+            read_chunk: Some(chunk),
+            ..self
+        }
+    }
+
+    pub fn fsync_batch(self, window: Duration) -> Self {
+        Self {
+            fsync_batch: Some(window),
+            ..self
+        }
+    }
+
+    pub fn op_timeout(self, timeout: Duration) -> Self {
+        Self {
+            op_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn missing_root_errno(self, errno: i32) -> Self {
+        Self {
+            missing_root_errno: Some(errno),
+            ..self
+        }
+    }
+
+    pub fn force_file_mode(self, mode: u32) -> Self {
+        Self {
+            force_file_mode: Some(mode),
+            ..self
+        }
+    }
+
+    pub fn force_dir_mode(self, mode: u32) -> Self {
+        Self {
+            force_dir_mode: Some(mode),
+            ..self
+        }
+    }
+
+    pub fn umask(self, umask: u32) -> Self {
+        Self {
+            umask: Some(umask),
+            ..self
+        }
+    }
+
+    pub fn trash_dir(self, trash: PathBuf) -> Self {
+        Self {
+            trash_dir: Some(trash),
+            ..self
+        }
+    }
+
+    /// Show backing uid `backing` to clients as `client`, as well as any already given.
+    pub fn map_uid(mut self, backing: u32, client: u32) -> Self {
+        self.uid_map.insert(backing, client);
+        self
+    }
+
+    /// Show backing gid `backing` to clients as `client`, as well as any already given.
+    pub fn map_gid(mut self, backing: u32, client: u32) -> Self {
+        self.gid_map.insert(backing, client);
+        self
+    }
+
+    pub fn audit(self, reply: AuditReply) -> Self {
+        Self {
+            audit: Some(reply),
+            ..self
+        }
+    }
+
+    pub fn stats_file(self, stats_file: bool) -> Self {
+        Self { stats_file, ..self }
+    }
+
+    pub fn list_info(self, list_info: bool) -> Self {
+        Self { list_info, ..self }
+    }
+
+    pub fn control_socket(self, path: PathBuf) -> Self {
+        Self {
+            control_socket: Some(path),
+            ..self
+        }
+    }
+
+    /// The number of threads to serve requests with, defaulting to the available parallelism.
+    pub fn threads(self, threads: usize) -> Self {
+        Self {
+            threads: Some(threads),
+            ..self
+        }
+    }
+
+    /// Fail with InvalidInput if options conflict, such as being read-only while changing how
+    /// writes are made, and otherwise as the `DecoFS` constructors and methods do.
+    fn check(&self) -> io::Result<()> {
+        if self.source.is_none() {
+            return Err(conflict("no source directory given"));
+        }
+        if self.follow_symlinks {
+            if self.upper.is_some() {
+                // Copying up a link copies the link, so writes through it would still reach
+                // the file beneath.
+                return Err(conflict("symlinks can't be followed with an upper layer"));
+            }
+            if self.relative_links {
+                return Err(conflict("no symlinks are shown when following them"));
+            }
+        }
+        if self.upper.is_some() && self.trash_dir.is_some() {
+            // Unlinking a file from a lower layer only whites it out, and the file itself stays.
+            return Err(conflict("a trash can't be used with an upper layer"));
+        }
+        if self.readahead.is_some() && self.read_chunk.is_some() {
+            return Err(conflict(
+                "reads through readahead aren't split into parallel reads",
+            ));
+        }
+        if self.read_only {
+            if self.force_file_mode.is_some() || self.force_dir_mode.is_some() {
+                return Err(conflict("forced modes have no effect when read-only"));
+            }
+            if self.umask.is_some() {
+                return Err(conflict("a umask has no effect when read-only"));
+            }
+            if self.trash_dir.is_some() {
+                return Err(conflict("a trash has no effect when read-only"));
+            }
+            if self.write_buffer.is_some() {
+                return Err(conflict("write buffering has no effect when read-only"));
+            }
+            if self.audit.is_some() {
+                return Err(conflict("audit mode has no effect when read-only"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> io::Result<DecoFS> {
+        self.check()?;
+        let source = self.source.unwrap();
+        let fs = match self.ttl {
+            Some(ttl) => DecoFS::with_ttl(source, ttl)?,
+            None => DecoFS::new(source)?,
+        };
+        let mut fs = fs.with_union(self.union_roots)?;
+        if let Some(prefix) = &self.prefix {
+            fs = fs.with_virtual_prefix(prefix)?;
+        }
+        if let Some(upper) = self.upper {
+            fs = fs.with_upper(upper)?;
+        }
+        if self.read_only {
+            fs = fs.with_read_only();
+        }
+        if let Some(transform) = self.transform {
+            fs = fs.with_transform(transform);
+        }
+        for (extension, transform) in self.extension_transforms {
+            fs = fs.with_extension_transform(&extension, transform);
+        }
+        fs = fs.with_hidden(self.hide_patterns);
+        if let Some(ttl) = self.stat_cache {
+            fs = fs.with_stat_cache(ttl);
+        }
+        if let Some((capacity, max_file)) = self.content_cache {
+            fs = fs.with_content_cache(capacity, max_file);
+        }
+        if let Some(limit) = self.write_buffer {
+            fs = fs.with_write_buffer(limit);
+        }
+        if self.case_insensitive {
+            fs = fs.with_case_insensitive_lookup();
+        }
+        if self.follow_symlinks {
+            fs = fs.with_followed_symlinks();
+        }
+        if self.relative_links {
+            fs = fs.with_relative_links();
+        }
+        if self.sort_entries {
+            fs = fs.with_sorted_entries();
+        }
+        if self.dir_cache {
+            fs = fs.with_dir_cache();
+        }
+        if self.watch {
+            fs = fs.with_watcher();
+        }
+        if self.direct_io {
+            fs = fs.with_direct_io();
+        }
+        if let Some(limit) = self.handle_limit {
+            fs = fs.with_handle_limit(limit);
+        }
+        if let Some(bytes_per_sec) = self.read_limit {
+            fs = fs.with_read_limit(bytes_per_sec);
+        }
+        if let Some(window) = self.readahead {
+            fs = fs.with_readahead(window);
+        }
+        if let Some(chunk) = self.read_chunk {
+            fs = fs.with_parallel_reads(chunk);
+        }
+        if let Some(window) = self.fsync_batch {
+            fs = fs.with_fsync_batching(window);
+        }
+        if let Some(timeout) = self.op_timeout {
+            fs = fs.with_op_timeout(timeout);
+        }
+        if let Some(mode) = self.force_file_mode {
+            fs = fs.with_force_file_mode(mode);
+        }
+        if let Some(mode) = self.force_dir_mode {
+            fs = fs.with_force_dir_mode(mode);
+        }
+        if let Some(umask) = self.umask {
+            fs = fs.with_umask(umask);
+        }
+        if let Some(trash) = &self.trash_dir {
+            fs = fs.with_trash_dir(trash)?;
+        }
+        if !self.uid_map.is_empty() || !self.gid_map.is_empty() {
+            fs = fs.with_id_maps(self.uid_map, self.gid_map);
+        }
+        if let Some(reply) = self.audit {
+            fs = fs.with_audit(reply);
+        }
+        if self.stats_file {
+            fs = fs.with_stats_file();
+        }
+        if self.list_info {
+            fs = fs.with_info_listed();
+        }
+        if let Some(path) = self.control_socket {
+            fs = fs.with_control_socket(path);
+        }
+        if let Some(errno) = self.missing_root_errno {
+            fs = fs.with_missing_root_errno(errno);
+        }
+        let threads = self
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        Ok(fs.with_threads(threads))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    /// A fresh directory for one test, removed by the test when it is done.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("decofs-builder-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A directory within `dir` to use as a source root or layer.
+    fn subdir(dir: &Path, name: &str) -> PathBuf {
+        let subdir = dir.join(name);
+        fs::create_dir_all(&subdir).unwrap();
+        subdir
+    }
+
+    fn rejection(builder: DecoFSBuilder) -> String {
+        match builder.build() {
+            Ok(_) => panic!("conflicting options were accepted"),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                e.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn builds_with_every_option_applied() {
+        let dir = scratch_dir("every-option");
+        let (source, upper) = (subdir(&dir, "source"), subdir(&dir, "upper"));
+        let fs = DecoFSBuilder::new()
+            .source(source.clone())
+            .upper(upper.clone())
+            .ttl(Duration::from_millis(250))
+            .hide(Pattern::new("*.tmp").unwrap())
+            .stat_cache(Duration::from_secs(1))
+            .content_cache(1 << 20, 4096)
+            .write_buffer(8192)
+            .case_insensitive(true)
+            .watch(true)
+            .direct_io(true)
+            .dir_cache(true)
+            .handle_limit(7)
+            .force_file_mode(0o640)
+            .force_dir_mode(0o750)
+            .umask(0o022)
+            .stats_file(true)
+            .threads(3)
+            .build()
+            .unwrap();
+        let info = fs.info_text();
+        let lines = info.lines().collect::<Vec<_>>();
+        for expected in [
+            format!("source: {}", fs::canonicalize(&source).unwrap().display()),
+            format!("upper: {}", fs::canonicalize(&upper).unwrap().display()),
+            "mode: overlay".to_string(),
+            "ttl: 250ms".to_string(),
+            "threads: 3".to_string(),
+            "hide: *.tmp".to_string(),
+            "stat-cache: on".to_string(),
+            "content-cache: on".to_string(),
+            "write-buffer: on".to_string(),
+            "case-insensitive: on".to_string(),
+            "watch: on".to_string(),
+            "direct-io: on".to_string(),
+            "dir-cache: on".to_string(),
+            "max-handles: 7".to_string(),
+            "file-mode: 640".to_string(),
+            "dir-mode: 750".to_string(),
+            "umask: 022".to_string(),
+            "stats-file: on".to_string(),
+        ] {
+            assert!(
+                lines.contains(&expected.as_str()),
+                "{:?} not in {:?}",
+                expected,
+                lines
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn builds_read_only() {
+        let dir = scratch_dir("read-only");
+        let fs = DecoFSBuilder::new()
+            .source(dir.clone())
+            .read_only(true)
+            .build()
+            .unwrap();
+        assert!(fs.info_text().lines().any(|line| line == "mode: read-only"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn needs_a_source() {
+        assert_eq!(rejection(DecoFSBuilder::new()), "no source directory given");
+    }
+
+    #[test]
+    fn rejects_conflicting_options() {
+        let dir = scratch_dir("conflicts");
+        let source = || DecoFSBuilder::new().source(dir.clone());
+        let read_only = || source().read_only(true);
+        let cases = [
+            (
+                source().follow_symlinks(true).upper(subdir(&dir, "upper")),
+                "symlinks can't be followed with an upper layer",
+            ),
+            (
+                source().follow_symlinks(true).relative_links(true),
+                "no symlinks are shown when following them",
+            ),
+            (
+                source()
+                    .upper(subdir(&dir, "upper"))
+                    .trash_dir(subdir(&dir, "trash")),
+                "a trash can't be used with an upper layer",
+            ),
+            (
+                source().readahead(1 << 20).parallel_reads(1 << 16),
+                "reads through readahead aren't split into parallel reads",
+            ),
+            (
+                read_only().force_file_mode(0o644),
+                "forced modes have no effect when read-only",
+            ),
+            (
+                read_only().force_dir_mode(0o755),
+                "forced modes have no effect when read-only",
+            ),
+            (
+                read_only().umask(0o022),
+                "a umask has no effect when read-only",
+            ),
+            (
+                read_only().trash_dir(subdir(&dir, "trash")),
+                "a trash has no effect when read-only",
+            ),
+            (
+                read_only().write_buffer(8192),
+                "write buffering has no effect when read-only",
+            ),
+            (
+                read_only().audit(AuditReply::Succeed),
+                "audit mode has no effect when read-only",
+            ),
+        ];
+        for (builder, message) in cases {
+            assert_eq!(rejection(builder), message);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
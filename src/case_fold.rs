@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CAPACITY: usize = 1024;
+
+/// Finds entries whose names differ from the one looked up only by case, remembering directory
+/// listings briefly so that a run of lookups in one directory doesn't rescan it every time.
+pub struct CaseFolder {
+    ttl: Duration,
+    listings: Mutex<HashMap<PathBuf, (Instant, Vec<OsString>)>>,
+}
+
+impl CaseFolder {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            listings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn fold(name: &OsStr) -> String {
+        name.to_string_lossy().to_lowercase()
+    }
+
+    /// The name of the entry in the backing directory `dir` matching `name` case-insensitively.
+    /// Where several match, the one sorting first byte-wise wins, so the choice is at least
+    /// consistent between lookups.
+    pub fn find(&self, dir: &Path, name: &OsStr) -> Option<OsString> {
+        let mut listings = self.listings.lock().unwrap();
+        let fresh = matches!(listings.get(dir), Some((listed, _)) if listed.elapsed() < self.ttl);
+        if !fresh {
+            let mut names = fs::read_dir(dir)
+                .ok()?
+                .filter_map(|entry| entry.ok().map(|entry| entry.file_name()))
+                .collect::<Vec<_>>();
+            names.sort();
+            if listings.len() >= CAPACITY {
+                let ttl = self.ttl;
+                listings.retain(|_, (listed, _)| listed.elapsed() < ttl);
+            }
+            if listings.len() >= CAPACITY {
+                listings.clear();
+            }
+            listings.insert(dir.to_path_buf(), (Instant::now(), names));
+        }
+
+        let wanted = Self::fold(name);
+        let (_, names) = &listings[dir];
+        names
+            .iter()
+            .find(|candidate| Self::fold(candidate) == wanted)
+            .cloned()
+    }
+
+    /// Forget the listing of the backing directory `dir`, after an entry is added to or removed
+    /// from it.
+    pub fn invalidate(&self, dir: &Path) {
+        self.listings.lock().unwrap().remove(dir);
+    }
+
+    /// Forget every listing, for when the source tree has been changed other than through the
+    /// mount.
+    pub fn clear(&self) {
+        self.listings.lock().unwrap().clear();
+    }
+}
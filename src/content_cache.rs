@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+struct Entry {
+    mtime: SystemTime,
+    size: u64,
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+struct Entries {
+    by_path: HashMap<PathBuf, Entry>,
+    total: usize,
+    clock: u64,
+}
+
+/// The whole content of small files, keyed on real path, bounded in total size and evicting the
+/// least recently used file first.
+///
+/// An entry is only known to be current as of the last `check`: changes made directly to the
+/// source tree are not seen until its attributes are next queried.
+pub struct ContentCache {
+    capacity: usize,
+    max_file: u64,
+    entries: Mutex<Entries>,
+}
+
+impl ContentCache {
+    /// A cache holding up to `capacity` bytes in all, of files no larger than `max_file`.
+    pub fn new(capacity: usize, max_file: u64) -> Self {
+        Self {
+            capacity,
+            max_file,
+            entries: Mutex::new(Entries {
+                by_path: HashMap::new(),
+                total: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Whether a file of `size` bytes is small enough to be cached.
+    pub fn admits(&self, size: u64) -> bool {
+        size <= self.max_file && size as usize <= self.capacity
+    }
+
+    pub fn get(&self, real: &Path) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.by_path.get_mut(real)?;
+        entry.last_used = clock;
+        Some(entry.data.clone())
+    }
+
+    /// Cache `data` as the content of `real` when it had modification time `mtime`.
+    pub fn insert(&self, real: PathBuf, mtime: SystemTime, data: Vec<u8>) -> Arc<Vec<u8>> {
+        let data = Arc::new(data);
+        if !self.admits(data.len() as u64) {
+            return data;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.by_path.remove(&real) {
+            entries.total -= old.data.len();
+        }
+        while entries.total + data.len() > self.capacity {
+            let oldest = entries
+                .by_path
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(oldest) => {
+                    let entry = entries.by_path.remove(&oldest).unwrap();
+                    entries.total -= entry.data.len();
+                }
+                None => break,
+            }
+        }
+        entries.clock += 1;
+        entries.total += data.len();
+        let entry = Entry {
+            mtime,
+            size: data.len() as u64,
+            data: data.clone(),
+            last_used: entries.clock,
+        };
+        entries.by_path.insert(real, entry);
+        data
+    }
+
+    /// Drop the entry for `real` if the file no longer has modification time `mtime` and size
+    /// `size`, meaning it has been changed since it was cached.
+    pub fn check(&self, real: &Path, mtime: SystemTime, size: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        let stale = matches!(entries.by_path.get(real),
+            Some(entry) if entry.mtime != mtime || entry.size != size);
+        if stale {
+            let entry = entries.by_path.remove(real).unwrap();
+            entries.total -= entry.data.len();
+        }
+    }
+
+    /// Forget `real` and everything beneath it.
+    pub fn invalidate_tree(&self, real: &Path) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut freed = 0;
+        entries.by_path.retain(|path, entry| {
+            let keep = !path.starts_with(real);
+            if !keep {
+                freed += entry.data.len();
+            }
+            keep
+        });
+        entries.total -= freed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn content(cache: &ContentCache, path: &str) -> Option<Vec<u8>> {
+        cache.get(Path::new(path)).map(|data| data.to_vec())
+    }
+
+    #[test]
+    fn only_small_enough_files_are_admitted() {
+        let cache = ContentCache::new(10, 4);
+        assert!(cache.admits(4));
+        assert!(!cache.admits(5));
+        cache.insert("/big".into(), SystemTime::UNIX_EPOCH, vec![0; 5]);
+        assert_eq!(content(&cache, "/big"), None);
+        assert!(!ContentCache::new(3, 4).admits(4));
+    }
+
+    #[test]
+    fn the_least_recently_used_file_is_evicted_first() {
+        let cache = ContentCache::new(6, 4);
+        let mtime = SystemTime::UNIX_EPOCH;
+        cache.insert("/a".into(), mtime, b"aa".to_vec());
+        cache.insert("/b".into(), mtime, b"bb".to_vec());
+        cache.insert("/c".into(), mtime, b"cc".to_vec());
+        assert!(content(&cache, "/a").is_some());
+        cache.insert("/d".into(), mtime, b"dd".to_vec());
+        assert_eq!(content(&cache, "/b"), None);
+        for path in ["/a", "/c", "/d"] {
+            assert!(content(&cache, path).is_some(), "{}", path);
+        }
+        // Replacing an entry frees its old size first.
+        cache.insert("/a".into(), mtime, b"AA".to_vec());
+        assert_eq!(content(&cache, "/a").unwrap(), b"AA");
+        assert!(content(&cache, "/c").is_some());
+    }
+
+    #[test]
+    fn changed_files_are_dropped_when_checked() {
+        let cache = ContentCache::new(64, 64);
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        cache.insert("/file".into(), mtime, b"old".to_vec());
+        cache.check(Path::new("/file"), mtime, 3);
+        assert_eq!(content(&cache, "/file").unwrap(), b"old");
+        cache.check(Path::new("/file"), mtime, 4);
+        assert_eq!(content(&cache, "/file"), None);
+
+        cache.insert("/file".into(), mtime, b"old".to_vec());
+        cache.check(Path::new("/file"), mtime + Duration::from_nanos(1), 3);
+        assert_eq!(content(&cache, "/file"), None);
+    }
+
+    #[test]
+    fn invalidating_a_tree_frees_its_space() {
+        let cache = ContentCache::new(4, 4);
+        let mtime = SystemTime::UNIX_EPOCH;
+        cache.insert("/dir/a".into(), mtime, b"aa".to_vec());
+        cache.insert("/dir/b".into(), mtime, b"bb".to_vec());
+        cache.invalidate_tree(Path::new("/dir"));
+        assert_eq!(content(&cache, "/dir/a"), None);
+        cache.insert("/c".into(), mtime, b"cc".to_vec());
+        cache.insert("/d".into(), mtime, b"dd".to_vec());
+        assert!(content(&cache, "/c").is_some());
+        assert!(content(&cache, "/d").is_some());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+/// Answers a command, given as the words of its line, with the lines of its reply or a
+/// message saying why it failed.
+pub type Handler = dyn Fn(&[&str]) -> Result<String, String> + Send + Sync;
+
+/// Bind a socket at `path`, replacing one left behind by an earlier mount that no one is
+/// listening on any more. Anything else already there is left alone, failing with EADDRINUSE.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            let stale = fs::symlink_metadata(path)?.file_type().is_socket()
+                && UnixStream::connect(path)
+                    .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+            if !stale {
+                return Err(e);
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Answer the commands sent on `stream`, one a line, until the client hangs up. Each reply is
+/// the command's output, if any, followed by a line saying `ok` or `error: ` and why.
+fn serve(stream: UnixStream, handler: &Handler) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+        debug!("control: {}", line);
+        match handler(&words) {
+            Ok(output) => writeln!(writer, "{}ok", output)?,
+            Err(message) => writeln!(writer, "error: {}", message)?,
+        }
+    }
+    Ok(())
+}
+
+/// Listen for connections on a Unix socket at `path` from a background thread, serving each
+/// from a thread of its own so a client left connected doesn't hold up the rest.
+pub fn spawn(path: &Path, handler: Arc<Handler>) -> io::Result<()> {
+    let listener = bind(path)?;
+    info!("control socket listening on {:?}", path);
+    thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("control: {}", e);
+                        continue;
+                    }
+                };
+                let handler = handler.clone();
+                let spawned =
+                    thread::Builder::new()
+                        .name("control-client".into())
+                        .spawn(move || {
+                            if let Err(e) = serve(stream, &*handler) {
+                                warn!("control: {}", e);
+                            }
+                        });
+                if let Err(e) = spawned {
+                    warn!("control: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -1,246 +1,3331 @@
+use chrono::Local;
 use fuse_mt::{
-    CallbackResult, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultEmpty,
-    ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultStatfs, Statfs,
+    CallbackResult, CreatedEntry, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo,
+    ResultCreate, ResultData, ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultSlice,
+    ResultStatfs, ResultWrite, ResultXattr, Statfs, Xattr,
 };
+use glob::Pattern;
 use libc::ENOENT;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::ffi::{CStr, OsStr, OsString};
+use std::fmt;
 use std::fs;
-use std::io::{self, Read, Seek, SeekFrom};
-use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::aligned_buffer::AlignedBuffer;
+use crate::attr;
+use crate::case_fold::CaseFolder;
+use crate::content_cache::ContentCache;
+use crate::control;
+use crate::fsync_batch::FsyncBatcher;
+use crate::id_map::IdMap;
 use crate::libc_wrapper;
+use crate::overlay;
+use crate::rate_limit::RateLimiter;
+use crate::readahead::Readahead;
+use crate::root_monitor::RootMonitor;
+use crate::stat_cache::StatCache;
+use crate::stats::{Counters, Op, Stats};
+use crate::transform::{self, ContentTransform, FileTransform, Transform};
 use crate::unmanaged_file;
+use crate::watcher;
+use crate::write_buffer::WriteBuffers;
 
 static TTL: Duration = Duration::from_secs(1);
+const STAT_CACHE_CAPACITY: usize = 16384;
+/// Most files with whole-file transforms whose decoded size is remembered, the least recently
+/// used being forgotten first.
+const DECODED_CAPACITY: usize = 1024;
+/// Where the operation counters are served from, when enabled.
+const STATS_PATH: &str = "/.decofs-stats";
+/// Where the description of the mount is served from.
+const INFO_PATH: &str = "/.decofs-info";
+/// Handles for generated files are allocated from here up, well clear of any file descriptor.
+const VIRTUAL_FH_BASE: u64 = 1 << 63;
+/// Most calls to the backing store that may be running at once under an operation timeout,
+/// counting those that timed out but haven't returned yet.
+const MAX_TIMED_OPS: usize = 256;
+/// Most preads a single read is split into when reads are made in parallel.
+const MAX_PARALLEL_READS: usize = 8;
+/// The open reply flag telling the kernel to bypass its page cache for a file, passing each read
+/// and write through as the client made it.
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+/// The open reply flags telling the kernel to keep what it has cached of a file across opens,
+/// and that it may cache a directory's entries.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+const FOPEN_CACHE_DIR: u32 = 1 << 3;
 
+/// The absolute, symlink-free form of `root`, which must be an existing directory. Otherwise
+/// the error says what was found instead, so that a mistyped path fails before mounting rather
+/// than giving a mount whose every operation fails.
+fn canonical_root(root: &Path) -> io::Result<PathBuf> {
+    let canonical = fs::canonicalize(root).map_err(|e| {
+        let message = match fs::symlink_metadata(root) {
+            Ok(meta) if meta.file_type().is_symlink() => format!(
+                "source root {:?} is a symlink to {:?}, which can't be resolved: {}",
+                root,
+                fs::read_link(root).unwrap_or_default(),
+                e
+            ),
+            _ => format!("source root {:?}: {}", root, e),
+        };
+        io::Error::new(e.kind(), message)
+    })?;
+    let meta = fs::metadata(&canonical)?;
+    if !meta.is_dir() {
+        let found = attr::filetype_from_mode(meta.mode()).map_or("something else", attr::describe);
+        let resolved = if canonical != root {
+            format!(" (resolved to {:?})", canonical)
+        } else {
+            String::new()
+        };
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!(
+                "source root {:?}{} is {}, not a directory",
+                root, resolved, found
+            ),
+        ));
+    }
+    Ok(canonical)
+}
+
+/// The path from the directory `dir` to `target`, both absolute, through their deepest common
+/// ancestor.
+fn relative_to(dir: &Path, target: &Path) -> PathBuf {
+    let dir: Vec<_> = dir.components().collect();
+    let target: Vec<_> = target.components().collect();
+    let common = dir.iter().zip(&target).take_while(|(a, b)| a == b).count();
+    let mut relative: PathBuf = dir[common..].iter().map(|_| Component::ParentDir).collect();
+    relative.extend(&target[common..]);
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
+}
+
+/// `DecoFS::invalidate_entry`, for where only the caches are to hand.
+fn invalidate_entry(
+    stat_cache: Option<&StatCache>,
+    content_cache: Option<&ContentCache>,
+    real: &Path,
+) {
+    if let Some(cache) = stat_cache {
+        cache.invalidate_tree(real);
+        if let Some(parent) = real.parent() {
+            cache.invalidate(parent);
+        }
+    }
+    if let Some(cache) = content_cache {
+        cache.invalidate_tree(real);
+    }
+}
+
+/// The errno to report for `e`. Errors that didn't come from the OS (such as a transform's
+/// decoding failing) are mapped from their kind, defaulting to EIO rather than anything that could be
+/// mistaken for a real result like ENOENT.
+fn errno_of(e: &io::Error) -> libc::c_int {
+    if let Some(errno) = e.raw_os_error() {
+        return errno;
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        io::ErrorKind::AlreadyExists => libc::EEXIST,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => libc::EINVAL,
+        io::ErrorKind::Interrupted => libc::EINTR,
+        io::ErrorKind::WouldBlock => libc::EAGAIN,
+        io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        io::ErrorKind::Unsupported => libc::ENOTSUP,
+        io::ErrorKind::NotADirectory => libc::ENOTDIR,
+        io::ErrorKind::IsADirectory => libc::EISDIR,
+        io::ErrorKind::DirectoryNotEmpty => libc::ENOTEMPTY,
+        io::ErrorKind::ReadOnlyFilesystem => libc::EROFS,
+        io::ErrorKind::CrossesDevices => libc::EXDEV,
+        io::ErrorKind::StorageFull => libc::ENOSPC,
+        io::ErrorKind::FileTooLarge => libc::EFBIG,
+        io::ErrorKind::InvalidFilename => libc::EINVAL,
+        io::ErrorKind::OutOfMemory => libc::ENOMEM,
+        _ => libc::EIO,
+    }
+}
+
+/// How mutations are answered in audit mode, where they are logged but not carried out.
+pub enum AuditReply {
+    /// Report success, so the client carries on as if its changes had been made.
+    Succeed,
+    /// Fail with `EROFS`, as if mounted read-only.
+    ReadOnly,
+}
+
+/// A file with a whole-file transform, as last decoded: the modification time and size of the
+/// backing file then, its decoded size, the decoded content while any handle holds it, and
+/// when it was last asked for.
+struct Decoded {
+    mtime: SystemTime,
+    size: u64,
+    len: u64,
+    content: Weak<Vec<u8>>,
+    last_used: Instant,
+}
+
+/// A `FilesystemMT` passing requests through to one or more source directories.
+///
+/// Everything is keyed on path: fuse_mt keeps the inode table, answers the kernel's `forget`s
+/// itself and never passes them on, so nothing here lives as long as an inode. State for an
+/// open handle is dropped when it is released. The caches are bounded instead: attributes to
+/// `STAT_CACHE_CAPACITY` paths and content to its configured size, each entry going when it
+/// expires, is invalidated or is evicted to make room, so looking up ever more paths can't grow
+/// them without limit.
 pub struct DecoFS {
-    sourceroot: PathBuf,
+    sourceroots: Vec<PathBuf>,
+    /// Whether the first source root is a writable upper layer over the others.
+    overlay: bool,
+    ttl: Duration,
+    read_only: bool,
+    transform: Box<dyn ContentTransform>,
+    /// Transforms used instead of `transform` for files with particular extensions.
+    extension_transforms: HashMap<OsString, Transform>,
+    /// What is known of files with whole-file transforms, by backing path, for up to
+    /// `DECODED_CAPACITY` files.
+    decoded: Mutex<HashMap<PathBuf, Decoded>>,
+    hide_patterns: Vec<Pattern>,
+    /// The caches are shared with the watcher thread, if there is one.
+    stat_cache: Option<Arc<StatCache>>,
+    content_cache: Option<Arc<ContentCache>>,
+    watch: bool,
+    /// Handles opened with O_APPEND, whose writes must go to the end of the file.
+    append_handles: RwLock<HashSet<u64>>,
+    /// Handles opened with O_DIRECT, whose I/O needs aligned buffers.
+    direct_handles: RwLock<HashSet<u64>>,
+    /// Handles opened for writing, whose data is synced each time they are closed.
+    writable_handles: RwLock<HashSet<u64>>,
+    direct_io: bool,
+    /// The number of backing files and directories currently open for clients.
+    open_handles: AtomicU64,
+    handle_limit: Option<u64>,
+    read_limit: Option<RateLimiter>,
+    readahead: Option<Arc<Readahead>>,
+    /// The smallest piece of a read made alongside the rest, if reads are split up.
+    read_chunk: Option<usize>,
+    fsync_batcher: Option<FsyncBatcher>,
+    op_timeout: Option<Duration>,
+    root_monitor: Option<RootMonitor>,
+    /// Calls to the backing store running under the operation timeout.
+    timed_ops: Arc<AtomicUsize>,
+    write_buffers: Option<WriteBuffers>,
+    case_folder: Option<Arc<CaseFolder>>,
+    relative_links: bool,
+    sort_entries: bool,
+    follow_symlinks: bool,
+    dir_cache: bool,
+    force_file_mode: Option<u32>,
+    force_dir_mode: Option<u32>,
+    umask: Option<u32>,
+    trash_dir: Option<PathBuf>,
+    audit: Option<AuditReply>,
+    uid_map: IdMap,
+    gid_map: IdMap,
+    /// The counters are shared with the control socket, if there is one.
+    stats: Arc<Counters>,
+    stats_file: bool,
+    control_socket: Option<PathBuf>,
+    info_listed: bool,
+    /// Content of the generated files currently open, by handle.
+    virtual_handles: Mutex<HashMap<u64, Arc<Vec<u8>>>>,
+    next_virtual_fh: AtomicU64,
+    threads: usize,
 }
 
 impl DecoFS {
-    pub fn new(sourceroot: PathBuf) -> Self {
-        Self { sourceroot }
+    /// Create a filesystem exposing `sourceroot`, which is resolved to an absolute path up front
+    /// so that the mount doesn't depend on the working directory. Fails if it is not an existing
+    /// directory.
+    pub fn new(sourceroot: PathBuf) -> io::Result<Self> {
+        Self::with_ttl(sourceroot, TTL)
     }
 
-    fn real_path(&self, partial: &Path) -> PathBuf {
-        PathBuf::from(&self.sourceroot).join(partial.strip_prefix("/").unwrap())
+    /// Create a filesystem whose attributes and entries are cached by the kernel for `ttl`.
+    ///
+    /// A longer TTL means fewer getattr round-trips, at the cost of changes made directly to the
+    /// source tree taking longer to become visible through the mount.
+    pub fn with_ttl(sourceroot: PathBuf, ttl: Duration) -> io::Result<Self> {
+        Ok(Self {
+            sourceroots: vec![canonical_root(&sourceroot)?],
+            overlay: false,
+            ttl,
+            read_only: false,
+            transform: Box::new(transform::Identity),
+            extension_transforms: HashMap::new(),
+            decoded: Mutex::new(HashMap::new()),
+            hide_patterns: vec![],
+            stat_cache: None,
+            content_cache: None,
+            watch: false,
+            append_handles: RwLock::new(HashSet::new()),
+            direct_handles: RwLock::new(HashSet::new()),
+            writable_handles: RwLock::new(HashSet::new()),
+            direct_io: false,
+            open_handles: AtomicU64::new(0),
+            handle_limit: None,
+            read_limit: None,
+            readahead: None,
+            read_chunk: None,
+            fsync_batcher: None,
+            op_timeout: None,
+            root_monitor: None,
+            timed_ops: Arc::new(AtomicUsize::new(0)),
+            write_buffers: None,
+            case_folder: None,
+            relative_links: false,
+            sort_entries: false,
+            follow_symlinks: false,
+            dir_cache: false,
+            force_file_mode: None,
+            force_dir_mode: None,
+            umask: None,
+            trash_dir: None,
+            audit: None,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            stats: Arc::new(Counters::new()),
+            stats_file: false,
+            control_socket: None,
+            info_listed: false,
+            virtual_handles: Mutex::new(HashMap::new()),
+            next_virtual_fh: AtomicU64::new(VIRTUAL_FH_BASE),
+            threads: 1,
+        })
     }
 
-    fn statfs_real(&self, path: &Path) -> io::Result<libc::statfs> {
-        let real = self.real_path(path);
-        libc_wrapper::statfs(&real)
+    /// Reject every mutating operation with `EROFS`, regardless of the options the filesystem is
+    /// mounted with.
+    pub fn with_read_only(self) -> Self {
+        Self {
+            read_only: true,
+            ..self
+        }
     }
 
-    fn stat_real(&self, path: &Path) -> io::Result<FileAttr> {
-        let real = self.real_path(path);
-        let stat = libc_wrapper::lstat(&real)?;
-        Ok(Self::stat_to_fuse(stat))
+    /// Serve requests with `threads` threads, once passed to `FuseMT::new` as `threads()`.
+    pub fn with_threads(self, threads: usize) -> Self {
+        Self { threads, ..self }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Decode file content with `transform` as it is read. Writes are passed through untouched.
+    pub fn with_transform(self, transform: Box<dyn ContentTransform>) -> Self {
+        Self { transform, ..self }
+    }
+
+    /// Decode the content of files whose names end `.extension` with `transform`, in place of
+    /// any given to `with_transform`.
+    ///
+    /// Block transforms decode each read as it is made. Whole-file transforms, such as gzip
+    /// decompression, can't: decoding data at one offset needs everything before it. Such
+    /// files are decoded in full when opened, and reads served from the result, which is held
+    /// in memory until the file is released. They can only be opened read-only, and report
+    /// their decoded size, which is worked out by decoding them in full the first time it is
+    /// asked for. The size is remembered until the backing file changes, for the
+    /// `DECODED_CAPACITY` files most recently used, but the content decoded to find it isn't.
+    pub fn with_extension_transform(mut self, extension: &str, transform: Transform) -> Self {
+        self.extension_transforms
+            .insert(OsString::from(extension), transform);
+        self
     }
 
-    fn mode_to_filetype(mode: libc::mode_t) -> FileType {
-        match mode & libc::S_IFMT {
-            libc::S_IFDIR => FileType::Directory,
-            libc::S_IFREG => FileType::RegularFile,
-            libc::S_IFLNK => FileType::Symlink,
-            libc::S_IFBLK => FileType::BlockDevice,
-            libc::S_IFCHR => FileType::CharDevice,
-            libc::S_IFIFO => FileType::NamedPipe,
-            libc::S_IFSOCK => FileType::Socket,
-            _ => {
-                panic!("unknown file type");
+    /// The transform to decode reads from `path` with, a block at a time.
+    fn block_transform(&self, path: &Path) -> &dyn ContentTransform {
+        match path
+            .extension()
+            .and_then(|ext| self.extension_transforms.get(ext))
+        {
+            Some(Transform::Blocks(transform)) => transform.as_ref(),
+            _ => self.transform.as_ref(),
+        }
+    }
+
+    /// The transform to decode `path` with as a whole, if it has one.
+    fn file_transform(&self, path: &Path) -> Option<&dyn FileTransform> {
+        match self.extension_transforms.get(path.extension()?)? {
+            Transform::WholeFile(transform) => Some(transform.as_ref()),
+            Transform::Blocks(_) => None,
+        }
+    }
+
+    /// The content of `path`, whose backing file is `real`, decoded with `transform`. Content
+    /// that can't be decoded fails with EIO.
+    fn decode_file(
+        &self,
+        transform: &dyn FileTransform,
+        path: &Path,
+        real: &Path,
+    ) -> io::Result<Vec<u8>> {
+        let fh = self.open_real(real, libc::O_RDONLY, 0)?;
+        let mut data = vec![];
+        let read = unsafe { unmanaged_file::UnmanagedFile::new(fh) }
+            .and_then(|mut file| file.read_to_end(&mut data));
+        let _ = libc_wrapper::close(fh);
+        read?;
+        transform.decode_file(path, &data).map_err(|e| {
+            error!("decode {:?}: {}", real, e);
+            match e.kind() {
+                io::ErrorKind::InvalidData => io::Error::from_raw_os_error(libc::EIO),
+                _ => e,
+            }
+        })
+    }
+
+    /// The content of `path`, whose attributes are `attr`, decoded with `transform`. Content
+    /// already decoded for a handle that is still open is shared if the backing file hasn't
+    /// changed since. Otherwise the file is decoded again: content is only held while a handle
+    /// is open on it, so it isn't kept after the lookup that first worked out its size.
+    fn decoded_content(
+        &self,
+        transform: &dyn FileTransform,
+        path: &Path,
+        attr: &FileAttr,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        let real = self.real_path(path);
+        if let Some((_, content)) = self.current_decoded(&real, attr) {
+            if let Some(content) = content.upgrade() {
+                return Ok(content);
+            }
+        }
+        let content = Arc::new(self.decode_file(transform, path, &real)?);
+        let mut decoded = self.decoded.lock().unwrap();
+        if decoded.len() >= DECODED_CAPACITY && !decoded.contains_key(&real) {
+            let oldest = decoded
+                .iter()
+                .min_by_key(|(_, decoded)| decoded.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                decoded.remove(&oldest);
             }
         }
+        decoded.insert(
+            real,
+            Decoded {
+                mtime: attr.mtime,
+                size: attr.size,
+                len: content.len() as u64,
+                content: Arc::downgrade(&content),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(content)
     }
 
-    fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
-        Statfs {
-            blocks: statfs.f_blocks,
-            bfree: statfs.f_bfree,
-            bavail: statfs.f_bavail,
-            files: statfs.f_files,
-            ffree: statfs.f_ffree,
-            bsize: statfs.f_bsize as u32,
-            namelen: statfs.f_namelen as u32,
-            frsize: statfs.f_frsize as u32,
+    /// The decoded size of the backing file `real` and its content, if a handle still holds it,
+    /// as last decoded, provided the file still has the attributes `attr` it had then.
+    fn current_decoded(&self, real: &Path, attr: &FileAttr) -> Option<(u64, Weak<Vec<u8>>)> {
+        let mut decoded = self.decoded.lock().unwrap();
+        let entry = decoded.get_mut(real)?;
+        if (entry.mtime, entry.size) != (attr.mtime, attr.size) {
+            return None;
         }
+        entry.last_used = Instant::now();
+        Some((entry.len, entry.content.clone()))
     }
 
-    fn stat_to_fuse(stat: libc::stat) -> FileAttr {
-        // st_mode encodes both the kind and the permissions
-        let kind = DecoFS::mode_to_filetype(stat.st_mode);
-        let perm = (stat.st_mode & 0o7777) as u16;
+    /// `attr`, the attributes of `path`, with its size as decoded by `transform`.
+    fn decoded_attr(
+        &self,
+        transform: &dyn FileTransform,
+        path: &Path,
+        mut attr: FileAttr,
+    ) -> io::Result<FileAttr> {
+        if attr.kind != FileType::RegularFile {
+            return Ok(attr);
+        }
+        if let Some((len, _)) = self.current_decoded(&self.real_path(path), &attr) {
+            attr.size = len;
+            return Ok(attr);
+        }
+        attr.size = self.decoded_content(transform, path, &attr)?.len() as u64;
+        Ok(attr)
+    }
 
-        FileAttr {
-            size: stat.st_size as u64,
-            blocks: stat.st_blocks as u64,
-            atime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_atime.try_into().unwrap())
-                + Duration::from_nanos(stat.st_atime_nsec.try_into().unwrap()),
-            mtime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_mtime.try_into().unwrap())
-                + Duration::from_nanos(stat.st_mtime_nsec.try_into().unwrap()),
-            ctime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_ctime.try_into().unwrap())
-                + Duration::from_nanos(stat.st_ctime_nsec.try_into().unwrap()),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind,
-            perm,
-            nlink: stat.st_nlink as u32,
-            uid: stat.st_uid,
-            gid: stat.st_gid,
-            rdev: stat.st_rdev as u32,
-            flags: 0,
+    /// Merge `roots` beneath the existing source root(s), as a union mount.
+    ///
+    /// A path resolves to the first root in which it exists, so earlier roots shadow later ones;
+    /// anything newly created goes to the first root holding its parent directory. As with the
+    /// first root, each must be an existing directory.
+    pub fn with_union(mut self, roots: Vec<PathBuf>) -> io::Result<Self> {
+        for root in roots {
+            self.sourceroots.push(canonical_root(&root)?);
         }
+        Ok(self)
     }
 
-    fn stat_to_filetype(stat: &libc::stat) -> FileType {
-        Self::mode_to_filetype(stat.st_mode)
+    /// Expose only the subtree `prefix` of the source root(s) given so far, as the root of the
+    /// mount: a client's `/file` is `prefix/file` in each root, and nothing outside the subtree
+    /// can be reached. `prefix` is relative to the roots even if it starts with `/`, and may not
+    /// contain `..`. It must be a directory in the first root, but needn't exist in the others.
+    pub fn with_virtual_prefix(mut self, prefix: &Path) -> io::Result<Self> {
+        if prefix.components().any(|c| c == Component::ParentDir) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("prefix {:?} may not contain ..", prefix),
+            ));
+        }
+        let prefix: PathBuf = prefix
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        for (i, root) in self.sourceroots.iter_mut().enumerate() {
+            *root = if i == 0 {
+                canonical_root(&root.join(&prefix))?
+            } else {
+                root.join(&prefix)
+            };
+        }
+        Ok(self)
     }
-}
 
-impl FilesystemMT for DecoFS {
-    fn init(&self, _req: RequestInfo) -> ResultEmpty {
-        info!("init");
-        Ok(())
+    /// Layer the writable directory `upper` over the existing source root(s), which are then
+    /// left untouched, as with overlayfs.
+    ///
+    /// Paths resolve to the upper layer first. Everything created goes there, and a file from a
+    /// lower layer is copied up before it is modified. Removing a lower entry leaves a whiteout
+    /// file in the upper layer; names starting `.wh.` are reserved for these and never shown.
+    /// Directories from a lower layer can't be renamed, failing with EXDEV so that `mv` falls
+    /// back to copying.
+    pub fn with_upper(mut self, upper: PathBuf) -> io::Result<Self> {
+        self.sourceroots.insert(0, canonical_root(&upper)?);
+        self.overlay = true;
+        Ok(self)
     }
 
-    fn destroy(&self) {
-        info!("destroy");
+    /// Whether the lower layers' entry for `partial`, relative to the mount root, shows through:
+    /// neither it nor any ancestor is whited out, and no ancestor is opaque.
+    fn lower_visible(&self, partial: &Path) -> bool {
+        let mut dir = self.sourceroots[0].clone();
+        for component in partial.components() {
+            let name = component.as_os_str();
+            if overlay::is_opaque(&dir) || overlay::is_whited_out(&dir, name) {
+                return false;
+            }
+            dir.push(name);
+        }
+        true
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
-        debug!("getattr: {:?}", path);
-        if let Some(fh) = fh {
-            match libc_wrapper::fstat(fh) {
-                Ok(stat) => Ok((TTL, Self::stat_to_fuse(stat))),
-                Err(e) => Err(e.raw_os_error().unwrap_or(ENOENT)),
+    /// The backing path of `path` in the first lower layer holding it, if it shows through.
+    fn lower_real(&self, path: &Path) -> Option<PathBuf> {
+        let partial = path.strip_prefix("/").unwrap();
+        if !self.lower_visible(partial) {
+            return None;
+        }
+        self.sourceroots[1..]
+            .iter()
+            .map(|root| root.join(partial))
+            .find(|real| fs::symlink_metadata(real).is_ok())
+    }
+
+    /// The upper layer's backing path for `path`, copying it and any missing ancestors up from
+    /// a lower layer first if needed.
+    fn copy_up(&self, path: &Path) -> io::Result<PathBuf> {
+        let upper = self.sourceroots[0].join(path.strip_prefix("/").unwrap());
+        if fs::symlink_metadata(&upper).is_ok() {
+            return Ok(upper);
+        }
+        let lower = self
+            .lower_real(path)
+            .ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+        if let Some(parent) = path.parent() {
+            self.copy_up(parent)?;
+        }
+        debug!("copy up: {:?} -> {:?}", lower, upper);
+        overlay::copy_up(&lower, &upper)?;
+        self.invalidate_entry(&upper);
+        Ok(upper)
+    }
+
+    /// The backing path at which to create `name` in the mount directory `parent`. With an
+    /// overlay, that is always in the upper layer.
+    fn creation_path(&self, parent: &Path, name: &OsStr) -> Result<PathBuf, libc::c_int> {
+        if !self.overlay {
+            return Ok(self.real_path(parent).join(name));
+        }
+        match self.copy_up(parent) {
+            Ok(dir) => Ok(dir.join(name)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    /// Tidy up after creating `real` in an overlay's upper layer, lifting any whiteout of the
+    /// same name. A directory replacing a whited-out one is made opaque, so the old lower
+    /// directory's entries stay hidden.
+    fn created(&self, real: &Path, is_dir: bool) {
+        if !self.overlay {
+            return;
+        }
+        let (dir, name) = match (real.parent(), real.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return,
+        };
+        let result = overlay::remove_whiteout(dir, name).and_then(|replaced| {
+            if is_dir && replaced {
+                overlay::set_opaque(real)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            error!("whiteout of {:?}: {}", real, e);
+        }
+    }
+
+    /// Whether the directory at `path` has no entries, as seen through the overlay.
+    fn merged_dir_is_empty(&self, path: &Path) -> io::Result<bool> {
+        let partial = path.strip_prefix("/").unwrap();
+        let upper = self.sourceroots[0].join(partial);
+        if upper.is_dir() {
+            for entry in fs::read_dir(&upper)? {
+                if !overlay::is_whiteout_name(&entry?.file_name()) {
+                    return Ok(false);
+                }
+            }
+            if overlay::is_opaque(&upper) {
+                return Ok(true);
+            }
+        }
+        if !self.lower_visible(partial) {
+            return Ok(true);
+        }
+        for root in &self.sourceroots[1..] {
+            let lower = root.join(partial);
+            if !lower.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&lower)? {
+                if !overlay::is_whited_out(&upper, &entry?.file_name()) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Remove `name` from the mount directory `parent` of an overlay: from the upper layer if
+    /// there, and by whiteout if a lower layer holds it.
+    fn overlay_remove(&self, parent: &Path, name: &OsStr, is_dir: bool) -> io::Result<()> {
+        let path = parent.join(name);
+        let upper = self.sourceroots[0].join(path.strip_prefix("/").unwrap());
+        let lower = self.lower_real(&path);
+        if is_dir && !self.merged_dir_is_empty(&path)? {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        if fs::symlink_metadata(&upper).is_ok() {
+            if is_dir {
+                overlay::clear_markers(&upper)?;
+                libc_wrapper::rmdir(&upper)?;
+            } else {
+                libc_wrapper::unlink(&upper)?;
             }
         } else {
-            match self.stat_real(path) {
-                Ok(attr) => Ok((TTL, attr)),
-                Err(e) => Err(e.raw_os_error().unwrap_or(ENOENT)),
+            let lower = lower
+                .as_ref()
+                .ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+            match (is_dir, fs::symlink_metadata(lower)?.is_dir()) {
+                (true, false) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                (false, true) => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                _ => {}
             }
         }
+        if let Some(lower) = lower {
+            overlay::create_whiteout(&self.copy_up(parent)?, name)?;
+            self.invalidate_entry(&lower);
+        }
+        self.invalidate_entry(&upper);
+        Ok(())
     }
 
-    fn statfs(&self, _req: RequestInfo, path: &Path) -> ResultStatfs {
-        debug!("statfs: {:?}", path);
+    /// Rename within an overlay, returning the old and new backing paths in the upper layer.
+    fn overlay_rename(
+        &self,
+        parent: &Path,
+        name: &OsStr,
+        newparent: &Path,
+        newname: &OsStr,
+    ) -> io::Result<(PathBuf, PathBuf)> {
+        let path = parent.join(name);
+        let newpath = newparent.join(newname);
+        let from_lower = self.lower_real(&path);
+        let is_dir = fs::symlink_metadata(self.real_path(&path))?.is_dir();
+        if is_dir && from_lower.is_some() {
+            // Moving a lower directory would mean copying up its whole tree.
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        let real = self.copy_up(&path)?;
+        let newdir = self.copy_up(newparent)?;
+        let newreal = newdir.join(newname);
+        let to_lower = self.lower_real(&newpath);
+        if is_dir {
+            if !self.merged_dir_is_empty(&newpath)? {
+                return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+            }
+            if newreal.is_dir() {
+                overlay::clear_markers(&newreal)?;
+            }
+        }
 
-        match self.statfs_real(path) {
-            Ok(stat) => Ok(Self::statfs_to_fuse(stat)),
-            Err(e) => Err(e.raw_os_error().unwrap_or(ENOENT)),
+        libc_wrapper::rename(&real, &newreal)?;
+        let replaced = overlay::remove_whiteout(&newdir, newname)?;
+        if is_dir && (replaced || to_lower.is_some()) {
+            overlay::set_opaque(&newreal)?;
+        }
+        if let Some(lower) = from_lower {
+            overlay::create_whiteout(&self.copy_up(parent)?, name)?;
+            self.invalidate_entry(&lower);
         }
+        Ok((real, newreal))
     }
 
-    fn opendir(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-        let real = self.real_path(path);
-        debug!("opendir: {:?} {:?} (flags = {:#o})", path, real, flags);
-        Ok((0, 0))
+    /// Hide entries whose file name matches any of `patterns`: they are left out of directory
+    /// listings and cannot be looked up or opened by name.
+    pub fn with_hidden(self, hide_patterns: Vec<Pattern>) -> Self {
+        Self {
+            hide_patterns,
+            ..self
+        }
     }
 
-    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
-        let real = self.real_path(path);
-        debug!("readdir: {:?} {:?}", path, real);
-        let mut entries: Vec<DirectoryEntry> = vec![];
-        // Consider using libc::readdir to prevent need for always stat-ing entries
-        let iter = match fs::read_dir(&real) {
-            Ok(iter) => iter,
-            Err(e) => return Err(e.raw_os_error().unwrap_or(ENOENT)),
-        };
-        for entry in iter {
-            match entry {
-                Ok(entry) => {
-                    let real_path = entry.path();
-                    debug!("readdir: {:?} {:?}", real, real_path);
-                    let stat = match libc_wrapper::lstat(&real_path) {
-                        Ok(stat) => stat,
-                        Err(e) => return Err(e.raw_os_error().unwrap_or(ENOENT)),
-                    };
-                    let filetype = DecoFS::stat_to_filetype(&stat);
-
-                    entries.push(DirectoryEntry {
-                        name: real_path.file_name().unwrap().to_os_string(),
-                        kind: filetype,
-                    });
+    /// Remember attributes for up to `ttl`, saving an `lstat` when the same path is queried
+    /// repeatedly. Entries are dropped when changed through the mount, but changes made directly
+    /// to the source tree are not seen until they expire.
+    pub fn with_stat_cache(self, ttl: Duration) -> Self {
+        Self {
+            stat_cache: Some(Arc::new(StatCache::new(ttl, STAT_CACHE_CAPACITY))),
+            ..self
+        }
+    }
+
+    /// Coalesce contiguous writes to a handle, up to `limit` bytes, before passing them to the
+    /// backing file. Buffered data is written out before the file is read or its attributes are
+    /// queried, and on flush, fsync and release; errors writing it are reported from those.
+    pub fn with_write_buffer(self, limit: usize) -> Self {
+        Self {
+            write_buffers: Some(WriteBuffers::new(limit)),
+            ..self
+        }
+    }
+
+    /// Let paths that don't exist as given be looked up, opened and listed under the name of an
+    /// entry differing only by case, for clients that expect a case-insensitive filesystem. Every
+    /// operation on such a path acts on the entry it was matched to, except that the new name of
+    /// a rename is used as given, so that it can change a name's case.
+    ///
+    /// Where several entries differ only by case, the one that sorts first byte-wise is used, and
+    /// the others can only be reached with their exact names.
+    pub fn with_case_insensitive_lookup(self) -> Self {
+        Self {
+            case_folder: Some(Arc::new(CaseFolder::new(self.ttl))),
+            ..self
+        }
+    }
+
+    /// Show symlinks whose targets are absolute paths into a source root as relative links to
+    /// the same place in the mount, so that they resolve there rather than to the source tree.
+    /// Only targets starting with a root's canonical path are recognised.
+    ///
+    /// Relative targets are always passed through unchanged: the mount has the same layout as
+    /// each root, so they resolve the same, except where they climb above the top of the mount.
+    pub fn with_relative_links(self) -> Self {
+        Self {
+            relative_links: true,
+            ..self
+        }
+    }
+
+    /// The target to show for the symlink at `path`, whose target in the source tree is
+    /// `target`.
+    fn client_link_target(&self, path: &Path, target: Vec<u8>) -> Vec<u8> {
+        let target_path = Path::new(OsStr::from_bytes(&target));
+        if !self.relative_links || !target_path.is_absolute() {
+            return target;
+        }
+        let inside = self
+            .sourceroots
+            .iter()
+            .find_map(|root| target_path.strip_prefix(root).ok());
+        match (inside, path.parent()) {
+            (Some(inside), Some(dir)) => relative_to(dir, &Path::new("/").join(inside))
+                .into_os_string()
+                .into_vec(),
+            _ => target,
+        }
+    }
+
+    /// List directories sorted by name, byte-wise, rather than in the backing filesystem's
+    /// order, so that listings are reproducible. Sorting costs time in proportion to the size of
+    /// each directory listed.
+    pub fn with_sorted_entries(self) -> Self {
+        Self {
+            sort_entries: true,
+            ..self
+        }
+    }
+
+    /// Resolve symlinks in the source tree rather than showing them as symlinks, so that each
+    /// appears as its target, with the target's type, attributes and content. Links that can't
+    /// be resolved, because they dangle or loop, are left out of listings and fail with ENOENT
+    /// or ELOOP when looked up. Removing or renaming an entry still acts on the link itself.
+    pub fn with_followed_symlinks(self) -> Self {
+        Self {
+            follow_symlinks: true,
+            ..self
+        }
+    }
+
+    /// Open the backing file `real`, following it if it is a symlink only if symlinks are being
+    /// followed; see `open_at_parent`. A client's O_NOFOLLOW is then dropped: the client was
+    /// shown the target, so the link isn't one to it.
+    fn open_real(&self, real: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<u64> {
+        if self.follow_symlinks {
+            let flags = (flags & !libc::O_NOFOLLOW) | libc::O_NONBLOCK;
+            libc_wrapper::open(&real.to_path_buf(), flags, mode)
+        } else {
+            Self::open_at_parent(real, flags, mode)
+        }
+    }
+
+    /// Let the kernel cache directory listings, and keep them from one open of a directory to
+    /// the next, so that listing it again doesn't call readdir. The kernel drops a listing when
+    /// the directory is changed through the mount, but not when the source tree is changed
+    /// directly, even with the watcher, so this is only safe if that doesn't happen: a listing
+    /// can otherwise stay out of date until the directory's inode is forgotten.
+    pub fn with_dir_cache(self) -> Self {
+        Self {
+            dir_cache: true,
+            ..self
+        }
+    }
+
+    /// Give everything created through the mount other than directories the permission bits
+    /// `mode`, whatever the client asked for and regardless of its umask.
+    pub fn with_force_file_mode(self, mode: u32) -> Self {
+        Self {
+            force_file_mode: Some(mode),
+            ..self
+        }
+    }
+
+    /// Give directories created through the mount the permission bits `mode`, whatever the client
+    /// asked for and regardless of its umask.
+    pub fn with_force_dir_mode(self, mode: u32) -> Self {
+        Self {
+            force_dir_mode: Some(mode),
+            ..self
+        }
+    }
+
+    /// Clear the permission bits in `umask` from the mode of everything created through the
+    /// mount, on top of the umask the kernel applies for the client, so that no client can
+    /// create anything more permissive. Forced modes take precedence, and are used as given.
+    pub fn with_umask(self, umask: u32) -> Self {
+        Self {
+            umask: Some(umask),
+            ..self
+        }
+    }
+
+    /// Move files unlinked through the mount into the directory `trash` rather than deleting them,
+    /// replying as if they were gone. Each is named after the file and the time, such as
+    /// `notes.txt.20240131T120000`, with `.1`, `.2` and so on added if that is taken. Files
+    /// already in the trash are deleted outright, so it can be emptied through the mount.
+    ///
+    /// `trash` must be on the same filesystem as the files, or unlinking them fails with EXDEV.
+    /// Directories are removed as usual: rmdir only removes empty ones, which hold nothing to
+    /// get back.
+    pub fn with_trash_dir(self, trash: &Path) -> io::Result<Self> {
+        let trash = fs::canonicalize(trash)
+            .map_err(|e| io::Error::new(e.kind(), format!("trash {:?}: {}", trash, e)))?;
+        if !fs::metadata(&trash)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("trash {:?} is not a directory", trash),
+            ));
+        }
+        Ok(Self {
+            trash_dir: Some(trash),
+            ..self
+        })
+    }
+
+    /// Move the backing file `real` into `trash`, under the first of its timestamped names that
+    /// is free.
+    fn move_to_trash(&self, trash: &Path, real: &Path) -> io::Result<()> {
+        let mut base = real.file_name().unwrap_or_default().to_os_string();
+        base.push(Local::now().format(".%Y%m%dT%H%M%S").to_string());
+        let mut target = trash.join(&base);
+        let mut n = 0;
+        loop {
+            match libc_wrapper::rename_noreplace(real, &target) {
+                Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                    n += 1;
+                    let mut name = base.clone();
+                    name.push(format!(".{}", n));
+                    target = trash.join(name);
                 }
-                Err(e) => {
-                    error!("readdir: {:?}: {}", path, e);
-                    return Err(e.raw_os_error().unwrap_or(ENOENT));
+                result => {
+                    self.invalidate_entry(&target);
+                    return result;
                 }
             }
         }
-        info!("entries: {:?}", entries);
-        Ok(entries)
     }
 
-    fn releasedir(&self, _req: RequestInfo, path: &Path, _fh: u64, flags: u32) -> ResultEmpty {
-        let real = self.real_path(path);
-        debug!("opendir: {:?} {:?} (flags = {:#o})", path, real, flags);
-        Ok(())
+    /// Report the owner of each entry with `uids` and `gids` applied, each taking backing ids to
+    /// the ids clients see, and apply them in reverse when ownership is changed through the mount.
+    /// Unmapped ids pass through unchanged, so a client id that is also a mapped backing id is
+    /// ambiguous.
+    pub fn with_id_maps(self, uids: HashMap<u32, u32>, gids: HashMap<u32, u32>) -> Self {
+        Self {
+            uid_map: IdMap::new(uids),
+            gid_map: IdMap::new(gids),
+            ..self
+        }
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-        let real = self.real_path(path);
-        debug!("open: {:?} {:?} flags={:#x}", path, real, flags);
+    /// Give `real`, just created by the client making `req`, the backing owner that the client's
+    /// uid and gid are mapped from, if they are mapped, so that the client sees the new entry as
+    /// its own. Otherwise it stays owned by whoever runs the filesystem. Failure, typically for
+    /// want of privilege, is only logged.
+    fn chown_created(&self, real: &Path, req: &RequestInfo) {
+        let uid = self.uid_map.mapped_to_backing(req.uid);
+        let gid = self.gid_map.mapped_to_backing(req.gid);
+        if uid.is_some() || gid.is_some() {
+            let _ = libc_wrapper::lchown(&real.to_path_buf(), uid, gid);
+        }
+    }
 
-        match libc_wrapper::open(&real, flags as libc::c_int) {
-            Ok(fh) => Ok((fh, flags)),
-            Err(e) => {
-                error!("readdir: {:?}: {}", path, e);
-                Err(e.raw_os_error().unwrap_or(ENOENT))
-            }
+    /// Log each mutation at info level instead of performing it, answering with `reply`. Reads
+    /// behave as normal, so don't see the changes a client believes it has made.
+    pub fn with_audit(self, reply: AuditReply) -> Self {
+        Self {
+            audit: Some(reply),
+            ..self
         }
     }
 
-    fn release(
-        &self,
-        _req: RequestInfo,
-        path: &Path,
-        fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
-        _flush: bool,
-    ) -> ResultEmpty {
-        debug!("release: {:?}", path);
-        match libc_wrapper::close(fh) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                error!("open({:?}): {}", path, e);
-                Err(e.raw_os_error().unwrap_or(ENOENT))
+    /// In audit mode, log the mutation described by `op` and return whether to skip it, or
+    /// `EROFS` if that is the configured reply. Otherwise return false, to carry it out.
+    fn audited(&self, op: fmt::Arguments) -> Result<bool, libc::c_int> {
+        match self.audit {
+            None => Ok(false),
+            Some(AuditReply::Succeed) => {
+                info!("audit: {}", op);
+                Ok(true)
+            }
+            Some(AuditReply::ReadOnly) => {
+                info!("audit: {}", op);
+                Err(self.failed(libc::EROFS))
             }
         }
     }
 
-    fn read(
-        &self,
-        _req: RequestInfo,
-        path: &Path,
-        fh: u64,
-        offset: u64,
-        size: u32,
-        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
-    ) -> CallbackResult {
-        debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
-        let mut file = unsafe { unmanaged_file::UnmanagedFile::new(fh) };
+    /// Serve the operation counters, as returned by `stats`, from a read-only `/.decofs-stats`
+    /// file at the root of the mount. It shadows any file of that name in the source tree.
+    pub fn with_stats_file(self) -> Self {
+        Self {
+            stats_file: true,
+            ..self
+        }
+    }
 
-        let mut data = Vec::<u8>::new();
-        data.resize(size as usize, 0);
+    /// How many times each operation has been called so far, and how many calls failed.
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
 
-        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-            error!("seek({:?}, {}): {}", path, offset, e);
-            callback(Err(e.raw_os_error().unwrap_or(ENOENT)))
-        } else {
-            match file.read(&mut data) {
-                Ok(n) => {
-                    data.truncate(n);
-                    callback(Ok(&data))
+    /// Listen for commands on a Unix socket at `path` once mounted, one a line, each answered
+    /// by its output and a final `ok` or `error: ` line:
+    ///
+    /// * `stats` lists the operation counters, as returned by `stats`.
+    /// * `flush-cache` empties the stat and content caches, and forgets data read ahead and
+    ///   case-insensitive listings, for when the source tree has been changed other than through
+    ///   the mount. The kernel's own caches last out their TTL.
+    /// * `reload-config` fails: the options are fixed when the filesystem is built.
+    ///
+    /// The socket is removed on unmount. One left behind by a mount that ended without cleaning
+    /// up is replaced.
+    pub fn with_control_socket(self, path: PathBuf) -> Self {
+        Self {
+            control_socket: Some(path),
+            ..self
+        }
+    }
+
+    /// The answer to the control socket command `words`.
+    fn control_command(
+        words: &[&str],
+        stats: &Counters,
+        stat_cache: Option<&StatCache>,
+        content_cache: Option<&ContentCache>,
+        readahead: Option<&Readahead>,
+        case_folder: Option<&CaseFolder>,
+        roots: &[PathBuf],
+    ) -> Result<String, String> {
+        match words {
+            ["stats"] => Ok(stats.snapshot().to_string()),
+            ["flush-cache"] => {
+                for root in roots {
+                    invalidate_entry(stat_cache, content_cache, root);
                 }
-                Err(e) => {
-                    error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
-                    callback(Err(e.raw_os_error().unwrap_or(ENOENT)))
+                if let Some(readahead) = readahead {
+                    readahead.clear();
                 }
+                if let Some(case_folder) = case_folder {
+                    case_folder.clear();
+                }
+                info!("control: caches flushed");
+                Ok(String::new())
             }
+            ["reload-config"] => Err("options can't be changed while mounted".into()),
+            [command, ..] => Err(format!("unknown command {:?}", command)),
+            [] => Err("no command".into()),
+        }
+    }
+
+    /// List `/.decofs-info` in the root directory. It can be read whether or not it is listed.
+    pub fn with_info_listed(self) -> Self {
+        Self {
+            info_listed: true,
+            ..self
+        }
+    }
+
+    /// A description of the mount, served from `/.decofs-info`: the version, the source roots,
+    /// how writes are handled and the options in effect, one `key: value` line each.
+    pub fn info_text(&self) -> String {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let (upper, lower) = if self.overlay {
+            (Some(&self.sourceroots[0]), &self.sourceroots[1..])
+        } else {
+            (None, &self.sourceroots[..])
+        };
+        let mode = if self.read_only {
+            "read-only"
+        } else if self.audit.is_some() {
+            "audit"
+        } else if self.overlay {
+            "overlay"
+        } else {
+            "read-write"
+        };
+        let mut lines = vec![format!("version: {}", env!("CARGO_PKG_VERSION"))];
+        lines.extend(
+            lower
+                .iter()
+                .map(|root| format!("source: {}", root.display())),
+        );
+        if let Some(upper) = upper {
+            lines.push(format!("upper: {}", upper.display()));
+        }
+        lines.push(format!("mode: {}", mode));
+        lines.push(format!("ttl: {}ms", self.ttl.as_millis()));
+        lines.push(format!("threads: {}", self.threads));
+        lines.extend(self.hide_patterns.iter().map(|p| format!("hide: {}", p)));
+        lines.push(format!("stat-cache: {}", on_off(self.stat_cache.is_some())));
+        lines.push(format!(
+            "content-cache: {}",
+            on_off(self.content_cache.is_some())
+        ));
+        lines.push(format!(
+            "write-buffer: {}",
+            on_off(self.write_buffers.is_some())
+        ));
+        lines.push(format!(
+            "case-insensitive: {}",
+            on_off(self.case_folder.is_some())
+        ));
+        lines.push(format!("watch: {}", on_off(self.watch)));
+        lines.push(format!("direct-io: {}", on_off(self.direct_io)));
+        lines.push(format!("dir-cache: {}", on_off(self.dir_cache)));
+        if let Some(limit) = self.handle_limit {
+            lines.push(format!("max-handles: {}", limit));
+        }
+        if let Some(mode) = self.force_file_mode {
+            lines.push(format!("file-mode: {:o}", mode));
+        }
+        if let Some(mode) = self.force_dir_mode {
+            lines.push(format!("dir-mode: {:o}", mode));
+        }
+        if let Some(umask) = self.umask {
+            lines.push(format!("umask: {:03o}", umask));
+        }
+        if let Some(trash) = &self.trash_dir {
+            lines.push(format!("trash: {}", trash.display()));
+        }
+        lines.push(format!("stats-file: {}", on_off(self.stats_file)));
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    /// Whether `path` is a generated file rather than one in the source tree.
+    fn is_virtual(&self, path: &Path) -> bool {
+        path == Path::new(INFO_PATH) || (self.stats_file && path == Path::new(STATS_PATH))
+    }
+
+    /// The content of the generated file at `path`, if there is one.
+    fn virtual_content(&self, path: &Path) -> Option<Vec<u8>> {
+        if path == Path::new(INFO_PATH) {
+            return Some(self.info_text().into_bytes());
+        }
+        if self.stats_file && path == Path::new(STATS_PATH) {
+            return Some(self.stats().to_string().into_bytes());
+        }
+        None
+    }
+
+    /// Attributes for a generated file of `len` bytes, owned like the root of the mount.
+    fn virtual_attr(&self, len: usize) -> io::Result<FileAttr> {
+        self.synthetic_attr(FileType::RegularFile, 0o444, len)
+    }
+
+    /// Attributes for an entry that doesn't exist in the source tree, owned like its root.
+    fn synthetic_attr(&self, kind: FileType, perm: u32, len: usize) -> io::Result<FileAttr> {
+        let root = self.stat_real(Path::new("/"))?;
+        let now = SystemTime::now();
+        Ok(FileAttr {
+            size: len as u64,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            kind,
+            perm: (perm & 0o7777) as u16,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            rdev: 0,
+            flags: 0,
+            ..root
+        })
+    }
+
+    /// Reply to a creation skipped in audit mode with made-up attributes for the new entry.
+    fn audited_entry(&self, kind: FileType, perm: u32) -> ResultEntry {
+        match self.synthetic_attr(kind, perm, 0) {
+            Ok(attr) => Ok((self.ttl, attr)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    /// The mode to create an entry with when the client asked for `mode`, after applying any
+    /// forced permissions, or otherwise any umask. Any file type bits are kept.
+    fn creation_mode(&self, is_dir: bool, mode: u32) -> libc::mode_t {
+        let forced = if is_dir {
+            self.force_dir_mode
+        } else {
+            self.force_file_mode
+        };
+        match forced {
+            Some(perm) => (mode & !0o7777) | (perm & 0o7777),
+            None => mode & !(self.umask.unwrap_or(0) & 0o7777),
+        }
+    }
+
+    /// `path`, with each component that doesn't exist replaced by a case-insensitive match when
+    /// case-insensitive lookup is enabled. Returned unchanged if there is nothing to match.
+    fn case_resolved<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let folder = match &self.case_folder {
+            Some(folder) => folder,
+            None => return Cow::Borrowed(path),
+        };
+        if fs::symlink_metadata(self.backing_path(path)).is_ok() {
+            return Cow::Borrowed(path);
+        }
+        let mut resolved = PathBuf::from("/");
+        for component in path.strip_prefix("/").unwrap().components() {
+            let name = component.as_os_str();
+            let candidate = resolved.join(name);
+            if fs::symlink_metadata(self.backing_path(&candidate)).is_ok() {
+                resolved = candidate;
+                continue;
+            }
+            match folder.find(&self.backing_path(&resolved), name) {
+                Some(matched) => resolved.push(matched),
+                None => return Cow::Borrowed(path),
+            }
+        }
+        debug!("case-insensitive lookup: {:?} -> {:?}", path, resolved);
+        Cow::Owned(resolved)
+    }
+
+    /// `name`, an entry in `parent`, replaced by the name of the entry case-insensitive lookup
+    /// matches it to, if that is enabled and there is one, for operations on the entry itself.
+    fn case_resolved_name<'a>(&self, parent: &Path, name: &'a OsStr) -> Cow<'a, OsStr> {
+        if self.case_folder.is_none() {
+            return Cow::Borrowed(name);
+        }
+        match self.case_resolved(&parent.join(name)) {
+            Cow::Owned(resolved) => Cow::Owned(resolved.file_name().unwrap().to_os_string()),
+            Cow::Borrowed(_) => Cow::Borrowed(name),
+        }
+    }
+
+    /// Write out anything buffered for `fh`.
+    fn flush_handle(&self, fh: u64) -> io::Result<()> {
+        match &self.write_buffers {
+            Some(buffers) => buffers.flush(fh),
+            None => Ok(()),
+        }
+    }
+
+    /// Write out anything buffered for `fh`, failing if any data written through it has been
+    /// lost even if that was already reported elsewhere.
+    fn sync_handle(&self, fh: u64) -> io::Result<()> {
+        match &self.write_buffers {
+            Some(buffers) => buffers.sync(fh),
+            None => Ok(()),
+        }
+    }
+
+    /// Write out anything buffered for the backing path `real`, through any handle.
+    fn flush_path(&self, real: &Path) -> io::Result<()> {
+        match &self.write_buffers {
+            Some(buffers) => buffers.flush_path(real),
+            None => Ok(()),
+        }
+    }
+
+    /// Note whether `fh` was opened for appending, for direct I/O or for writing, as reported
+    /// by `flags`.
+    fn track_open(&self, fh: u64, flags: u32) {
+        if flags as libc::c_int & libc::O_APPEND != 0 {
+            self.append_handles.write().unwrap().insert(fh);
+        }
+        if flags as libc::c_int & libc::O_DIRECT != 0 {
+            self.direct_handles.write().unwrap().insert(fh);
+        }
+        if flags as libc::c_int & libc::O_ACCMODE != libc::O_RDONLY {
+            self.writable_handles.write().unwrap().insert(fh);
+        }
+    }
+
+    /// The flags to reply to an open with `flags` with. Files opened with O_DIRECT, which is
+    /// passed on to the backing file, bypass the kernel's page cache too.
+    fn open_reply_flags(&self, flags: u32) -> u32 {
+        if self.direct_io || flags as libc::c_int & libc::O_DIRECT != 0 {
+            FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    /// Fail opening files and directories with EMFILE once `limit` are open, rather than
+    /// running out of descriptors partway through some other operation.
+    pub fn with_handle_limit(self, limit: u64) -> Self {
+        Self {
+            handle_limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Keep reads from the backing store to at most `bytes_per_sec` bytes a second in all, with
+    /// bursts of up to a second's worth. Reads over the limit are delayed rather than failed.
+    /// Reads answered from the content cache don't touch the backing store, so aren't limited.
+    pub fn with_read_limit(self, bytes_per_sec: u64) -> Self {
+        Self {
+            read_limit: Some(RateLimiter::new(bytes_per_sec)),
+            ..self
+        }
+    }
+
+    /// Read up to `window` bytes ahead in the background on handles being read sequentially,
+    /// so that streaming a file isn't held up by the latency of each read from the backing
+    /// store. Up to twice `window` is held in memory per handle. Handles opened with O_DIRECT
+    /// are never read ahead.
+    pub fn with_readahead(self, window: usize) -> Self {
+        Self {
+            readahead: Some(Arc::new(Readahead::new(window, Self::pread_full))),
+            ..self
+        }
+    }
+
+    /// Split reads larger than `chunk` bytes into pieces read by concurrent preads, for backing
+    /// stores with high latency but the bandwidth to serve several requests at once. A read is
+    /// split into at most `MAX_PARALLEL_READS` pieces, each on a thread of its own, and the data
+    /// passed back is exactly what one pread would have given. Reads through the readahead
+    /// buffers or with O_DIRECT aren't split.
+    pub fn with_parallel_reads(self, chunk: usize) -> Self {
+        Self {
+            read_chunk: Some(chunk),
+            ..self
+        }
+    }
+
+    /// Answer fsyncs arriving within `window` of one another with one `syncfs` of the backing
+    /// filesystem, made once the window has passed, rather than one sync each. Each waits for
+    /// the sync, so none returns before its data is durable, but each is delayed by up to
+    /// `window`. Only fsyncs being handled at the same time can share a sync, so this needs more
+    /// than one thread to help. A failed `syncfs` fails every fsync in its batch, even where the
+    /// error came from another file.
+    pub fn with_fsync_batching(self, window: Duration) -> Self {
+        Self {
+            fsync_batcher: Some(FsyncBatcher::new(window)),
+            ..self
+        }
+    }
+
+    /// Fail reads and attribute lookups on the backing store with ETIMEDOUT if they take longer
+    /// than `timeout`, so that a hung backend doesn't leave the mount's threads waiting forever.
+    /// Each is run on a thread of its own, which costs time on every call; one that times out
+    /// is left to return in its own time. Writes and other changes are never timed out, since
+    /// they might still be carried out after the client was told they failed.
+    pub fn with_op_timeout(self, timeout: Duration) -> Self {
+        Self {
+            op_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Fail every operation with `errno`, such as EIO or ENOTCONN, while any of the source roots
+    /// given so far is unavailable, as when the removable or network filesystem holding it goes
+    /// away. The outage is logged once, as is the roots coming back, after which operations work
+    /// as before. Operations that can still succeed, such as reads on files already open, do.
+    pub fn with_missing_root_errno(self, errno: libc::c_int) -> Self {
+        Self {
+            root_monitor: Some(RootMonitor::new(&self.sourceroots, errno)),
+            ..self
+        }
+    }
+
+    /// Run `op`, a call to the backing store, under the operation timeout if there is one.
+    /// Once `MAX_TIMED_OPS` calls are outstanding, further ones fail with ETIMEDOUT straight
+    /// away, so that calls stuck on a hung backend can't pile up threads without limit.
+    fn timed<T: Send + 'static>(
+        &self,
+        op: impl FnOnce() -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let timeout = match self.op_timeout {
+            Some(timeout) => timeout,
+            None => return op(),
+        };
+        if self.timed_ops.fetch_add(1, Ordering::SeqCst) >= MAX_TIMED_OPS {
+            self.timed_ops.fetch_sub(1, Ordering::SeqCst);
+            return Err(io::Error::from_raw_os_error(libc::ETIMEDOUT));
+        }
+        let (sender, receiver) = mpsc::channel();
+        let timed_ops = self.timed_ops.clone();
+        let spawned = thread::Builder::new()
+            .name("backing-op".into())
+            .spawn(move || {
+                let _ = sender.send(op());
+                timed_ops.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            self.timed_ops.fetch_sub(1, Ordering::SeqCst);
+            return Err(e);
+        }
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("backing store call timed out after {:?}", timeout);
+                Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::from_raw_os_error(libc::EIO)),
+        }
+    }
+
+    /// Count a backing file or directory about to be opened, failing with EMFILE if the handle
+    /// limit has been reached. `release_handle` must follow once it is closed, or fails to open.
+    fn reserve_handle(&self) -> ResultEmpty {
+        let open = self.open_handles.fetch_add(1, Ordering::Relaxed);
+        match self.handle_limit {
+            Some(limit) if open >= limit => {
+                self.open_handles.fetch_sub(1, Ordering::Relaxed);
+                warn!("limit of {} open handles reached", limit);
+                Err(self.failed(libc::EMFILE))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn release_handle(&self) {
+        self.open_handles.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Have the kernel bypass its page cache for every file, passing reads and writes through
+    /// with the sizes and offsets the client used instead of whole pages. Files are only opened
+    /// with O_DIRECT in the backing store if the client asked for it. Shared writable mappings
+    /// of files through the mount are then unavailable.
+    pub fn with_direct_io(self) -> Self {
+        Self {
+            direct_io: true,
+            ..self
+        }
+    }
+
+    /// Keep the whole content of files up to `max_file` bytes in memory once read, up to
+    /// `capacity` bytes in all, so that rereading them doesn't touch the backing store. A cached
+    /// file is dropped when its modification time or size is seen to change, so changes made
+    /// directly to the source tree are only picked up once its attributes are queried again.
+    pub fn with_content_cache(self, capacity: usize, max_file: u64) -> Self {
+        Self {
+            content_cache: Some(Arc::new(ContentCache::new(capacity, max_file))),
+            ..self
+        }
+    }
+
+    /// Drop any cached attributes and content for the backing path `real`.
+    fn invalidate(&self, real: &Path) {
+        if let Some(cache) = &self.stat_cache {
+            cache.invalidate(real);
+        }
+        if let Some(cache) = &self.content_cache {
+            cache.invalidate_tree(real);
+        }
+        if let Some(readahead) = &self.readahead {
+            readahead.invalidate(real);
+        }
+    }
+
+    /// Drop cached attributes after `real` is created or removed: for it, anything beneath it,
+    /// and its parent directory, whose times and link count have changed. Cached content for it
+    /// and anything beneath it goes too, as does the parent's listing for case-insensitive lookup.
+    fn invalidate_entry(&self, real: &Path) {
+        invalidate_entry(
+            self.stat_cache.as_deref(),
+            self.content_cache.as_deref(),
+            real,
+        );
+        if let (Some(folder), Some(parent)) = (&self.case_folder, real.parent()) {
+            folder.invalidate(parent);
+        }
+    }
+
+    /// Watch the source tree once mounted, dropping anything cached about a path when it changes
+    /// other than through the mount. Without this, the stat and content caches only notice such
+    /// changes when their entries expire or the file's attributes are next read.
+    ///
+    /// Only DecoFS's own caches are invalidated: fuse_mt gives no access to the kernel's, which
+    /// still hold attributes and entries for up to the TTL.
+    pub fn with_watcher(self) -> Self {
+        Self {
+            watch: true,
+            ..self
+        }
+    }
+
+    /// Open `real` through a descriptor for its parent directory, following a symlink in place of
+    /// neither: one swapped into the source tree after the kernel looked the path up fails with
+    /// ELOOP rather than redirecting the open, or a create, somewhere else. The kernel resolves
+    /// symlinks in the mount itself, so a genuine open never reaches one here.
+    ///
+    /// The same goes for FIFOs, which the kernel opens locally, but one swapped in could block a
+    /// request thread until a writer came along. The open is made non-blocking so that it fails
+    /// with ENXIO instead, as the kernel's own does; that has no effect on regular files.
+    fn open_at_parent(real: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<u64> {
+        let flags = flags | libc::O_NOFOLLOW | libc::O_NONBLOCK;
+        let (parent, name) = match (real.parent(), real.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return libc_wrapper::open(&real.to_path_buf(), flags, mode),
+        };
+        let dirfd = libc_wrapper::open(
+            &parent.to_path_buf(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0,
+        )?;
+        let result = libc_wrapper::openat(dirfd, name, flags, mode);
+        let _ = libc_wrapper::close(dirfd);
+        result
+    }
+
+    /// Read from `fh` at `offset` until `buf` is full or end-of-file, returning how much was read.
+    /// A single pread may come up short even mid-file, which FUSE would take for end-of-file.
+    /// An error after some data has been read is left for the next read to report.
+    ///
+    /// Devices that can't seek, such as terminals, fail pread with ESPIPE; they are read
+    /// sequentially instead, ignoring `offset`, with a single read returning whatever the device
+    /// has rather than waiting for more.
+    ///
+    /// With no shared file position involved, concurrent reads on one handle need no lock. The
+    /// exception is such a device, where each read takes whatever comes next, in whichever order
+    /// they run, just as with concurrent reads of the device anywhere else.
+    fn pread_full(fh: u64, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match Self::pread_seekable(fh, buf, offset) {
+            Err(e) if e.raw_os_error() == Some(libc::ESPIPE) => libc_wrapper::read(fh, buf),
+            result => result,
+        }
+    }
+
+    /// `pread_full`, without the fallback for devices that can't seek.
+    fn pread_seekable(fh: u64, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match libc_wrapper::pread(fh, &mut buf[filled..], offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if filled == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(filled)
+    }
+
+    /// `pread_full`, with `buf` split into pieces of at least `chunk` bytes that are read
+    /// concurrently. The result runs up to the first piece that comes up short or fails, as if
+    /// read in order, so data beyond end-of-file or an error is never passed back. A piece that
+    /// can't be given a thread is read once the others are done.
+    fn pread_parallel(fh: u64, buf: &mut [u8], offset: u64, chunk: usize) -> io::Result<usize> {
+        let chunk = chunk.max(buf.len().div_ceil(MAX_PARALLEL_READS)).max(1);
+        if buf.len() <= chunk {
+            return Self::pread_full(fh, buf, offset);
+        }
+        let mut results = thread::scope(|scope| {
+            let reads = buf
+                .chunks_mut(chunk)
+                .enumerate()
+                .map(|(i, piece)| {
+                    let at = offset + (i * chunk) as u64;
+                    thread::Builder::new()
+                        .name("read".into())
+                        .spawn_scoped(scope, move || Self::pread_seekable(fh, piece, at))
+                        .ok()
+                })
+                .collect::<Vec<_>>();
+            reads
+                .into_iter()
+                .map(|read| read.map(|read| read.join().unwrap()))
+                .collect::<Vec<_>>()
+        });
+        for (i, result) in results.iter_mut().enumerate() {
+            if result.is_none() {
+                let start = i * chunk;
+                let end = (start + chunk).min(buf.len());
+                *result = Some(Self::pread_seekable(
+                    fh,
+                    &mut buf[start..end],
+                    offset + start as u64,
+                ));
+            }
+        }
+
+        let mut filled = 0;
+        for (i, result) in results.into_iter().enumerate() {
+            let len = chunk.min(buf.len() - i * chunk);
+            match result.unwrap() {
+                Ok(n) => {
+                    filled += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(e) if i == 0 && e.raw_os_error() == Some(libc::ESPIPE) => {
+                    return libc_wrapper::read(fh, buf);
+                }
+                Err(e) if i == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(filled)
+    }
+
+    /// The whole content of `real`, open as `fh`, from the content cache, reading it in if it is
+    /// small enough. None if it isn't cached and can't be.
+    fn cached_content(&self, cache: &ContentCache, real: &Path, fh: u64) -> Option<Arc<Vec<u8>>> {
+        if let Some(content) = cache.get(real) {
+            return Some(content);
+        }
+        let stat = libc_wrapper::fstat(fh).ok()?;
+        if stat.st_mode & libc::S_IFMT != libc::S_IFREG || !cache.admits(stat.st_size as u64) {
+            return None;
+        }
+        let mut data = vec![0u8; stat.st_size as usize];
+        let filled = Self::pread_full(fh, &mut data, 0).ok()?;
+        data.truncate(filled);
+        let mtime = Self::to_system_time(stat.st_mtime, stat.st_mtime_nsec);
+        Some(cache.insert(real.to_path_buf(), mtime, data))
+    }
+
+    fn is_hidden(&self, path: &Path) -> bool {
+        match path.file_name() {
+            Some(name) if self.overlay && overlay::is_whiteout_name(name) => true,
+            Some(name) => {
+                let name = name.to_string_lossy();
+                self.hide_patterns.iter().any(|p| p.matches(&name))
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failed operation, returning its `errno`, or the one chosen for a missing source
+    /// root if that is why it failed.
+    fn failed(&self, errno: libc::c_int) -> libc::c_int {
+        self.stats.error();
+        match &self.root_monitor {
+            Some(monitor) => monitor.failed(errno),
+            None => errno,
+        }
+    }
+
+    /// The errno to report for `e`, counting the failure.
+    fn errno(&self, e: &io::Error) -> libc::c_int {
+        self.failed(errno_of(e))
+    }
+
+    /// Fail with ENAMETOOLONG if `name` is longer than NAME_MAX, or the backing path `real` it
+    /// would be created at is longer than PATH_MAX, and with EINVAL if `name` contains a NUL.
+    /// Checked before anything is done, rather than left to fail partway through.
+    fn check_new_name(&self, name: &OsStr, real: &Path) -> ResultEmpty {
+        if name.as_bytes().contains(&0) {
+            return Err(self.failed(libc::EINVAL));
+        }
+        if name.len() > libc::NAME_MAX as usize || real.as_os_str().len() >= libc::PATH_MAX as usize
+        {
+            return Err(self.failed(libc::ENAMETOOLONG));
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> ResultEmpty {
+        if self.read_only {
+            Err(self.failed(libc::EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The backing path of `path`, resolved case-insensitively if that is enabled, so that every
+    /// operation on a path reaches the entry its lookup found.
+    fn real_path(&self, path: &Path) -> PathBuf {
+        self.backing_path(&self.case_resolved(path))
+    }
+
+    /// The backing path of `path` as it is given.
+    fn backing_path(&self, path: &Path) -> PathBuf {
+        let partial = path.strip_prefix("/").unwrap();
+        if let [root] = self.sourceroots.as_slice() {
+            return root.join(partial);
+        }
+        if self.overlay {
+            let upper = self.sourceroots[0].join(partial);
+            if fs::symlink_metadata(&upper).is_ok() || !self.lower_visible(partial) {
+                return upper;
+            }
+            return self.lower_real(path).unwrap_or(upper);
+        }
+        self.sourceroots
+            .iter()
+            .map(|root| root.join(partial))
+            .find(|real| fs::symlink_metadata(real).is_ok())
+            .unwrap_or_else(|| self.sourceroots[0].join(partial))
+    }
+
+    /// Usage of the filesystem holding the first source root, whatever path was asked about, so
+    /// that `df` gives the same totals anywhere in the mount. With a union, the other roots'
+    /// filesystems are not counted.
+    fn statfs_real(&self) -> io::Result<libc::statfs> {
+        libc_wrapper::statfs(&self.sourceroots[0])
+    }
+
+    fn stat_real(&self, path: &Path) -> io::Result<FileAttr> {
+        let real = self.real_path(path);
+        if let Some(attr) = self.stat_cache.as_ref().and_then(|cache| cache.get(&real)) {
+            return Ok(attr);
+        }
+        // Unlike open, there is no later use for a symlink swapped in to redirect: lstat never
+        // follows one in place of the entry, and its result is used as it is. When symlinks
+        // are followed, doing so is the point.
+        let target = real.clone();
+        let follow = self.follow_symlinks;
+        let (stat, statx) = self.timed(move || {
+            if follow {
+                let stat = libc_wrapper::stat(&target)?;
+                Ok((stat, libc_wrapper::statx(&target, 0, libc::STATX_BTIME)))
+            } else {
+                let stat = libc_wrapper::lstat(&target)?;
+                let statx =
+                    libc_wrapper::statx(&target, libc::AT_SYMLINK_NOFOLLOW, libc::STATX_BTIME);
+                Ok((stat, statx))
+            }
+        })?;
+        let mut attr = self.stat_to_fuse(stat)?;
+
+        // Birth time is only available through statx, and only where the backing filesystem
+        // records it; otherwise crtime stays at the epoch.
+        if let Ok(statx) = statx {
+            if statx.stx_mask & libc::STATX_BTIME != 0 {
+                attr.crtime =
+                    Self::to_system_time(statx.stx_btime.tv_sec, statx.stx_btime.tv_nsec.into());
+            }
+        }
+        if let Some(cache) = &self.stat_cache {
+            cache.insert(real, attr);
+        }
+        Ok(attr)
+    }
+
+    /// The filesystem type (`f_type`) can't be passed on: FUSE has no field for it, and the
+    /// kernel always reports `FUSE_SUPER_MAGIC`. The sizes are signed on some platforms, so
+    /// any out of range are reported as 0 rather than wrapped, and a fragment size of 0, which
+    /// is what the block counts are in units of, is taken to be the block size.
+    fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
+        let bsize = statfs.f_bsize.try_into().unwrap_or(0);
+        let frsize = match statfs.f_frsize.try_into().unwrap_or(0) {
+            0 => bsize,
+            frsize => frsize,
+        };
+        Statfs {
+            blocks: statfs.f_blocks,
+            bfree: statfs.f_bfree,
+            bavail: statfs.f_bavail,
+            files: statfs.f_files,
+            ffree: statfs.f_ffree,
+            bsize,
+            namelen: statfs.f_namelen.try_into().unwrap_or(0),
+            frsize,
+        }
+    }
+
+    /// Convert a (seconds, nanoseconds) timestamp to a `SystemTime`, allowing for times before
+    /// the epoch. As with `timespec`, the nanoseconds are always a positive offset.
+    fn to_system_time(secs: i64, nsec: i64) -> SystemTime {
+        let base = if secs < 0 {
+            SystemTime::UNIX_EPOCH
+                .checked_sub(Duration::from_secs(secs.unsigned_abs()))
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        } else {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64)
+        };
+        base + Duration::from_nanos(nsec.try_into().unwrap_or(0))
+    }
+
+    /// Convert `time` to a `timespec` for utimensat, to the nanosecond. As there, `None` leaves
+    /// the time as it is.
+    fn to_timespec(time: Option<SystemTime>) -> libc::timespec {
+        let time = match time {
+            Some(time) => time,
+            None => {
+                return libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                }
+            }
+        };
+        let (tv_sec, tv_nsec) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos() as i64),
+            // Before the epoch, the nanoseconds are still a positive offset from the seconds.
+            Err(e) => {
+                let before = e.duration();
+                match before.subsec_nanos() {
+                    0 => (-(before.as_secs() as i64), 0),
+                    nsec => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nsec as i64),
+                }
+            }
+        };
+        libc::timespec { tv_sec, tv_nsec }
+    }
+
+    /// Convert a backing `stat` into the attributes reported through the mount.
+    ///
+    /// `st_ino` is deliberately not carried: `FileAttr` has no inode field, and fuse_mt assigns
+    /// inodes from its own path-keyed table. A path keeps the same inode while the kernel holds a
+    /// reference to it, but hard links to one backing inode appear as distinct inodes and numbers
+    /// are not stable across mounts.
+    ///
+    /// Carrying it would not be enough to change that. fuser sends one number as both the node ID
+    /// the kernel addresses later requests by and the `st_ino` it reports, and fuse_mt needs each
+    /// node ID to name a single path, which backing inode numbers can't: hard links share one,
+    /// and the roots of a union can repeat them.
+    ///
+    /// Nor is `st_dev`: the kernel reports the mount's own device for everything in it, so a
+    /// mount is one device however many backing filesystems it draws on.
+    ///
+    /// `st_blocks` is carried as it is: both it and `FileAttr::blocks` count 512-byte units,
+    /// whatever the backing filesystem's block size, so `du` over the mount agrees with `du` over
+    /// the source tree, holes in sparse files included. That stays true where a transform changes
+    /// the size reported, since the blocks are still the ones the encoded file takes up. fuse_mt
+    /// fixes `st_blksize` at 4096, so the backing filesystem's preferred I/O size isn't passed on.
+    ///
+    /// The owner is translated by any id maps.
+    fn stat_to_fuse(&self, stat: libc::stat) -> io::Result<FileAttr> {
+        // st_mode encodes both the kind and the permissions
+        let kind = match attr::filetype_from_mode(stat.st_mode) {
+            Some(kind) => kind,
+            None => {
+                error!("unknown file type: mode = {:#o}", stat.st_mode);
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+        };
+        let perm = (stat.st_mode & 0o7777) as u16;
+
+        Ok(FileAttr {
+            size: stat.st_size as u64,
+            blocks: stat.st_blocks as u64,
+            atime: Self::to_system_time(stat.st_atime, stat.st_atime_nsec),
+            mtime: Self::to_system_time(stat.st_mtime, stat.st_mtime_nsec),
+            ctime: Self::to_system_time(stat.st_ctime, stat.st_ctime_nsec),
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: stat.st_nlink as u32,
+            uid: self.uid_map.to_client(stat.st_uid),
+            gid: self.gid_map.to_client(stat.st_gid),
+            rdev: stat.st_rdev as u32,
+            flags: 0,
+        })
+    }
+
+    /// Add the entries of `path` from every other source root to `entries`, skipping any name
+    /// already present. `real` is the first root's copy of `path`, which has already been read.
+    fn merge_entries(&self, path: &Path, real: &Path, entries: &mut Vec<DirectoryEntry>) {
+        let partial = path.strip_prefix("/").unwrap();
+        let mut seen: HashSet<OsString> = entries.iter().map(|e| e.name.clone()).collect();
+        let mut lower_hidden = false;
+        if self.overlay {
+            // Entries removed through the overlay are whited out in the upper directory.
+            let upper = self.sourceroots[0].join(partial);
+            lower_hidden = overlay::is_opaque(&upper) || !self.lower_visible(partial);
+            seen.extend(overlay::whited_out_names(&upper));
+        }
+        for (i, root) in self.sourceroots.iter().enumerate() {
+            let other = root.join(partial);
+            if other == real || !other.is_dir() || (i > 0 && lower_hidden) {
+                continue;
+            }
+            let dh = match libc_wrapper::opendir(&other) {
+                Ok(dh) => dh,
+                Err(_) => continue,
+            };
+            if let Ok(more) = Self::read_entries(&other, dh, self.follow_symlinks) {
+                entries.extend(more.into_iter().filter(|e| seen.insert(e.name.clone())));
+            }
+            let _ = libc_wrapper::closedir(dh);
+        }
+    }
+
+    /// Read every remaining entry from the directory stream `dh`, open on `real`. fuse_mt asks for
+    /// a directory's entries only once per handle and pages through them itself, so there is no
+    /// offset to resume from and the listing can't be streamed.
+    fn read_entries(
+        real: &Path,
+        dh: u64,
+        follow: bool,
+    ) -> Result<Vec<DirectoryEntry>, libc::c_int> {
+        let mut entries: Vec<DirectoryEntry> = vec![];
+        loop {
+            let entry = match libc_wrapper::readdir(dh) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("readdir: {:?}: {}", real, e);
+                    return Err(errno_of(&e));
+                }
+            };
+            let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+            let name = OsStr::from_bytes(name.to_bytes());
+            if name == "." || name == ".." {
+                continue;
+            }
+            debug!("readdir: {:?} {:?}", real, name);
+
+            // Only stat the entry when the backing filesystem doesn't report d_type, or it is a
+            // symlink to be followed, relative to the directory being read rather than by
+            // walking its path again.
+            let listed = attr::filetype_from_dirent(entry.d_type)
+                .filter(|&filetype| !(follow && filetype == FileType::Symlink));
+            let filetype = match listed {
+                Some(filetype) => Some(filetype),
+                None => {
+                    let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+                    match libc_wrapper::dirfd(dh)
+                        .and_then(|fd| libc_wrapper::fstatat(fd, name, flags))
+                    {
+                        Ok(stat) => attr::filetype_from_mode(stat.st_mode),
+                        // Removed since it was listed, which mustn't fail the whole listing
+                        // with ENOENT as if the directory itself were missing. The same goes
+                        // for a link that can't be followed.
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(e) if follow && e.raw_os_error() == Some(libc::ELOOP) => continue,
+                        Err(e) => return Err(errno_of(&e)),
+                    }
+                }
+            };
+            // An unrecognised entry shouldn't hide the rest of the directory.
+            let filetype = match filetype {
+                Some(filetype) => filetype,
+                None => {
+                    warn!(
+                        "readdir: {:?}: unknown file type, skipping",
+                        real.join(name)
+                    );
+                    continue;
+                }
+            };
+
+            entries.push(DirectoryEntry {
+                name: name.to_os_string(),
+                kind: filetype,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+impl FilesystemMT for DecoFS {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        info!("init");
+        // The kernel applies the client's umask to the modes it sends, so applying ours on top
+        // would mask them twice, and could strip bits from a forced mode.
+        libc_wrapper::umask(0);
+
+        if self.watch {
+            let stat_cache = self.stat_cache.clone();
+            let content_cache = self.content_cache.clone();
+            let on_change = move |real: &Path| {
+                invalidate_entry(stat_cache.as_deref(), content_cache.as_deref(), real)
+            };
+            if let Err(e) = watcher::spawn(self.sourceroots.clone(), on_change) {
+                error!("watcher: {}", e);
+                return Err(self.errno(&e));
+            }
+        }
+        if let Some(path) = &self.control_socket {
+            let stats = self.stats.clone();
+            let stat_cache = self.stat_cache.clone();
+            let content_cache = self.content_cache.clone();
+            let readahead = self.readahead.clone();
+            let case_folder = self.case_folder.clone();
+            let roots = self.sourceroots.clone();
+            let handler = move |words: &[&str]| {
+                Self::control_command(
+                    words,
+                    &stats,
+                    stat_cache.as_deref(),
+                    content_cache.as_deref(),
+                    readahead.as_deref(),
+                    case_folder.as_deref(),
+                    &roots,
+                )
+            };
+            if let Err(e) = control::spawn(path, Arc::new(handler)) {
+                error!("control socket {:?}: {}", path, e);
+                return Err(self.errno(&e));
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&self) {
+        info!("destroy");
+        if let Some(path) = &self.control_socket {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+        self.stats.call(Op::Getattr);
+        debug!("getattr: {:?}", path);
+        let path = &*self.case_resolved(path);
+        if self.is_hidden(path) {
+            return Err(self.failed(ENOENT));
+        }
+        if let Some(content) = self.virtual_content(path) {
+            return match self.virtual_attr(content.len()) {
+                Ok(attr) => Ok((self.ttl, attr)),
+                Err(e) => Err(self.errno(&e)),
+            };
+        }
+        if let Err(e) = self.flush_path(&self.real_path(path)) {
+            return Err(self.errno(&e));
+        }
+        if let Some(fh) = fh {
+            if let Err(e) = self.flush_handle(fh) {
+                return Err(self.errno(&e));
+            }
+        }
+        // Handles for generated and decoded content have no descriptor to stat.
+        let fh = fh.filter(|fh| !self.virtual_handles.lock().unwrap().contains_key(fh));
+        let result = match fh {
+            Some(fh) => self
+                .timed(move || libc_wrapper::fstat(fh))
+                .and_then(|stat| self.stat_to_fuse(stat)),
+            None => self.stat_real(path),
+        };
+        let result = result.and_then(|attr| {
+            if let Some(cache) = &self.content_cache {
+                cache.check(&self.real_path(path), attr.mtime, attr.size);
+            }
+            match self.file_transform(path) {
+                Some(transform) => self.decoded_attr(transform, path, attr),
+                None => Ok(attr),
+            }
+        });
+        match result {
+            Ok(attr) => Ok((self.ttl, attr)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn statfs(&self, _req: RequestInfo, path: &Path) -> ResultStatfs {
+        self.stats.call(Op::Statfs);
+        debug!("statfs: {:?}", path);
+
+        match self.statfs_real() {
+            Ok(stat) => Ok(Self::statfs_to_fuse(stat)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn opendir(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        self.stats.call(Op::Opendir);
+        let path = &*self.case_resolved(path);
+        let real = self.real_path(path);
+        debug!("opendir: {:?} {:?} (flags = {:#o})", path, real, flags);
+
+        // The DIR* itself is the handle; it stays open until releasedir.
+        self.reserve_handle()?;
+        match libc_wrapper::opendir(&real) {
+            Ok(dh) if self.dir_cache => Ok((dh, FOPEN_CACHE_DIR | FOPEN_KEEP_CACHE)),
+            Ok(dh) => Ok((dh, 0)),
+            Err(e) => {
+                self.release_handle();
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
+        self.stats.call(Op::Readdir);
+        let path = &*self.case_resolved(path);
+        let real = self.real_path(path);
+        debug!("readdir: {:?} {:?}", path, real);
+        let mut entries = Self::read_entries(&real, fh, self.follow_symlinks)
+            .map_err(|errno| self.failed(errno))?;
+        if self.sourceroots.len() > 1 {
+            self.merge_entries(path, &real, &mut entries);
+        }
+        entries.retain(|entry| !self.is_hidden(Path::new(&entry.name)));
+        if path == Path::new("/") {
+            let listed = [(self.stats_file, STATS_PATH), (self.info_listed, INFO_PATH)];
+            for (_, virtual_path) in listed.iter().filter(|(listed, _)| *listed) {
+                let name = OsStr::new(&virtual_path[1..]);
+                entries.retain(|entry| entry.name != name);
+                entries.push(DirectoryEntry {
+                    name: name.to_os_string(),
+                    kind: FileType::RegularFile,
+                });
+            }
+        }
+        if self.sort_entries {
+            entries.sort_unstable_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+        }
+        // The full listing can be huge, so it is only built when tracing.
+        info!("readdir: {:?}: {} entries", path, entries.len());
+        if log_enabled!(log::Level::Trace) {
+            trace!("entries: {:?}", entries);
+        }
+        Ok(entries)
+    }
+
+    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, flags: u32) -> ResultEmpty {
+        self.stats.call(Op::Releasedir);
+        let real = self.real_path(path);
+        debug!("releasedir: {:?} {:?} (flags = {:#o})", path, real, flags);
+        // The stream is freed even if closing it fails.
+        self.release_handle();
+        match libc_wrapper::closedir(fh) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        self.stats.call(Op::Open);
+        let path = &*self.case_resolved(path);
+        let mut real = self.real_path(path);
+        debug!("open: {:?} {:?} flags={:#x}", path, real, flags);
+        if self.is_hidden(path) {
+            return Err(self.failed(ENOENT));
+        }
+        // The flags are the client's open(2) flags, a c_int the kernel passes on as a u32, so
+        // the cast gives back exactly what it asked for. The kernel has already dealt with the
+        // flags about what is being opened: O_PATH opens never get here, O_NOFOLLOW has failed
+        // with ELOOP if the entry is a symlink, and O_DIRECTORY with ENOTDIR if it isn't a
+        // directory, all against the attributes decofs reports.
+        let mut flags_int = flags as libc::c_int;
+        if let Some(content) = self.virtual_content(path) {
+            if flags_int & libc::O_ACCMODE != libc::O_RDONLY || flags_int & libc::O_TRUNC != 0 {
+                return Err(self.failed(libc::EACCES));
+            }
+            let fh = self.next_virtual_fh.fetch_add(1, Ordering::Relaxed);
+            self.virtual_handles
+                .lock()
+                .unwrap()
+                .insert(fh, Arc::new(content));
+            return Ok((fh, FOPEN_DIRECT_IO));
+        }
+        if let Some(transform) = self.file_transform(path) {
+            if flags_int & libc::O_ACCMODE != libc::O_RDONLY || flags_int & libc::O_TRUNC != 0 {
+                return Err(self.failed(libc::EACCES));
+            }
+            let content = self
+                .stat_real(path)
+                .and_then(|attr| self.decoded_content(transform, path, &attr));
+            let content = match content {
+                Ok(content) => content,
+                Err(e) => return Err(self.errno(&e)),
+            };
+            let fh = self.next_virtual_fh.fetch_add(1, Ordering::Relaxed);
+            self.virtual_handles.lock().unwrap().insert(fh, content);
+            return Ok((fh, 0));
+        }
+        if flags_int & libc::O_ACCMODE != libc::O_RDONLY || flags_int & libc::O_TRUNC != 0 {
+            self.check_writable()?;
+            if self.audited(format_args!("open {:?} flags={:#x}", real, flags))? {
+                // Reads still work through a read-only descriptor, while writes are skipped.
+                flags_int &= !(libc::O_ACCMODE | libc::O_TRUNC | libc::O_APPEND);
+            } else if self.overlay {
+                real = match self.copy_up(path) {
+                    Ok(upper) => upper,
+                    Err(e) => return Err(self.errno(&e)),
+                };
+            }
+        }
+
+        self.reserve_handle()?;
+        // The kernel opens directories with opendir, and refuses to open them for writing, so a
+        // directory only gets here if one replaced a file after it was looked up. Opening it
+        // read-only would succeed, so it is checked for, rather than handing out a file handle
+        // that can only fail reads.
+        let opened = self.open_real(&real, flags_int, 0).and_then(|fh| {
+            if flags_int & libc::O_ACCMODE != libc::O_RDONLY {
+                return Ok(fh);
+            }
+            let checked = libc_wrapper::fstat(fh).and_then(|stat| {
+                if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                    Err(io::Error::from_raw_os_error(libc::EISDIR))
+                } else {
+                    Ok(fh)
+                }
+            });
+            if checked.is_err() {
+                let _ = libc_wrapper::close(fh);
+            }
+            checked
+        });
+        match opened {
+            Ok(fh) => {
+                self.track_open(fh, flags_int as u32);
+                Ok((fh, self.open_reply_flags(flags_int as u32)))
+            }
+            Err(e) => {
+                self.release_handle();
+                error!("open({:?}): {}", path, e);
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn release(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> ResultEmpty {
+        self.stats.call(Op::Release);
+        debug!("release: {:?}", path);
+        if self.virtual_handles.lock().unwrap().remove(&fh).is_some() {
+            return Ok(());
+        }
+        self.append_handles.write().unwrap().remove(&fh);
+        self.direct_handles.write().unwrap().remove(&fh);
+        self.writable_handles.write().unwrap().remove(&fh);
+        if let Some(readahead) = &self.readahead {
+            readahead.release(fh);
+        }
+        self.release_handle();
+        // Close even if buffered data couldn't be written, but report that failure.
+        let flushed = match &self.write_buffers {
+            Some(buffers) => buffers.release(fh),
+            None => Ok(()),
+        };
+        // An interrupted close has still released the descriptor, but whether its data was
+        // written is unknown, so it is reported as an I/O error rather than as EINTR, which
+        // would invite the client to close again.
+        let closed = libc_wrapper::close(fh).map_err(|e| match e.raw_os_error() {
+            Some(libc::EINTR) => io::Error::from_raw_os_error(libc::EIO),
+            _ => e,
+        });
+        // A buffered write that failed is the underlying problem, so it is reported first.
+        match flushed.and(closed) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("release({:?}): {}", path, e);
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn read(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        self.stats.call(Op::Read);
+        debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
+        if let Some(content) = self.virtual_handles.lock().unwrap().get(&fh) {
+            let start = (offset as usize).min(content.len());
+            let end = start.saturating_add(size as usize).min(content.len());
+            return callback(Ok(&content[start..end]));
+        }
+        let real = self.real_path(path);
+        if let Err(e) = self.flush_handle(fh).and_then(|()| self.flush_path(&real)) {
+            return callback(Err(self.errno(&e)));
+        }
+        // O_DIRECT is a request to bypass caches, so the content cache is skipped too.
+        let direct = self.direct_handles.read().unwrap().contains(&fh);
+        match &self.content_cache {
+            Some(cache) if !direct => {
+                if let Some(content) = self.cached_content(cache, &real, fh) {
+                    let start = (offset as usize).min(content.len());
+                    let end = start.saturating_add(size as usize).min(content.len());
+                    let mut data = content[start..end].to_vec();
+                    self.block_transform(path)
+                        .decode_read(path, offset, &mut data);
+                    return callback(Ok(&data));
+                }
+            }
+            _ => {}
+        }
+
+        // pread leaves the shared descriptor's offset untouched, so concurrent reads on the same
+        // handle from multiple FuseMT threads cannot disturb one another. O_DIRECT needs an
+        // aligned buffer, which the data is then copied out of; the backing filesystem rejects
+        // a misaligned offset or size with EINVAL, which is passed back as it is.
+        let result = if direct {
+            self.timed(move || {
+                let mut buffer = AlignedBuffer::zeroed(size as usize);
+                Self::pread_full(fh, &mut buffer, offset).map(|n| buffer[..n].to_vec())
+            })
+        } else if let Some(readahead) = &self.readahead {
+            let (readahead, real, wait) = (readahead.clone(), real.clone(), self.op_timeout);
+            self.timed(move || readahead.read(fh, &real, offset, size as usize, wait))
+        } else if let Some(chunk) = self.read_chunk {
+            self.timed(move || {
+                let mut data = vec![0u8; size as usize];
+                Self::pread_parallel(fh, &mut data, offset, chunk).map(|n| {
+                    data.truncate(n);
+                    data
+                })
+            })
+        } else {
+            self.timed(move || {
+                let mut data = vec![0u8; size as usize];
+                Self::pread_full(fh, &mut data, offset).map(|n| {
+                    data.truncate(n);
+                    data
+                })
+            })
+        };
+        match result {
+            Ok(mut data) => {
+                if let Some(limiter) = &self.read_limit {
+                    limiter.take(data.len());
+                }
+                self.block_transform(path)
+                    .decode_read(path, offset, &mut data);
+                callback(Ok(&data))
+            }
+            Err(e) => {
+                error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
+                callback(Err(self.errno(&e)))
+            }
+        }
+    }
+
+    fn write(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        data: Vec<u8>,
+        _flags: u32,
+    ) -> ResultWrite {
+        self.stats.call(Op::Write);
+        self.check_writable()?;
+        debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
+        let real = self.real_path(path);
+        if self.audited(format_args!(
+            "write {:?} {:#x} @ {:#x}",
+            real,
+            data.len(),
+            offset
+        ))? {
+            return Ok(data.len() as u32);
+        }
+
+        // Unless it is buffered, a write only succeeds once the backing filesystem has taken the
+        // data, so ENOSPC and the like are reported for the write that met them, and a short
+        // count is passed back for the client to retry the rest. Durability still needs fsync.
+        //
+        // As with read, pwrite keeps the shared descriptor's offset untouched. It would ignore
+        // O_APPEND though, so appending handles use write, which the OS appends atomically.
+        // O_DIRECT writes are copied to an aligned buffer, and never buffered.
+        let direct = self.direct_handles.read().unwrap().contains(&fh);
+        let aligned;
+        let buf: &[u8] = if direct {
+            aligned = AlignedBuffer::copy_of(&data);
+            &aligned
+        } else {
+            &data
+        };
+        let result = if self.append_handles.read().unwrap().contains(&fh) {
+            libc_wrapper::write(fh, buf)
+        } else if let (Some(buffers), false) = (&self.write_buffers, direct) {
+            buffers.write(fh, &real, offset, buf).map(|()| buf.len())
+        } else {
+            libc_wrapper::pwrite(fh, buf, offset)
+        };
+        self.invalidate(&real);
+        match result {
+            Ok(n) => Ok(n as u32),
+            Err(e) => {
+                error!("write {:?}, {:#x} @ {:#x}: {}", path, data.len(), offset, e);
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
+        self.stats.call(Op::Flush);
+        debug!("flush: {:?}", path);
+        // Each close of a client's descriptor is flushed, so clients relying on close-to-open
+        // consistency find what they wrote on the backing store once the close returns. The
+        // kernel's flag asking for a flush on release is left unset on Linux, so isn't relied on.
+        let writable = self.writable_handles.read().unwrap().contains(&fh);
+        let result = self.sync_handle(fh).and_then(|()| {
+            if !writable {
+                return Ok(());
+            }
+            unsafe { unmanaged_file::UnmanagedFile::new(fh) }.and_then(|file| file.sync_data())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("flush({:?}): {}", path, e);
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.stats.call(Op::Fsync);
+        debug!("fsync: {:?} (datasync = {})", path, datasync);
+        // Handles for generated and decoded content have no descriptor, and nothing to sync.
+        if self.virtual_handles.lock().unwrap().contains_key(&fh) {
+            return Ok(());
+        }
+        let result = self
+            .sync_handle(fh)
+            .and_then(|()| match &self.fsync_batcher {
+                Some(batcher) => batcher.sync(fh),
+                None => unsafe { unmanaged_file::UnmanagedFile::new(fh) }.and_then(|file| {
+                    if datasync {
+                        file.sync_data()
+                    } else {
+                        file.sync_all()
+                    }
+                }),
+            });
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("fsync({:?}): {}", path, e);
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn fsyncdir(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
+        self.stats.call(Op::Fsyncdir);
+        debug!("fsyncdir: {:?} (datasync = {})", path, datasync);
+        // Sync through the descriptor of the open DIR*, which stays owned by it.
+        let result = libc_wrapper::dirfd(fh)
+            .and_then(|fd| unsafe { unmanaged_file::UnmanagedFile::new(fd) })
+            .and_then(|dir| {
+                if datasync {
+                    dir.sync_data()
+                } else {
+                    dir.sync_all()
+                }
+            });
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("fsyncdir({:?}): {}", path, e);
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn create(
+        &self,
+        req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> ResultCreate {
+        self.stats.call(Op::Create);
+        self.check_writable()?;
+        let real = self.real_path(parent).join(name);
+        self.check_new_name(name, &real)?;
+        debug!(
+            "create: {:?}/{:?} {:?} (mode={:#o}, flags={:#x})",
+            parent, name, real, mode, flags
+        );
+
+        if self.audited(format_args!(
+            "create {:?} mode={:#o} flags={:#x}",
+            real, mode, flags
+        ))? {
+            // Hand out an empty generated file, which the skipped writes then go to.
+            let attr = match self.synthetic_attr(
+                FileType::RegularFile,
+                self.creation_mode(false, mode),
+                0,
+            ) {
+                Ok(attr) => attr,
+                Err(e) => return Err(self.errno(&e)),
+            };
+            let fh = self.next_virtual_fh.fetch_add(1, Ordering::Relaxed);
+            self.virtual_handles
+                .lock()
+                .unwrap()
+                .insert(fh, Arc::new(vec![]));
+            return Ok(CreatedEntry {
+                ttl: self.ttl,
+                attr,
+                fh,
+                flags: FOPEN_DIRECT_IO,
+            });
+        }
+
+        let real = self.creation_path(parent, name)?;
+
+        // The kernel has already applied the caller's umask to mode, unless it is forced.
+        self.reserve_handle()?;
+        let fh = match Self::open_at_parent(
+            &real,
+            flags as libc::c_int | libc::O_CREAT,
+            self.creation_mode(false, mode),
+        ) {
+            Ok(fh) => fh,
+            Err(e) => {
+                self.release_handle();
+                error!("create({:?}/{:?}): {}", parent, name, e);
+                return Err(self.errno(&e));
+            }
+        };
+        self.created(&real, false);
+        self.chown_created(&real, &req);
+        self.invalidate_entry(&real);
+
+        match libc_wrapper::fstat(fh).and_then(|stat| self.stat_to_fuse(stat)) {
+            Ok(attr) => {
+                self.track_open(fh, flags);
+                Ok(CreatedEntry {
+                    ttl: self.ttl,
+                    attr,
+                    fh,
+                    flags: self.open_reply_flags(flags),
+                })
+            }
+            Err(e) => {
+                let _ = libc_wrapper::close(fh);
+                self.release_handle();
+                Err(self.errno(&e))
+            }
+        }
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &Path, name: &OsStr, mode: u32) -> ResultEntry {
+        self.stats.call(Op::Mkdir);
+        self.check_writable()?;
+        let real = self.real_path(parent).join(name);
+        self.check_new_name(name, &real)?;
+        debug!(
+            "mkdir: {:?}/{:?} {:?} (mode={:#o})",
+            parent, name, real, mode
+        );
+
+        if self.audited(format_args!("mkdir {:?} mode={:#o}", real, mode))? {
+            return self.audited_entry(FileType::Directory, self.creation_mode(true, mode));
+        }
+
+        let real = self.creation_path(parent, name)?;
+
+        // As with create, the kernel has already applied the caller's umask to mode.
+        if let Err(e) = libc_wrapper::mkdir(&real, self.creation_mode(true, mode)) {
+            return Err(self.errno(&e));
+        }
+        self.created(&real, true);
+        self.chown_created(&real, &req);
+        self.invalidate_entry(&real);
+
+        match self.stat_real(&parent.join(name)) {
+            Ok(attr) => Ok((self.ttl, attr)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn mknod(
+        &self,
+        req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> ResultEntry {
+        self.stats.call(Op::Mknod);
+        self.check_writable()?;
+        let real = self.real_path(parent).join(name);
+        self.check_new_name(name, &real)?;
+        debug!(
+            "mknod: {:?}/{:?} {:?} (mode={:#o}, rdev={:#x})",
+            parent, name, real, mode, rdev
+        );
+
+        if self.audited(format_args!(
+            "mknod {:?} mode={:#o} rdev={:#x}",
+            real, mode, rdev
+        ))? {
+            let kind = attr::filetype_from_mode(mode).unwrap_or(FileType::RegularFile);
+            return self.audited_entry(kind, self.creation_mode(false, mode));
+        }
+
+        // The device number only means something for device nodes; FIFOs and sockets ignore it.
+        let rdev = match mode & libc::S_IFMT {
+            libc::S_IFBLK | libc::S_IFCHR => rdev as libc::dev_t,
+            _ => 0,
+        };
+        let real = self.creation_path(parent, name)?;
+        // Creating a device node without privilege fails with EPERM, which is passed through.
+        if let Err(e) = libc_wrapper::mknod(&real, self.creation_mode(false, mode), rdev) {
+            return Err(self.errno(&e));
+        }
+        self.created(&real, false);
+        self.chown_created(&real, &req);
+        self.invalidate_entry(&real);
+
+        match self.stat_real(&parent.join(name)) {
+            Ok(attr) => Ok((self.ttl, attr)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        self.stats.call(Op::Unlink);
+        self.check_writable()?;
+        let name = &*self.case_resolved_name(parent, name);
+        if self.is_virtual(&parent.join(name)) {
+            return Err(self.failed(libc::EPERM));
+        }
+        let real = self.real_path(parent).join(name);
+        debug!("unlink: {:?}/{:?} {:?}", parent, name, real);
+        if self.audited(format_args!("unlink {:?}", real))? {
+            return Ok(());
+        }
+        if self.overlay {
+            return match self.overlay_remove(parent, name, false) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(self.errno(&e)),
+            };
+        }
+
+        let result = match &self.trash_dir {
+            Some(trash) if !real.starts_with(trash) => {
+                // rename would happily move a directory, which unlink must refuse.
+                match fs::symlink_metadata(&real) {
+                    Ok(meta) if meta.is_dir() => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                    Ok(_) => self.move_to_trash(trash, &real),
+                    Err(e) => Err(e),
+                }
+            }
+            _ => libc_wrapper::unlink(&real),
+        };
+        match result {
+            Ok(()) => {
+                self.invalidate_entry(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        self.stats.call(Op::Rmdir);
+        self.check_writable()?;
+        let name = &*self.case_resolved_name(parent, name);
+        let real = self.real_path(parent).join(name);
+        debug!("rmdir: {:?}/{:?} {:?}", parent, name, real);
+        if self.audited(format_args!("rmdir {:?}", real))? {
+            return Ok(());
+        }
+        if self.overlay {
+            return match self.overlay_remove(parent, name, true) {
+                Ok(()) => Ok(()),
+                Err(e) => Err(self.errno(&e)),
+            };
+        }
+
+        match libc_wrapper::rmdir(&real) {
+            Ok(()) => {
+                self.invalidate_entry(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn rename(
+        &self,
+        _req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        newparent: &Path,
+        newname: &OsStr,
+    ) -> ResultEmpty {
+        self.stats.call(Op::Rename);
+        self.check_writable()?;
+        // The new name is used as given, so that a rename can change the case of a name.
+        let name = &*self.case_resolved_name(parent, name);
+        if self.is_virtual(&parent.join(name)) || self.is_virtual(&newparent.join(newname)) {
+            return Err(self.failed(libc::EPERM));
+        }
+        let real = self.real_path(parent).join(name);
+        let newreal = self.real_path(newparent).join(newname);
+        self.check_new_name(newname, &newreal)?;
+        debug!(
+            "rename: {:?}/{:?} -> {:?}/{:?} ({:?} -> {:?})",
+            parent, name, newparent, newname, real, newreal
+        );
+
+        if self.audited(format_args!("rename {:?} -> {:?}", real, newreal))? {
+            return Ok(());
+        }
+
+        // fuse_mt does not forward the renameat2 flags (RENAME_NOREPLACE, RENAME_EXCHANGE), so
+        // only a plain rename can be requested; EXDEV is passed back for the kernel to handle.
+        let result = if self.overlay {
+            self.overlay_rename(parent, name, newparent, newname)
+        } else {
+            libc_wrapper::rename(&real, &newreal).map(|()| (real, newreal))
+        };
+        match result {
+            Ok((real, newreal)) => {
+                if let Some(buffers) = &self.write_buffers {
+                    buffers.renamed(&real, &newreal);
+                }
+                self.invalidate_entry(&real);
+                self.invalidate_entry(&newreal);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        self.stats.call(Op::Readlink);
+        let real = self.real_path(path);
+        debug!("readlink: {:?} {:?}", path, real);
+
+        match libc_wrapper::readlink(&real) {
+            Ok(target) => Ok(self.client_link_target(path, target)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &Path, name: &OsStr, target: &Path) -> ResultEntry {
+        self.stats.call(Op::Symlink);
+        self.check_writable()?;
+        let real = self.real_path(parent).join(name);
+        self.check_new_name(name, &real)?;
+        debug!(
+            "symlink: {:?}/{:?} {:?} -> {:?}",
+            parent, name, real, target
+        );
+
+        if self.audited(format_args!("symlink {:?} -> {:?}", real, target))? {
+            let len = target.as_os_str().len();
+            return match self.synthetic_attr(FileType::Symlink, 0o777, len) {
+                Ok(attr) => Ok((self.ttl, attr)),
+                Err(e) => Err(self.errno(&e)),
+            };
+        }
+
+        let real = self.creation_path(parent, name)?;
+        if let Err(e) = libc_wrapper::symlink(target, &real) {
+            return Err(self.errno(&e));
+        }
+        self.created(&real, false);
+        self.chown_created(&real, &req);
+        self.invalidate_entry(&real);
+
+        match self.stat_real(&parent.join(name)) {
+            Ok(attr) => Ok((self.ttl, attr)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn link(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        newparent: &Path,
+        newname: &OsStr,
+    ) -> ResultEntry {
+        self.stats.call(Op::Link);
+        self.check_writable()?;
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::EPERM));
+        }
+        let real = self.real_path(path);
+        let newreal = self.real_path(newparent).join(newname);
+        self.check_new_name(newname, &newreal)?;
+        debug!(
+            "link: {:?} -> {:?}/{:?} ({:?} -> {:?})",
+            path, newparent, newname, real, newreal
+        );
+
+        if self.audited(format_args!("link {:?} -> {:?}", real, newreal))? {
+            // The new name would share the existing entry's attributes.
+            return match self.stat_real(path) {
+                Ok(attr) => Ok((self.ttl, attr)),
+                Err(e) => Err(self.errno(&e)),
+            };
+        }
+
+        let newreal = self.creation_path(newparent, newname)?;
+        let real = if self.overlay {
+            match self.copy_up(path) {
+                Ok(upper) => upper,
+                Err(e) => return Err(self.errno(&e)),
+            }
+        } else {
+            real
+        };
+        if let Err(e) = libc_wrapper::link(&real, &newreal) {
+            return Err(self.errno(&e));
+        }
+        self.created(&newreal, false);
+        self.invalidate(&real);
+        self.invalidate_entry(&newreal);
+
+        // The inode number is not carried in FileAttr; fuse_mt allocates its own inode for the
+        // new path, so only nlink reflects that both names share a backing inode.
+        match self.stat_real(&newparent.join(newname)) {
+            Ok(attr) => Ok((self.ttl, attr)),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn chown(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        _fh: Option<u64>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> ResultEmpty {
+        self.stats.call(Op::Chown);
+        self.check_writable()?;
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::EPERM));
+        }
+        let real = if self.overlay {
+            match self.copy_up(path) {
+                Ok(upper) => upper,
+                Err(e) => return Err(self.errno(&e)),
+            }
+        } else {
+            self.real_path(path)
+        };
+        let uid = uid.map(|uid| self.uid_map.to_backing(uid));
+        let gid = gid.map(|gid| self.gid_map.to_backing(gid));
+        debug!("chown: {:?} {:?} ({:?}, {:?})", path, real, uid, gid);
+
+        if self.audited(format_args!("chown {:?} uid={:?} gid={:?}", real, uid, gid))? {
+            return Ok(());
+        }
+
+        match libc_wrapper::lchown(&real, uid, gid) {
+            Ok(()) => {
+                self.invalidate(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    /// The kernel doesn't pass O_TRUNC on to open unless the filesystem asks it to, which
+    /// `fuse_mt` has no way of doing, so opens that truncate arrive here afterwards instead.
+    fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        self.stats.call(Op::Truncate);
+        self.check_writable()?;
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::EPERM));
+        }
+        if self.file_transform(path).is_some() {
+            return Err(self.failed(libc::EACCES));
+        }
+        let real = if self.overlay {
+            match self.copy_up(path) {
+                Ok(upper) => upper,
+                Err(e) => return Err(self.errno(&e)),
+            }
+        } else {
+            self.real_path(path)
+        };
+        debug!("truncate: {:?} {:?} (size = {})", path, real, size);
+
+        if self.audited(format_args!("truncate {:?} size={}", real, size))? {
+            return Ok(());
+        }
+
+        // Buffered writes landing afterwards would undo it.
+        if let Err(e) = self.flush_path(&real) {
+            return Err(self.errno(&e));
+        }
+        // With an overlay, a handle may still refer to the file beneath the copy just made.
+        let result = match fh {
+            Some(fh) if !self.overlay => libc_wrapper::ftruncate(fh, size),
+            _ => self.open_real(&real, libc::O_WRONLY, 0).and_then(|fh| {
+                let truncated = libc_wrapper::ftruncate(fh, size);
+                let _ = libc_wrapper::close(fh);
+                truncated
+            }),
+        };
+        match result {
+            Ok(()) => {
+                self.invalidate(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn utimens(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> ResultEmpty {
+        self.stats.call(Op::Utimens);
+        self.check_writable()?;
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::EPERM));
+        }
+        let real = if self.overlay {
+            match self.copy_up(path) {
+                Ok(upper) => upper,
+                Err(e) => return Err(self.errno(&e)),
+            }
+        } else {
+            self.real_path(path)
+        };
+        debug!(
+            "utimens: {:?} {:?} (atime = {:?}, mtime = {:?})",
+            path, real, atime, mtime
+        );
+
+        if self.audited(format_args!(
+            "utimens {:?} atime={:?} mtime={:?}",
+            real, atime, mtime
+        ))? {
+            return Ok(());
+        }
+
+        // Buffered writes landing afterwards would move the new mtime on.
+        if let Err(e) = self.flush_path(&real) {
+            return Err(self.errno(&e));
+        }
+        let times = [Self::to_timespec(atime), Self::to_timespec(mtime)];
+        // With an overlay, a handle may still refer to the file beneath the copy just made, and
+        // handles for decoded content have no descriptor at all.
+        let fh = fh.filter(|fh| !self.virtual_handles.lock().unwrap().contains_key(fh));
+        let result = match fh {
+            Some(fh) if !self.overlay => libc_wrapper::futimens(fh, &times),
+            _ => libc_wrapper::utimensat(&real, &times),
+        };
+        match result {
+            Ok(()) => {
+                self.invalidate(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn setxattr(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+    ) -> ResultEmpty {
+        self.stats.call(Op::Setxattr);
+        self.check_writable()?;
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::ENOTSUP));
+        }
+        let real = if self.overlay {
+            match self.copy_up(path) {
+                Ok(upper) => upper,
+                Err(e) => return Err(self.errno(&e)),
+            }
+        } else {
+            self.real_path(path)
+        };
+        debug!(
+            "setxattr: {:?} {:?} {:?} ({:#x} bytes)",
+            path,
+            real,
+            name,
+            value.len()
+        );
+
+        if self.audited(format_args!(
+            "setxattr {:?} {:?} ({:#x} bytes)",
+            real,
+            name,
+            value.len()
+        ))? {
+            return Ok(());
+        }
+
+        match libc_wrapper::lsetxattr(&real, name, value, flags as libc::c_int) {
+            Ok(()) => {
+                self.invalidate(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.stats.call(Op::Getxattr);
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::ENODATA));
+        }
+        let real = self.real_path(path);
+        debug!(
+            "getxattr: {:?} {:?} {:?} (size = {:#x})",
+            path, real, name, size
+        );
+
+        // A zero size is a probe for the length of the value; the kernel reports ERANGE itself
+        // when a non-zero buffer is too small.
+        let mut data = vec![0u8; size as usize];
+        match libc_wrapper::lgetxattr(&real, name, &mut data) {
+            Ok(n) if size == 0 => Ok(Xattr::Size(n as u32)),
+            Ok(n) => {
+                data.truncate(n);
+                Ok(Xattr::Data(data))
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        self.stats.call(Op::Listxattr);
+        if self.is_virtual(path) {
+            return Ok(if size == 0 {
+                Xattr::Size(0)
+            } else {
+                Xattr::Data(vec![])
+            });
+        }
+        let real = self.real_path(path);
+        debug!("listxattr: {:?} {:?} (size = {:#x})", path, real, size);
+
+        let mut data = vec![0u8; size as usize];
+        match libc_wrapper::llistxattr(&real, &mut data) {
+            Ok(n) if size == 0 => Ok(Xattr::Size(n as u32)),
+            Ok(n) => {
+                data.truncate(n);
+                Ok(Xattr::Data(data))
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
+        self.stats.call(Op::Removexattr);
+        self.check_writable()?;
+        if self.is_virtual(path) {
+            return Err(self.failed(libc::ENODATA));
+        }
+        let real = if self.overlay {
+            match self.copy_up(path) {
+                Ok(upper) => upper,
+                Err(e) => return Err(self.errno(&e)),
+            }
+        } else {
+            self.real_path(path)
+        };
+        debug!("removexattr: {:?} {:?} {:?}", path, real, name);
+        if self.audited(format_args!("removexattr {:?} {:?}", real, name))? {
+            return Ok(());
+        }
+
+        match libc_wrapper::lremovexattr(&real, name) {
+            Ok(()) => {
+                self.invalidate(&real);
+                Ok(())
+            }
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+
+    fn access(&self, _req: RequestInfo, path: &Path, mask: u32) -> ResultEmpty {
+        self.stats.call(Op::Access);
+        if self.is_virtual(path) {
+            return if mask as libc::c_int & (libc::W_OK | libc::X_OK) != 0 {
+                Err(self.failed(libc::EACCES))
+            } else {
+                Ok(())
+            };
+        }
+        let real = self.real_path(path);
+        debug!("access: {:?} {:?} (mask = {:#o})", path, real, mask);
+
+        // The check is made by the backing filesystem (including any ACLs), using the
+        // credentials of the decofs process.
+        match libc_wrapper::access(&real, mask as libc::c_int) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.errno(&e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::os::unix::fs::symlink;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("decofs-deco-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn request() -> RequestInfo {
+        RequestInfo {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn entries_and_attributes_carry_the_configured_ttl() {
+        let dir = scratch_dir("ttl");
+        fs::write(dir.join("file"), "").unwrap();
+        let ttl = Duration::from_millis(1234);
+        let decofs = DecoFS::with_ttl(dir.clone(), ttl).unwrap();
+        assert_eq!(
+            decofs
+                .getattr(request(), Path::new("/file"), None)
+                .unwrap()
+                .0,
+            ttl
+        );
+        let (entry_ttl, _) = decofs
+            .mkdir(request(), Path::new("/"), OsStr::new("dir"), 0o755)
+            .unwrap();
+        assert_eq!(entry_ttl, ttl);
+
+        let decofs = DecoFS::new(dir.clone()).unwrap();
+        assert_eq!(
+            decofs
+                .getattr(request(), Path::new("/file"), None)
+                .unwrap()
+                .0,
+            TTL
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_stat_cache_stays_bounded_however_many_paths_are_looked_up() {
+        let dir = scratch_dir("bounded");
+        let count = STAT_CACHE_CAPACITY + 100;
+        for i in 0..count {
+            fs::write(dir.join(i.to_string()), "").unwrap();
+        }
+        let decofs = DecoFS::new(dir.clone())
+            .unwrap()
+            .with_stat_cache(Duration::from_secs(60));
+        let paths = (0..count)
+            .map(|i| PathBuf::from(format!("/{}", i)))
+            .collect::<Vec<_>>();
+        for path in &paths {
+            decofs.getattr(request(), path, None).unwrap();
+        }
+        let cache = decofs.stat_cache.as_ref().unwrap();
+        let cached = paths
+            .iter()
+            .filter(|path| cache.get(&decofs.real_path(path)).is_some())
+            .count();
+        assert_eq!(cached, STAT_CACHE_CAPACITY);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// "hi\n", gzipped.
+    const HI_GZ: [u8; 23] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0xc8, 0xe4, 0x02, 0x00,
+        0x7a, 0x7a, 0x6f, 0xed, 0x03, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn decoded_content_is_held_only_while_a_handle_is_open() {
+        let dir = scratch_dir("decoded");
+        fs::write(dir.join("hi.gz"), HI_GZ).unwrap();
+        let transform = Transform::WholeFile(Box::new(crate::transform::Gunzip::new(1 << 20)));
+        let decofs = DecoFS::new(dir.clone())
+            .unwrap()
+            .with_extension_transform("gz", transform);
+        let path = Path::new("/hi.gz");
+        let real = decofs.real_path(path);
+        let held = || {
+            let decoded = decofs.decoded.lock().unwrap();
+            decoded[&real].content.upgrade()
+        };
+
+        // Working out the size decodes the file, but doesn't keep what it decoded.
+        assert_eq!(decofs.getattr(request(), path, None).unwrap().1.size, 3);
+        assert!(held().is_none());
+
+        let flags = libc::O_RDONLY as u32;
+        let (first, _) = decofs.open(request(), path, flags).unwrap();
+        let (second, _) = decofs.open(request(), path, flags).unwrap();
+        {
+            let handles = decofs.virtual_handles.lock().unwrap();
+            assert!(Arc::ptr_eq(&handles[&first], &handles[&second]));
+        }
+        assert_eq!(held().unwrap().as_slice(), b"hi\n");
+        decofs
+            .release(request(), path, first, flags, 0, false)
+            .unwrap();
+        assert!(held().is_some());
+        decofs
+            .release(request(), path, second, flags, 0, false)
+            .unwrap();
+        assert!(held().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn decoded_sizes_are_remembered_for_the_most_recently_used_files() {
+        let dir = scratch_dir("decoded-lru");
+        fs::write(dir.join("hi.gz"), HI_GZ).unwrap();
+        let transform = Transform::WholeFile(Box::new(crate::transform::Gunzip::new(1 << 20)));
+        let decofs = DecoFS::new(dir.clone())
+            .unwrap()
+            .with_extension_transform("gz", transform);
+        let attr = decofs.getattr(request(), Path::new("/"), None).unwrap().1;
+        {
+            let now = Instant::now();
+            let mut decoded = decofs.decoded.lock().unwrap();
+            for i in 0..DECODED_CAPACITY {
+                let used = Duration::from_secs((DECODED_CAPACITY - i) as u64);
+                decoded.insert(
+                    decofs.real_path(&Path::new("/").join(i.to_string())),
+                    Decoded {
+                        mtime: attr.mtime,
+                        size: attr.size,
+                        len: 0,
+                        content: Weak::new(),
+                        last_used: now.checked_sub(used).unwrap_or(now),
+                    },
+                );
+            }
+        }
+
+        assert_eq!(
+            decofs
+                .getattr(request(), Path::new("/hi.gz"), None)
+                .unwrap()
+                .1
+                .size,
+            3
+        );
+        let decoded = decofs.decoded.lock().unwrap();
+        assert_eq!(decoded.len(), DECODED_CAPACITY);
+        assert!(!decoded.contains_key(&decofs.real_path(Path::new("/0"))));
+        assert!(decoded.contains_key(&decofs.real_path(Path::new("/1"))));
+        assert!(decoded.contains_key(&decofs.real_path(Path::new("/hi.gz"))));
+        drop(decoded);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crtime_is_the_birth_time_where_the_source_records_one() {
+        let dir = scratch_dir("crtime");
+        fs::write(dir.join("file"), "").unwrap();
+        let decofs = DecoFS::new(dir.clone()).unwrap();
+        let (_, attr) = decofs.getattr(request(), Path::new("/file"), None).unwrap();
+        let statx = libc_wrapper::statx(&dir.join("file"), 0, libc::STATX_BTIME).unwrap();
+        if statx.stx_mask & libc::STATX_BTIME == 0 {
+            assert_eq!(attr.crtime, SystemTime::UNIX_EPOCH);
+        } else {
+            let since_epoch = attr.crtime.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            assert_eq!(since_epoch.as_secs(), statx.stx_btime.tv_sec as u64);
+            assert_eq!(since_epoch.subsec_nanos(), statx.stx_btime.tv_nsec);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn times_before_the_epoch_convert_without_panicking() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(DecoFS::to_system_time(0, 0), epoch);
+        assert_eq!(
+            DecoFS::to_system_time(-1, 500_000_000),
+            epoch - Duration::from_millis(500)
+        );
+        assert_eq!(
+            DecoFS::to_system_time(-86_400, 0),
+            epoch - Duration::from_secs(86_400)
+        );
+        assert_eq!(
+            DecoFS::to_system_time(86_400, 1),
+            epoch + Duration::new(86_400, 1)
+        );
+    }
+
+    #[test]
+    fn read_only_rejects_every_change_with_erofs() {
+        let dir = scratch_dir("read-only");
+        fs::write(dir.join("file"), "content").unwrap();
+        let decofs = DecoFS::new(dir.clone()).unwrap().with_read_only();
+        let (root, file, name) = (Path::new("/"), Path::new("/file"), OsStr::new("new"));
+        let erofs = Some(libc::EROFS);
+        assert_eq!(decofs.create(request(), root, name, 0o644, 0).err(), erofs);
+        assert_eq!(decofs.mkdir(request(), root, name, 0o755).err(), erofs);
+        assert_eq!(
+            decofs.unlink(request(), root, OsStr::new("file")).err(),
+            erofs
+        );
+        assert_eq!(
+            decofs
+                .rename(request(), root, OsStr::new("file"), root, name)
+                .err(),
+            erofs
+        );
+        assert_eq!(decofs.truncate(request(), file, None, 0).err(), erofs);
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "content");
+        assert!(!dir.join("new").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relative_and_symlinked_roots_resolve_to_the_same_directory() {
+        let dir = scratch_dir("relative");
+        let canonical = fs::canonicalize("src").unwrap();
+        symlink(&canonical, dir.join("link")).unwrap();
+        for root in [
+            PathBuf::from("src"),
+            PathBuf::from("./src"),
+            PathBuf::from("src/../src"),
+            canonical.clone(),
+            dir.join("link"),
+        ] {
+            let decofs = DecoFS::new(root.clone()).unwrap();
+            assert_eq!(
+                decofs.sourceroots,
+                std::slice::from_ref(&canonical),
+                "{:?}",
+                root
+            );
+        }
+
+        fs::write(dir.join("file"), "").unwrap();
+        symlink(dir.join("absent"), dir.join("dangling")).unwrap();
+        for root in ["absent", "file", "dangling"] {
+            let e = DecoFS::new(dir.join(root)).err().unwrap();
+            assert!(e.to_string().contains(root), "{}", e);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors_not_from_the_os_are_mapped_from_their_kind() {
+        let error = |kind| io::Error::new(kind, "not from the OS");
+        assert_eq!(errno_of(&error(io::ErrorKind::NotFound)), libc::ENOENT);
+        assert_eq!(errno_of(&error(io::ErrorKind::InvalidInput)), libc::EINVAL);
+        assert_eq!(
+            errno_of(&error(io::ErrorKind::InvalidFilename)),
+            libc::EINVAL
+        );
+        assert_eq!(errno_of(&error(io::ErrorKind::Other)), libc::EIO);
+    }
+
+    #[test]
+    fn a_nul_in_a_path_is_reported_as_einval() {
+        let path = PathBuf::from(OsStr::from_bytes(b"a\0b"));
+        let e = libc_wrapper::lstat(&path).unwrap_err();
+        assert_eq!(errno_of(&e), libc::EINVAL);
+    }
+
+    #[test]
+    fn roots_that_are_not_directories_say_what_was_found() {
+        let dir = scratch_dir("not-a-dir");
+        fs::write(dir.join("file"), "").unwrap();
+        let fifo = CString::new(dir.join("fifo").into_os_string().into_vec()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        symlink(dir.join("file"), dir.join("link")).unwrap();
+        symlink(dir.join("absent"), dir.join("dangling")).unwrap();
+        for (root, found) in [
+            ("file", "is a regular file, not a directory"),
+            ("fifo", "is a FIFO, not a directory"),
+            ("link", "resolved to"),
+            ("dangling", "is a symlink to"),
+        ] {
+            let e = DecoFS::new(dir.join(root)).err().unwrap();
+            let message = e.to_string();
+            assert!(
+                message.contains(&format!("{:?}", dir.join(root))),
+                "{}",
+                message
+            );
+            assert!(message.contains(found), "{}", message);
         }
+        let e = DecoFS::new(dir.join("link")).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotADirectory);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
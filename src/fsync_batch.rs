@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::libc_wrapper;
+
+/// The fsyncs of handles on one backing filesystem.
+#[derive(Default)]
+struct Group {
+    /// The batch taking new requests, and whether a request is waiting out the window to sync it.
+    collecting: u64,
+    leading: bool,
+    /// How many requests each unfinished batch has, and the outcome of those synced.
+    waiting: HashMap<u64, usize>,
+    results: HashMap<u64, Result<(), i32>>,
+}
+
+/// A group, with the condition signalled as each of its batches is synced.
+type SyncedGroup = Arc<(Mutex<Group>, Condvar)>;
+
+/// Group commit for fsync: requests arriving within `window` of one another on the same
+/// backing filesystem are answered by a single `syncfs` of it, made once the window has passed.
+/// The first request of each batch makes the sync, and every request in it waits for that to
+/// finish, so none succeeds before its data is durable.
+pub struct FsyncBatcher {
+    window: Duration,
+    groups: Mutex<HashMap<u64, SyncedGroup>>,
+}
+
+impl FsyncBatcher {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Make everything written to `fh`, and to the rest of its filesystem, durable.
+    pub fn sync(&self, fh: u64) -> io::Result<()> {
+        let dev = libc_wrapper::fstat(fh)?.st_dev;
+        let group = self.groups.lock().unwrap().entry(dev).or_default().clone();
+        let (group, synced) = &*group;
+
+        let mut state = group.lock().unwrap();
+        let batch = state.collecting;
+        *state.waiting.entry(batch).or_insert(0) += 1;
+        if !state.leading {
+            state.leading = true;
+            drop(state);
+            thread::sleep(self.window);
+            // Requests from here on may have written after the sync starts, so go in the next
+            // batch. Its sync can run alongside this one.
+            state = group.lock().unwrap();
+            state.collecting += 1;
+            state.leading = false;
+            debug!(
+                "fsync batch {} on device {:#x}: syncing for {} requests",
+                batch, dev, state.waiting[&batch]
+            );
+            drop(state);
+            let result =
+                libc_wrapper::syncfs(fh).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO));
+            state = group.lock().unwrap();
+            state.results.insert(batch, result);
+            synced.notify_all();
+        }
+        while !state.results.contains_key(&batch) {
+            state = synced.wait(state).unwrap();
+        }
+        let result = state.results[&batch];
+        let waiting = state.waiting.get_mut(&batch).unwrap();
+        *waiting -= 1;
+        if *waiting == 0 {
+            state.waiting.remove(&batch);
+            state.results.remove(&batch);
+        }
+        result.map_err(io::Error::from_raw_os_error)
+    }
+}
@@ -0,0 +1,458 @@
+use std::io;
+
+// A gzip decoder (RFC 1952) around a DEFLATE decoder (RFC 1951), decoding canonical Huffman
+// codes a bit at a time in the manner of zlib's puff. It favours being small over being fast.
+
+const MAX_BITS: usize = 15;
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are given in by a dynamic block.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("gzip: {}", message))
+}
+
+/// Fail with EFBIG unless `len` bytes of output are within `limit`.
+fn check_limit(len: usize, limit: usize) -> io::Result<()> {
+    if len > limit {
+        return Err(io::Error::from_raw_os_error(libc::EFBIG));
+    }
+    Ok(())
+}
+
+/// Reads a byte slice a bit at a time, least significant bit first.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| corrupt("unexpected end of data"))?;
+            self.buffer |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u32 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary, returning the rest of the data from there.
+    fn align(&mut self) -> &'a [u8] {
+        self.buffer = 0;
+        self.count = 0;
+        &self.data[self.pos..]
+    }
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and the symbols they
+/// stand for, in code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits<'_>) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+/// Decode the symbols of one compressed block into `out`, which may grow to `limit` bytes.
+/// Back-references may reach no further than `start`, where the current member's output begins.
+fn inflate_codes(
+    bits: &mut Bits<'_>,
+    out: &mut Vec<u8>,
+    start: usize,
+    limit: usize,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = lengths.decode(bits)? as usize;
+        if symbol < 256 {
+            check_limit(out.len() + 1, limit)?;
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(corrupt("invalid length code"));
+        }
+        let len = LENGTH_BASE[symbol] as usize + bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+        let symbol = distances.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(corrupt("invalid distance code"));
+        }
+        let distance =
+            DISTANCE_BASE[symbol] as usize + bits.bits(DISTANCE_EXTRA[symbol] as u32)? as usize;
+        if distance > out.len() - start {
+            return Err(corrupt("distance too far back"));
+        }
+        check_limit(out.len() + len, limit)?;
+        // The copy may overlap what it produces, so goes a byte at a time.
+        let from = out.len() - distance;
+        for i in 0..len {
+            out.push(out[from + i]);
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut Bits<'_>) -> io::Result<(Huffman, Huffman)> {
+    let nlen = bits.bits(5)? as usize + 257;
+    let ndist = bits.bits(5)? as usize + 1;
+    let ncode = bits.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(corrupt("too many codes"));
+    }
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[index] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_lengths.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match index {
+                0 => return Err(corrupt("repeat with no previous length")),
+                _ => (lengths[index - 1], 3 + bits.bits(2)? as usize),
+            },
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err(corrupt("too many lengths"));
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(corrupt("no end-of-block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen]),
+        Huffman::new(&lengths[nlen..]),
+    ))
+}
+
+/// Decode the raw DEFLATE stream at the start of `data` onto the end of `out`, which may grow
+/// to `limit` bytes, returning how many bytes of `data` it took up.
+fn inflate(data: &[u8], out: &mut Vec<u8>, limit: usize) -> io::Result<usize> {
+    let start = out.len();
+    let mut bits = Bits::new(data);
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                let rest = bits.align();
+                if rest.len() < 4 {
+                    return Err(corrupt("unexpected end of data"));
+                }
+                let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+                let nlen = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+                if len != !nlen & 0xffff {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                let stored = rest
+                    .get(4..4 + len)
+                    .ok_or_else(|| corrupt("unexpected end of data"))?;
+                check_limit(out.len() + len, limit)?;
+                out.extend_from_slice(stored);
+                bits.pos += 4 + len;
+            }
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                inflate_codes(&mut bits, out, start, limit, &lengths, &distances)?;
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(&mut bits)?;
+                inflate_codes(&mut bits, out, start, limit, &lengths, &distances)?;
+            }
+            _ => return Err(corrupt("invalid block type")),
+        }
+        if last {
+            bits.align();
+            return Ok(bits.pos);
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    !data.iter().fold(!0u32, |c, &byte| {
+        table[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+/// The skip past a NUL-terminated field at the start of `data`.
+fn skip_string(data: &[u8]) -> io::Result<usize> {
+    data.iter()
+        .position(|&b| b == 0)
+        .map(|end| end + 1)
+        .ok_or_else(|| corrupt("unterminated header field"))
+}
+
+/// Decompress `data`, the content of a gzip file, which may hold several members one after
+/// another. Each member's length and checksum are checked. Fails with EFBIG as soon as the
+/// output would exceed `limit` bytes, rather than holding however much a small file expands to.
+pub fn decompress(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut rest = data;
+    loop {
+        if rest.len() < 10 || rest[0] != 0x1f || rest[1] != 0x8b {
+            return Err(corrupt("not in gzip format"));
+        }
+        if rest[2] != 8 {
+            return Err(corrupt("unknown compression method"));
+        }
+        let flags = rest[3];
+        let mut pos = 10;
+        if flags & FEXTRA != 0 {
+            let len = rest
+                .get(pos..pos + 2)
+                .ok_or_else(|| corrupt("unexpected end of data"))?;
+            pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+        }
+        for field in [FNAME, FCOMMENT] {
+            if flags & field != 0 {
+                pos += skip_string(rest.get(pos..).unwrap_or_default())?;
+            }
+        }
+        if flags & FHCRC != 0 {
+            pos += 2;
+        }
+        let body = rest
+            .get(pos..)
+            .ok_or_else(|| corrupt("unexpected end of data"))?;
+
+        let start = out.len();
+        let used = inflate(body, &mut out, limit)?;
+        let trailer = body
+            .get(used..used + 8)
+            .ok_or_else(|| corrupt("unexpected end of data"))?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if crc != crc32(&out[start..]) || size != (out.len() - start) as u32 {
+            return Err(corrupt("checksum mismatch"));
+        }
+        rest = &body[used + 8..];
+        if rest.is_empty() {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "stored block", compressed at level 0.
+    const STORED: [u8; 35] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x0c, 0x00, 0xf3, 0xff,
+        0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c, 0x6f, 0x63, 0x6b, 0x94, 0xa3, 0x24,
+        0x3d, 0x0c, 0x00, 0x00, 0x00,
+    ];
+    /// "fixed Huffman codes, fixed Huffman codes", which zlib codes with the fixed codes and a
+    /// back-reference.
+    const FIXED: [u8; 44] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0xcb, 0xac, 0x48, 0x4d,
+        0x51, 0xf0, 0x28, 0x4d, 0x4b, 0xcb, 0x4d, 0xcc, 0x53, 0x48, 0xce, 0x4f, 0x49, 0x2d, 0xd6,
+        0x51, 0x48, 0xc3, 0x14, 0x04, 0x00, 0x05, 0x64, 0x4f, 0x4d, 0x28, 0x00, 0x00, 0x00,
+    ];
+    const DYNAMIC_TEXT: &[u8] = b"length block the length huffman distance length deflate \
+        distance tree tree tree deflate length of code";
+    /// `DYNAMIC_TEXT`, which zlib codes with dynamic codes.
+    const DYNAMIC: [u8; 79] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4d, 0xca, 0x4b, 0x0e, 0x80,
+        0x50, 0x08, 0x43, 0xd1, 0xad, 0xb0, 0x35, 0x84, 0x22, 0x46, 0x84, 0xe4, 0x89, 0xfb, 0x77,
+        0xe0, 0x77, 0xd2, 0xc1, 0xe9, 0x0d, 0xe4, 0xdc, 0x4e, 0x53, 0x94, 0xac, 0xd4, 0x0e, 0x8a,
+        0x0b, 0xfc, 0x30, 0xdb, 0x38, 0x49, 0x97, 0xbd, 0x39, 0xe5, 0x75, 0x85, 0x05, 0x37, 0x3e,
+        0xef, 0x81, 0xff, 0x3c, 0xff, 0x9d, 0x97, 0x91, 0x94, 0xe2, 0x04, 0xc3, 0x70, 0xf8, 0xc3,
+        0x66, 0x00, 0x00, 0x00,
+    ];
+    /// A member whose only symbol copies from one byte back, before anything it has produced,
+    /// with the trailer it would have were that the last byte of an earlier member, "k".
+    const BACK_REFERENCE: [u8; 21] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x03, 0x02, 0x00, 0x6f, 0xf7,
+        0xaa, 0xe7, 0x03, 0x00, 0x00, 0x00,
+    ];
+    const LIMIT: usize = 1 << 20;
+
+    fn block_type(gzip: &[u8]) -> u8 {
+        (gzip[10] >> 1) & 3
+    }
+
+    fn assert_invalid(data: &[u8]) {
+        let e = decompress(data, LIMIT).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", e);
+    }
+
+    #[test]
+    fn decodes_stored_blocks() {
+        assert_eq!(block_type(&STORED), 0);
+        assert_eq!(decompress(&STORED, LIMIT).unwrap(), b"stored block");
+    }
+
+    #[test]
+    fn decodes_fixed_huffman_blocks() {
+        assert_eq!(block_type(&FIXED), 1);
+        assert_eq!(
+            decompress(&FIXED, LIMIT).unwrap(),
+            b"fixed Huffman codes, fixed Huffman codes"
+        );
+    }
+
+    #[test]
+    fn decodes_dynamic_huffman_blocks() {
+        assert_eq!(block_type(&DYNAMIC), 2);
+        assert_eq!(decompress(&DYNAMIC, LIMIT).unwrap(), DYNAMIC_TEXT);
+    }
+
+    #[test]
+    fn decodes_members_one_after_another() {
+        let data = [&STORED[..], &FIXED, &DYNAMIC].concat();
+        let expected = [
+            &b"stored block"[..],
+            b"fixed Huffman codes, fixed Huffman codes",
+            DYNAMIC_TEXT,
+        ]
+        .concat();
+        assert_eq!(decompress(&data, LIMIT).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_back_references_into_an_earlier_member() {
+        assert_invalid(&BACK_REFERENCE);
+        assert_invalid(&[&STORED[..], &BACK_REFERENCE].concat());
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum_or_length() {
+        let trailer = FIXED.len() - 8;
+        let mut bad_crc = FIXED;
+        bad_crc[trailer] ^= 1;
+        assert_invalid(&bad_crc);
+        let mut bad_size = FIXED;
+        bad_size[trailer + 4] ^= 1;
+        assert_invalid(&bad_size);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        for gzip in [&STORED[..], &FIXED, &DYNAMIC] {
+            for len in 0..gzip.len() {
+                assert_invalid(&gzip[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert_invalid(b"plain text, not gzip");
+    }
+
+    #[test]
+    fn fails_with_efbig_past_the_limit() {
+        for gzip in [&STORED[..], &FIXED, &DYNAMIC] {
+            let len = decompress(gzip, LIMIT).unwrap().len();
+            assert_eq!(decompress(gzip, len).unwrap().len(), len);
+            let e = decompress(gzip, len - 1).unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::EFBIG));
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+/// A translation of user or group ids between the backing store and clients. Ids without an
+/// entry pass through unchanged in both directions.
+#[derive(Default)]
+pub struct IdMap {
+    to_client: HashMap<u32, u32>,
+}
+
+impl IdMap {
+    /// A map taking each backing id to the client id paired with it.
+    pub fn new(to_client: HashMap<u32, u32>) -> Self {
+        Self { to_client }
+    }
+
+    /// The id a client sees for the backing id `id`.
+    pub fn to_client(&self, id: u32) -> u32 {
+        self.to_client.get(&id).copied().unwrap_or(id)
+    }
+
+    /// The backing id for the client id `id`, if it is mapped. Where several backing ids map to
+    /// it, the lowest is used, so the choice is at least consistent.
+    pub fn mapped_to_backing(&self, id: u32) -> Option<u32> {
+        self.to_client
+            .iter()
+            .filter(|(_, &client)| client == id)
+            .map(|(&backing, _)| backing)
+            .min()
+    }
+
+    /// The backing id for the client id `id`.
+    pub fn to_backing(&self, id: u32) -> u32 {
+        self.mapped_to_backing(id).unwrap_or(id)
+    }
+}
@@ -1,36 +1,235 @@
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
 use std::io;
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-pub fn open(path: &PathBuf, flags: libc::c_int) -> io::Result<u64> {
-    let cstr = CString::new(path.as_os_str().as_bytes())?;
-    let result = unsafe { libc::open(cstr.as_ptr(), flags) };
+/// Evaluate the syscall `$call` again for as long as it fails with EINTR, giving the result of
+/// the first attempt that wasn't interrupted.
+///
+/// Every call that can be interrupted is made through it, but for `close` and `closedir`: on
+/// Linux the descriptor is released even when it is interrupted, so a retry could close a
+/// descriptor another thread has just been given. `umask`, `readdir`, `dirfd` and the inotify
+/// calls don't wait on anything a signal could interrupt, so are made directly.
+macro_rules! retry_eintr {
+    ($call:expr) => {
+        loop {
+            let result = $call;
+            if result != -1 || io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                break result;
+            }
+        }
+    };
+}
+
+/// `bytes`, a path or name, as a C string. One with a NUL in it fails with EINVAL, as the
+/// syscall would if it could be passed one, rather than with an error carrying no errno.
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+pub fn open(path: &PathBuf, flags: libc::c_int, mode: libc::mode_t) -> io::Result<u64> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    // The mode is only meaningful when a file may be created.
+    let mode = if flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE {
+        mode
+    } else {
+        0
+    };
+    let result = retry_eintr!(unsafe { libc::open(cstr.as_ptr(), flags, mode as libc::c_uint) });
     if -1 == result {
         let e = io::Error::last_os_error();
-        error!("lstat({:?}): {}", path, e);
+        error!("open({:?}, {:#x}, {:#o}): {}", path, flags, mode, e);
         Err(e)
     } else {
         Ok(result as u64)
     }
 }
 
-pub fn close(fh: u64) -> io::Result<i32> {
+/// Open `name` relative to the directory open as `dirfd`, as with `open`.
+pub fn openat(dirfd: u64, name: &OsStr, flags: libc::c_int, mode: libc::mode_t) -> io::Result<u64> {
+    let cstr = c_string(name.as_bytes())?;
+    let mode = if flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE {
+        mode
+    } else {
+        0
+    };
+    let result = retry_eintr!(unsafe {
+        libc::openat(
+            dirfd as libc::c_int,
+            cstr.as_ptr(),
+            flags,
+            mode as libc::c_uint,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!(
+            "openat({:?}, {:?}, {:#x}, {:#o}): {}",
+            dirfd, name, flags, mode, e
+        );
+        Err(e)
+    } else {
+        Ok(result as u64)
+    }
+}
+
+/// Close `fh`, which is released whether or not this fails. Errors such as EIO or ENOSPC
+/// report writes to it that failed after the fact.
+pub fn close(fh: u64) -> io::Result<()> {
+    // Never retried on EINTR; see retry_eintr.
     let result = unsafe { libc::close(fh as libc::c_int) };
     if -1 == result {
         let e = io::Error::last_os_error();
-        error!("close({:?}: {}", fh, e);
+        error!("close({:?}): {}", fh, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn fcntl_getfd(fh: u64) -> io::Result<libc::c_int> {
+    let result = retry_eintr!(unsafe { libc::fcntl(fh as libc::c_int, libc::F_GETFD) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("fcntl({:?}, F_GETFD): {}", fh, e);
+        Err(e)
+    } else {
+        Ok(result)
+    }
+}
+
+pub fn fsync(fh: u64) -> io::Result<()> {
+    let result = retry_eintr!(unsafe { libc::fsync(fh as libc::c_int) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("fsync({:?}): {}", fh, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn ftruncate(fh: u64, size: u64) -> io::Result<()> {
+    let result = retry_eintr!(unsafe { libc::ftruncate(fh as libc::c_int, size as libc::off_t) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("ftruncate({:?}, {}): {}", fh, size, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn fdatasync(fh: u64) -> io::Result<()> {
+    let result = retry_eintr!(unsafe { libc::fdatasync(fh as libc::c_int) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("fdatasync({:?}): {}", fh, e);
         Err(e)
     } else {
-        Ok(0)
+        Ok(())
+    }
+}
+
+/// Write out everything cached for the filesystem holding `fh`.
+pub fn syncfs(fh: u64) -> io::Result<()> {
+    let result = retry_eintr!(unsafe { libc::syncfs(fh as libc::c_int) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("syncfs({:?}): {}", fh, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+/// A new descriptor for the same open file as `fh`, closed on exec.
+pub fn dup(fh: u64) -> io::Result<u64> {
+    let result = retry_eintr!(unsafe { libc::fcntl(fh as libc::c_int, libc::F_DUPFD_CLOEXEC, 0) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("fcntl({:?}, F_DUPFD_CLOEXEC): {}", fh, e);
+        Err(e)
+    } else {
+        Ok(result as u64)
+    }
+}
+
+pub fn pread(fh: u64, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let result = retry_eintr!(unsafe {
+        libc::pread(
+            fh as libc::c_int,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            offset as libc::off_t,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("pread({:?}, {:#x} @ {:#x}): {}", fh, buf.len(), offset, e);
+        Err(e)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub fn read(fh: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let result = retry_eintr!(unsafe {
+        libc::read(
+            fh as libc::c_int,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("read({:?}, {:#x}): {}", fh, buf.len(), e);
+        Err(e)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub fn write(fh: u64, buf: &[u8]) -> io::Result<usize> {
+    let result = retry_eintr!(unsafe {
+        libc::write(
+            fh as libc::c_int,
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("write({:?}, {:#x}): {}", fh, buf.len(), e);
+        Err(e)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub fn pwrite(fh: u64, buf: &[u8], offset: u64) -> io::Result<usize> {
+    let result = retry_eintr!(unsafe {
+        libc::pwrite(
+            fh as libc::c_int,
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            offset as libc::off_t,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("pwrite({:?}, {:#x} @ {:#x}): {}", fh, buf.len(), offset, e);
+        Err(e)
+    } else {
+        Ok(result as usize)
     }
 }
 
 pub fn fstat(fh: u64) -> io::Result<libc::stat> {
     let mut stat = MaybeUninit::<libc::stat>::uninit();
 
-    let result = unsafe { libc::fstat(fh as libc::c_int, stat.as_mut_ptr()) };
+    let result = retry_eintr!(unsafe { libc::fstat(fh as libc::c_int, stat.as_mut_ptr()) });
     if -1 == result {
         let e = io::Error::last_os_error();
         error!("fstat({:?}): {}", fh, e);
@@ -41,11 +240,26 @@ pub fn fstat(fh: u64) -> io::Result<libc::stat> {
     }
 }
 
+pub fn stat(path: &PathBuf) -> io::Result<libc::stat> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::stat(cstr.as_ptr(), stat.as_mut_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("stat({:?}): {}", path, e);
+        Err(e)
+    } else {
+        let stat = unsafe { stat.assume_init() };
+        Ok(stat)
+    }
+}
+
 pub fn lstat(path: &PathBuf) -> io::Result<libc::stat> {
     let mut stat = MaybeUninit::<libc::stat>::uninit();
 
-    let cstr = CString::new(path.as_os_str().as_bytes())?;
-    let result = unsafe { libc::lstat(cstr.as_ptr(), stat.as_mut_ptr()) };
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::lstat(cstr.as_ptr(), stat.as_mut_ptr()) });
     if -1 == result {
         let e = io::Error::last_os_error();
         error!("lstat({:?}): {}", path, e);
@@ -56,11 +270,57 @@ pub fn lstat(path: &PathBuf) -> io::Result<libc::stat> {
     }
 }
 
+/// Stat `name` relative to the directory open as `dirfd`.
+pub fn fstatat(dirfd: u64, name: &OsStr, flags: libc::c_int) -> io::Result<libc::stat> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+
+    let cstr = c_string(name.as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::fstatat(
+            dirfd as libc::c_int,
+            cstr.as_ptr(),
+            stat.as_mut_ptr(),
+            flags,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("fstatat({:?}, {:?}): {}", dirfd, name, e);
+        Err(e)
+    } else {
+        let stat = unsafe { stat.assume_init() };
+        Ok(stat)
+    }
+}
+
+pub fn statx(path: &PathBuf, flags: libc::c_int, mask: libc::c_uint) -> io::Result<libc::statx> {
+    let mut stat = MaybeUninit::<libc::statx>::uninit();
+
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            cstr.as_ptr(),
+            flags,
+            mask,
+            stat.as_mut_ptr(),
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("statx({:?}): {}", path, e);
+        Err(e)
+    } else {
+        let stat = unsafe { stat.assume_init() };
+        Ok(stat)
+    }
+}
+
 pub fn statfs(path: &PathBuf) -> io::Result<libc::statfs> {
-    let mut stat = MaybeUninit::<libc::statfs>::zeroed();
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
 
-    let cstr = CString::new(path.as_os_str().as_bytes())?;
-    let result = unsafe { libc::statfs(cstr.as_ptr(), stat.as_mut_ptr()) };
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::statfs(cstr.as_ptr(), stat.as_mut_ptr()) });
 
     if -1 == result {
         let e = io::Error::last_os_error();
@@ -71,3 +331,449 @@ pub fn statfs(path: &PathBuf) -> io::Result<libc::statfs> {
         Ok(stat)
     }
 }
+
+pub fn mkdir(path: &PathBuf, mode: libc::mode_t) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::mkdir(cstr.as_ptr(), mode) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("mkdir({:?}, {:#o}): {}", path, mode, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+/// Set the process file mode creation mask, returning the previous one. This cannot fail.
+pub fn umask(mask: libc::mode_t) -> libc::mode_t {
+    unsafe { libc::umask(mask) }
+}
+
+pub fn mknod(path: &PathBuf, mode: libc::mode_t, rdev: libc::dev_t) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::mknod(cstr.as_ptr(), mode, rdev) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("mknod({:?}, {:#o}, {:#x}): {}", path, mode, rdev, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn rmdir(path: &PathBuf) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::rmdir(cstr.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("rmdir({:?}): {}", path, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn unlink(path: &PathBuf) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::unlink(cstr.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("unlink({:?}): {}", path, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn rename(from: &PathBuf, to: &PathBuf) -> io::Result<()> {
+    let from_cstr = c_string(from.as_os_str().as_bytes())?;
+    let to_cstr = c_string(to.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::rename(from_cstr.as_ptr(), to_cstr.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("rename({:?}, {:?}): {}", from, to, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+/// Rename `from` to `to`, failing with EEXIST rather than replacing anything already there.
+/// EEXIST isn't logged, being what a caller trying names in turn expects.
+pub fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let from_cstr = c_string(from.as_os_str().as_bytes())?;
+    let to_cstr = c_string(to.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_cstr.as_ptr(),
+            libc::AT_FDCWD,
+            to_cstr.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EEXIST) {
+            error!("renameat2({:?}, {:?}, RENAME_NOREPLACE): {}", from, to, e);
+        }
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn symlink(target: &Path, linkpath: &PathBuf) -> io::Result<()> {
+    let target_cstr = c_string(target.as_os_str().as_bytes())?;
+    let link_cstr = c_string(linkpath.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::symlink(target_cstr.as_ptr(), link_cstr.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("symlink({:?}, {:?}): {}", target, linkpath, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn readlink(path: &PathBuf) -> io::Result<Vec<u8>> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let mut buf = Vec::<u8>::with_capacity(256);
+    loop {
+        let result = retry_eintr!(unsafe {
+            libc::readlink(
+                cstr.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.capacity(),
+            )
+        });
+        if -1 == result {
+            let e = io::Error::last_os_error();
+            error!("readlink({:?}): {}", path, e);
+            return Err(e);
+        }
+        let len = result as usize;
+        // A completely filled buffer may mean the target was truncated, so grow and retry.
+        if len < buf.capacity() {
+            unsafe { buf.set_len(len) };
+            return Ok(buf);
+        }
+        buf.reserve(buf.capacity() * 2);
+    }
+}
+
+pub fn link(oldpath: &PathBuf, newpath: &PathBuf) -> io::Result<()> {
+    let old_cstr = c_string(oldpath.as_os_str().as_bytes())?;
+    let new_cstr = c_string(newpath.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::link(old_cstr.as_ptr(), new_cstr.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("link({:?}, {:?}): {}", oldpath, newpath, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+/// Change the owner of `path`, without following a final symlink. A `None` id is left as it is.
+pub fn lchown(
+    path: &PathBuf,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let uid = uid.unwrap_or(libc::uid_t::MAX);
+    let gid = gid.unwrap_or(libc::gid_t::MAX);
+    let result = retry_eintr!(unsafe { libc::lchown(cstr.as_ptr(), uid, gid) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("lchown({:?}, {}, {}): {}", path, uid, gid, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+/// Set the access and modification times of `path`, without following a final symlink.
+pub fn utimensat(path: &PathBuf, times: &[libc::timespec; 2]) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            cstr.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("utimensat({:?}): {}", path, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn futimens(fh: u64, times: &[libc::timespec; 2]) -> io::Result<()> {
+    let result = retry_eintr!(unsafe { libc::futimens(fh as libc::c_int, times.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("futimens({:?}): {}", fh, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn opendir(path: &PathBuf) -> io::Result<u64> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    // As retry_eintr, for a call that fails with a null pointer rather than -1.
+    let result = loop {
+        let result = unsafe { libc::opendir(cstr.as_ptr()) };
+        if !result.is_null() || io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            break result;
+        }
+    };
+    if result.is_null() {
+        let e = io::Error::last_os_error();
+        error!("opendir({:?}): {}", path, e);
+        Err(e)
+    } else {
+        Ok(result as u64)
+    }
+}
+
+pub fn readdir(dh: u64) -> io::Result<Option<libc::dirent>> {
+    // readdir signals both end-of-directory and failure with NULL; only the latter sets errno.
+    unsafe { *libc::__errno_location() = 0 };
+    let result = unsafe { libc::readdir(dh as *mut libc::DIR) };
+    if result.is_null() {
+        let e = io::Error::last_os_error();
+        if Some(0) == e.raw_os_error() {
+            Ok(None)
+        } else {
+            error!("readdir({:#x}): {}", dh, e);
+            Err(e)
+        }
+    } else {
+        let entry = unsafe { *result };
+        Ok(Some(entry))
+    }
+}
+
+/// The file descriptor underlying the directory stream `dh`, which stays owned by the stream.
+pub fn dirfd(dh: u64) -> io::Result<u64> {
+    let result = unsafe { libc::dirfd(dh as *mut libc::DIR) };
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("dirfd({:#x}): {}", dh, e);
+        Err(e)
+    } else {
+        Ok(result as u64)
+    }
+}
+
+pub fn closedir(dh: u64) -> io::Result<()> {
+    let result = unsafe { libc::closedir(dh as *mut libc::DIR) };
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("closedir({:#x}): {}", dh, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn lgetxattr(path: &PathBuf, name: &OsStr, buf: &mut [u8]) -> io::Result<usize> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let name_cstr = c_string(name.as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::lgetxattr(
+            cstr.as_ptr(),
+            name_cstr.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("lgetxattr({:?}, {:?}): {}", path, name, e);
+        Err(e)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub fn lsetxattr(path: &PathBuf, name: &OsStr, value: &[u8], flags: libc::c_int) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let name_cstr = c_string(name.as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::lsetxattr(
+            cstr.as_ptr(),
+            name_cstr.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            flags,
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("lsetxattr({:?}, {:?}): {}", path, name, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn llistxattr(path: &PathBuf, buf: &mut [u8]) -> io::Result<usize> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe {
+        libc::llistxattr(
+            cstr.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("llistxattr({:?}): {}", path, e);
+        Err(e)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub fn lremovexattr(path: &PathBuf, name: &OsStr) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let name_cstr = c_string(name.as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::lremovexattr(cstr.as_ptr(), name_cstr.as_ptr()) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("lremovexattr({:?}, {:?}): {}", path, name, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn access(path: &PathBuf, mode: libc::c_int) -> io::Result<()> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = retry_eintr!(unsafe { libc::access(cstr.as_ptr(), mode) });
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        debug!("access({:?}, {:#o}): {}", path, mode, e);
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn inotify_init() -> io::Result<u64> {
+    let result = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        error!("inotify_init1: {}", e);
+        Err(e)
+    } else {
+        Ok(result as u64)
+    }
+}
+
+pub fn inotify_add_watch(fh: u64, path: &PathBuf, mask: u32) -> io::Result<libc::c_int> {
+    let cstr = c_string(path.as_os_str().as_bytes())?;
+    let result = unsafe { libc::inotify_add_watch(fh as libc::c_int, cstr.as_ptr(), mask) };
+    if -1 == result {
+        let e = io::Error::last_os_error();
+        debug!("inotify_add_watch({:?}, {:#x}): {}", path, mask, e);
+        Err(e)
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_signal(_: libc::c_int) {
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn opens_interrupted_by_a_signal_are_retried() {
+        // Without SA_RESTART, so that the kernel fails the blocked open with EINTR rather than
+        // restarting it itself.
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = count_signal as extern "C" fn(libc::c_int) as usize;
+        assert_eq!(
+            unsafe { libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) },
+            0
+        );
+        // Opening a FIFO for reading blocks until a writer opens it too.
+        let path = std::env::temp_dir().join(format!("decofs-eintr-{}", std::process::id()));
+        let cstr = c_string(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cstr.as_ptr(), 0o600) }, 0);
+
+        let (started, thread_id) = mpsc::channel();
+        let fifo = path.clone();
+        let blocked = thread::spawn(move || {
+            started.send(unsafe { libc::pthread_self() }).unwrap();
+            open(&fifo, libc::O_RDONLY, 0)
+        });
+        let thread_id = thread_id.recv().unwrap();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(unsafe { libc::pthread_kill(thread_id, libc::SIGUSR1) }, 0);
+        }
+        thread::sleep(Duration::from_millis(20));
+        // Non-blocking, so that this fails with ENXIO rather than hanging if the reader gave up.
+        let writer = open(&path, libc::O_WRONLY | libc::O_NONBLOCK, 0).unwrap();
+
+        let reader = blocked.join().unwrap().unwrap();
+        assert!(SIGNALS.load(Ordering::SeqCst) > 0);
+        close(reader).unwrap();
+        close(writer).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn close_errors_keep_their_errno() {
+        // u64::MAX is -1 as a descriptor, and 1 << 30 is past any descriptor limit, so neither
+        // can be open, unlike a descriptor closed here that another test could be handed.
+        for fh in [u64::MAX, 1 << 30] {
+            assert_eq!(close(fh).unwrap_err().raw_os_error(), Some(libc::EBADF));
+        }
+    }
+
+    #[test]
+    fn fsync_and_fdatasync_sync_open_files() {
+        let path = std::env::temp_dir().join(format!("decofs-fsync-{}", std::process::id()));
+        let fh = open(&path, libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC, 0o600).unwrap();
+        assert!(fsync(fh).is_ok());
+        assert!(fdatasync(fh).is_ok());
+        close(fh).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fsync_and_fdatasync_fail_with_ebadf_without_a_descriptor() {
+        // Descriptors that can't be open, as in close_errors_keep_their_errno.
+        for fh in [u64::MAX, 1 << 30] {
+            assert_eq!(fsync(fh).unwrap_err().raw_os_error(), Some(libc::EBADF));
+            assert_eq!(fdatasync(fh).unwrap_err().raw_os_error(), Some(libc::EBADF));
+        }
+    }
+
+    #[test]
+    fn paths_with_a_nul_fail_with_einval() {
+        let path = PathBuf::from(OsStr::from_bytes(b"a\0b"));
+        let e = lstat(&path).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+    }
+}
@@ -1,23 +1,50 @@
 use chrono::Local;
+use clap::builder::TypedValueParser;
+use clap::{CommandFactory, Parser};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::ffi::{OsStr, OsString};
+use std::io;
 use std::path::PathBuf;
-use std::{env, io};
+use std::thread;
+use std::time::Duration;
 
 #[macro_use]
 extern crate log;
 
+mod aligned_buffer;
+mod attr;
+mod builder;
+mod case_fold;
+mod content_cache;
+mod control;
 mod deco;
+mod fsync_batch;
+mod gzip;
+mod id_map;
 mod libc_wrapper;
+mod overlay;
+mod rate_limit;
+mod readahead;
+mod root_monitor;
+mod stat_cache;
+mod stats;
+mod transform;
 mod unmanaged_file;
+mod watcher;
+mod write_buffer;
 
 struct ConsoleLogger;
 
 impl log::Log for ConsoleLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
         println!(
             "{} {} {} - {}",
             Local::now().format("%Y-%m-%dT%H:%M:%S%z"),
@@ -32,33 +59,489 @@ impl log::Log for ConsoleLogger {
 
 static LOGGER: ConsoleLogger = ConsoleLogger;
 
+/// Pass-through filesystem, to assist with disk decommissioning.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Directory to expose through the mount
+    target: PathBuf,
+    /// Where to mount the filesystem
+    mountpoint: PathBuf,
+    /// Further directory to merge beneath TARGET; earlier directories shadow later ones (may be
+    /// repeated)
+    #[arg(long = "union", value_name = "DIR")]
+    union_roots: Vec<PathBuf>,
+    /// Expose only this directory within TARGET (and any --union directories) as the root of the
+    /// mount
+    #[arg(long, value_name = "PATH")]
+    prefix: Option<PathBuf>,
+    /// Writable directory to layer over TARGET (and any --union directories), which are then
+    /// left untouched: changes go to DIR, copying files up from beneath as they are modified
+    #[arg(long, value_name = "DIR")]
+    upper: Option<PathBuf>,
+    /// Additional mount options, comma-separated, passed through to FUSE (may be repeated)
+    #[arg(short = 'o', long = "option", value_name = "OPTION", value_parser = parse_mount_options)]
+    options: Vec<String>,
+    /// Filesystem name reported for the mount
+    #[arg(long, default_value = "decofs")]
+    fsname: String,
+    /// Allow users other than the mounting user to access the mount (needs user_allow_other in
+    /// /etc/fuse.conf when not run as root)
+    #[arg(long)]
+    allow_other: bool,
+    /// Have the kernel check permissions against the reported modes and owners, rather than
+    /// leaving checks to the source filesystem
+    #[arg(long)]
+    default_permissions: bool,
+    /// Largest read request the kernel should send, in bytes
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    max_read: Option<u32>,
+    /// Largest write request the kernel should send, in bytes, from 4096 to 16 MiB. The kernel
+    /// allows at most 1 MiB by default, however large this is
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(4096..=16 << 20))]
+    max_write: Option<u32>,
+    /// Bypass the kernel's page cache for every file, passing reads and writes through as the
+    /// client made them (files opened with O_DIRECT always are)
+    #[arg(long)]
+    direct_io: bool,
+    /// How long the kernel may cache attributes and entries, in milliseconds [default: 1000]
+    #[arg(long, value_name = "MILLISECONDS")]
+    ttl: Option<u64>,
+    /// Reject all mutating operations
+    #[arg(long)]
+    read_only: bool,
+    /// Decode file content on read by XORing it with this key
+    #[arg(long, value_name = "KEY", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    xor_key: Option<String>,
+    /// Only XOR files with this extension (may be repeated)
+    #[arg(long = "xor-extension", value_name = "EXT", requires = "xor_key")]
+    xor_extensions: Vec<String>,
+    /// Decompress .gz files on read, showing their uncompressed content and size
+    #[arg(long)]
+    gunzip: bool,
+    /// Largest size to decompress a .gz file to; opening or listing one that would be larger
+    /// fails with EFBIG
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 64 << 20,
+        requires = "gunzip",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    gunzip_max_size: u64,
+    /// Hide entries whose name matches this glob pattern (may be repeated)
+    #[arg(long = "hide", value_name = "PATTERN", value_parser = glob::Pattern::new)]
+    hide_patterns: Vec<glob::Pattern>,
+    /// Cache attributes for this many milliseconds to save repeated lstat calls; only safe when
+    /// the source tree isn't modified other than through the mount
+    #[arg(long, value_name = "MILLISECONDS")]
+    stat_cache: Option<u64>,
+    /// Keep the content of small files in memory once read, up to this many bytes in all
+    #[arg(long, value_name = "BYTES")]
+    content_cache: Option<u64>,
+    /// Largest file to keep in the content cache
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 65536,
+        requires = "content_cache"
+    )]
+    content_cache_max_file: u64,
+    /// Watch the source tree with inotify, so that the stat and content caches notice changes
+    /// made other than through the mount (costs one watch per directory)
+    #[arg(long)]
+    watch: bool,
+    /// Fall back to a case-insensitive match when a path doesn't exist as given
+    #[arg(long)]
+    case_insensitive: bool,
+    /// Show symlinks to absolute paths inside the source tree as relative links within the mount
+    #[arg(long)]
+    relative_links: bool,
+    /// Show symlinks in the source tree as the files and directories they point to
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// List directories sorted by name rather than in the source filesystem's order
+    #[arg(long)]
+    sort_entries: bool,
+    /// Let the kernel keep directory listings between opens; only safe if the source tree
+    /// isn't modified other than through the mount
+    #[arg(long)]
+    cache_dirs: bool,
+    /// Create files, device nodes, FIFOs and sockets with these octal permissions, whatever the
+    /// client asks for
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    file_mode: Option<u32>,
+    /// Create directories with these octal permissions, whatever the client asks for
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+    /// Clear these octal permission bits from everything created, except where a mode is forced
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    umask: Option<u32>,
+    /// Show files owned by backing uid BACKING as owned by uid CLIENT, and the reverse when
+    /// changing ownership (may be repeated)
+    #[arg(long = "map-uid", value_name = "BACKING:CLIENT", value_parser = parse_id_pair)]
+    uid_map: Vec<(u32, u32)>,
+    /// Show files with backing gid BACKING as having gid CLIENT, and the reverse when changing
+    /// ownership (may be repeated)
+    #[arg(long = "map-gid", value_name = "BACKING:CLIENT", value_parser = parse_id_pair)]
+    gid_map: Vec<(u32, u32)>,
+    /// Log mutations at info level instead of carrying them out, replying with success (the
+    /// default) or EROFS
+    #[arg(
+        long,
+        value_name = "REPLY",
+        num_args = 0..=1,
+        default_missing_value = "succeed",
+        value_parser = clap::builder::PossibleValuesParser::new(["succeed", "erofs"])
+    )]
+    audit: Option<String>,
+    /// Serve operation counters from a read-only /.decofs-stats file
+    #[arg(long)]
+    stats_file: bool,
+    /// List the /.decofs-info file describing the mount in the root directory
+    #[arg(long)]
+    list_info: bool,
+    /// Move files unlinked through the mount into this directory, under timestamped names,
+    /// instead of deleting them; files already there are deleted
+    #[arg(long, value_name = "DIR")]
+    trash_dir: Option<PathBuf>,
+    /// Answer commands such as `stats` and `flush-cache` on a Unix socket at this path
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+    /// Coalesce small sequential writes into buffers of up to this many bytes; errors writing
+    /// them out are reported late, from flush, fsync or close
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    write_buffer: Option<u64>,
+    /// Most files and directories to have open at once for clients; further opens fail with
+    /// EMFILE
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_handles: Option<u64>,
+    /// Most bytes a second to read from the source tree, across all clients; reads over the
+    /// limit are delayed
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u64).range(1..))]
+    read_limit: Option<u64>,
+    /// Read this many bytes ahead in the background when a file is read sequentially
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    readahead: Option<u64>,
+    /// Split reads larger than this many bytes into pieces read from the source tree at once
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    parallel_read: Option<u64>,
+    /// Answer fsyncs arriving within this many milliseconds of one another with a single sync
+    /// of the source filesystem
+    #[arg(long, value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    fsync_batch: Option<u64>,
+    /// Fail reads and attribute lookups with ETIMEDOUT when the source takes longer than this
+    /// many milliseconds to answer
+    #[arg(long, value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    op_timeout: Option<u64>,
+    /// Fail every operation with this errno while the source root is unavailable, such as when
+    /// a network filesystem behind it disconnects
+    #[arg(
+        long,
+        value_name = "ERRNO",
+        value_parser = clap::builder::PossibleValuesParser::new(["eio", "enotconn"])
+    )]
+    missing_root: Option<String>,
+    /// Number of threads serving requests [default: available parallelism]. Each request holds a
+    /// thread for as long as the source takes to answer it, so sources with high latency, such as
+    /// network filesystems, are better served by more threads than cores; a local disk rarely
+    /// gains from more than the default
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Most verbose messages to log [default: RUST_LOG, or warn]
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_parser = clap::builder::PossibleValuesParser::new(
+            ["off", "error", "warn", "info", "debug", "trace"]
+        ).map(|level| level.parse::<log::LevelFilter>().unwrap())
+    )]
+    log_level: Option<log::LevelFilter>,
+}
+
+/// Parse octal permission bits, such as `664` or `0775`.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{:?} is not an octal mode between 0 and 7777", s)),
+    }
+}
+
+/// Mount options that may be passed through with `-o`, which take no value.
+const MOUNT_FLAGS: [&str; 15] = [
+    "rw",
+    "ro",
+    "allow_other",
+    "allow_root",
+    "auto_unmount",
+    "default_permissions",
+    "dev",
+    "nodev",
+    "suid",
+    "nosuid",
+    "exec",
+    "noexec",
+    "atime",
+    "noatime",
+    "sync",
+];
+/// Mount options that may be passed through with `-o`, which take one.
+const MOUNT_SETTINGS: [&str; 4] = ["fsname", "subtype", "max_read", "max_write"];
+
+/// Check comma-separated mount options against those known to be understood, rejecting others
+/// before mounting rather than leaving the kernel to refuse them, or worse, accept them.
+fn parse_mount_options(s: &str) -> Result<String, String> {
+    for option in s.split(',') {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        match value {
+            None if MOUNT_FLAGS.contains(&name) => {}
+            Some(value) if MOUNT_SETTINGS.contains(&name) && !value.is_empty() => {
+                let numeric = ["max_read", "max_write"].contains(&name);
+                if numeric && value.parse::<u32>().is_err() {
+                    return Err(format!(
+                        "{} must be a number of bytes, not {:?}",
+                        name, value
+                    ));
+                }
+            }
+            _ if MOUNT_FLAGS.contains(&name) => {
+                return Err(format!("mount option {:?} takes no value", name))
+            }
+            _ if MOUNT_SETTINGS.contains(&name) => {
+                return Err(format!(
+                    "mount option {:?} needs a value, as {}=VALUE",
+                    name, name
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "unknown mount option {:?}; expected one of {}, or {}=VALUE",
+                    option,
+                    MOUNT_FLAGS.join(", "),
+                    MOUNT_SETTINGS.join("=VALUE, ")
+                ))
+            }
+        }
+    }
+    Ok(s.to_string())
+}
+
+/// Parse a pair of numeric ids, such as `1000:0`.
+fn parse_id_pair(s: &str) -> Result<(u32, u32), String> {
+    let pair = s
+        .split_once(':')
+        .and_then(|(backing, client)| Some((backing.parse().ok()?, client.parse().ok()?)));
+    pair.ok_or_else(|| format!("{:?} is not a pair of numeric ids, BACKING:CLIENT", s))
+}
+
+impl Args {
+    /// Mount options, one per entry, in the form expected by `fuse_mt::spawn_mount`.
+    fn mount_options(&self) -> Vec<OsString> {
+        let mut options: Vec<OsString> = vec![
+            if self.read_only { "ro" } else { "rw" }.into(),
+            format!("fsname={}", self.fsname).into(),
+        ];
+        if self.allow_other {
+            options.push("allow_other".into());
+        }
+        if self.default_permissions {
+            options.push("default_permissions".into());
+        }
+        if let Some(max_read) = self.max_read {
+            options.push(format!("max_read={}", max_read).into());
+        }
+        if let Some(max_write) = self.max_write {
+            options.push(format!("max_write={}", max_write).into());
+        }
+        options.extend(
+            self.options
+                .iter()
+                .flat_map(|option| option.split(','))
+                .map(OsString::from),
+        );
+        options
+    }
+
+    /// The log level from `--log-level`, falling back to `RUST_LOG` and then `warn`.
+    fn log_level(&self) -> log::LevelFilter {
+        self.log_level
+            .or_else(|| std::env::var("RUST_LOG").ok()?.parse().ok())
+            .unwrap_or(log::LevelFilter::Warn)
+    }
+}
+
 fn main() -> io::Result<()> {
-    log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Debug);
+    let args = Args::parse();
 
-    let args: Vec<OsString> = env::args_os().collect();
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(args.log_level());
 
-    if args.len() != 3 {
-        println!(
-            "usage: {} <target> <mountpoint>",
-            &env::args().next().unwrap()
+    let mut builder = builder::DecoFSBuilder::new()
+        .source(args.target.clone())
+        .read_only(args.read_only)
+        .case_insensitive(args.case_insensitive)
+        .relative_links(args.relative_links)
+        .follow_symlinks(args.follow_symlinks)
+        .sort_entries(args.sort_entries)
+        .dir_cache(args.cache_dirs)
+        .watch(args.watch)
+        .direct_io(args.direct_io)
+        .stats_file(args.stats_file)
+        .list_info(args.list_info);
+    for root in &args.union_roots {
+        builder = builder.union(root.clone());
+    }
+    for pattern in &args.hide_patterns {
+        builder = builder.hide(pattern.clone());
+    }
+    for &(backing, client) in &args.uid_map {
+        builder = builder.map_uid(backing, client);
+    }
+    for &(backing, client) in &args.gid_map {
+        builder = builder.map_gid(backing, client);
+    }
+    if let Some(prefix) = &args.prefix {
+        builder = builder.prefix(prefix.clone());
+    }
+    if let Some(path) = &args.control_socket {
+        builder = builder.control_socket(path.clone());
+    }
+    if let Some(trash) = &args.trash_dir {
+        builder = builder.trash_dir(trash.clone());
+    }
+    if let Some(upper) = &args.upper {
+        builder = builder.upper(upper.clone());
+    }
+    if let Some(ms) = args.ttl {
+        builder = builder.ttl(Duration::from_millis(ms));
+    }
+    if let Some(key) = &args.xor_key {
+        let xor = || Box::new(transform::Xor::new(key.clone().into_bytes()));
+        if args.xor_extensions.is_empty() {
+            builder = builder.transform(xor());
+        }
+        for extension in &args.xor_extensions {
+            builder = builder.extension_transform(extension, transform::Transform::Blocks(xor()));
+        }
+    }
+    if args.gunzip {
+        builder = builder.extension_transform(
+            "gz",
+            transform::Transform::WholeFile(Box::new(transform::Gunzip::new(
+                args.gunzip_max_size as usize,
+            ))),
         );
-        ::std::process::exit(-1);
-    }
-
-    let filesystem = deco::DecoFS::new(PathBuf::from(args[1].clone()));
-    let options = [
-        "-o",
-        "rw",
-        "-o",
-        "fsname=decofs",
-        "-o",
-        "allow_other",
-        "-a",
-        "auto_mount",
-    ]
-    .iter()
-    .map(|o| o.as_ref())
-    .collect::<Vec<&OsStr>>();
-    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, 1), &args[2], &options)
+    }
+    if let Some(ms) = args.stat_cache {
+        builder = builder.stat_cache(Duration::from_millis(ms));
+    }
+    if let Some(bytes) = args.content_cache {
+        builder = builder.content_cache(bytes as usize, args.content_cache_max_file);
+    }
+    if let Some(bytes) = args.write_buffer {
+        builder = builder.write_buffer(bytes as usize);
+    }
+    if let Some(limit) = args.max_handles {
+        builder = builder.handle_limit(limit);
+    }
+    if let Some(bytes_per_sec) = args.read_limit {
+        builder = builder.read_limit(bytes_per_sec);
+    }
+    if let Some(window) = args.readahead {
+        builder = builder.readahead(window as usize);
+    }
+    if let Some(chunk) = args.parallel_read {
+        builder = builder.parallel_reads(chunk as usize);
+    }
+    if let Some(ms) = args.fsync_batch {
+        builder = builder.fsync_batch(Duration::from_millis(ms));
+    }
+    if let Some(ms) = args.op_timeout {
+        builder = builder.op_timeout(Duration::from_millis(ms));
+    }
+    match args.missing_root.as_deref() {
+        Some("enotconn") => builder = builder.missing_root_errno(libc::ENOTCONN),
+        Some(_) => builder = builder.missing_root_errno(libc::EIO),
+        None => {}
+    }
+    if let Some(mode) = args.file_mode {
+        builder = builder.force_file_mode(mode);
+    }
+    if let Some(mode) = args.dir_mode {
+        builder = builder.force_dir_mode(mode);
+    }
+    if let Some(umask) = args.umask {
+        builder = builder.umask(umask);
+    }
+    match args.audit.as_deref() {
+        Some("erofs") => builder = builder.audit(deco::AuditReply::ReadOnly),
+        Some(_) => builder = builder.audit(deco::AuditReply::Succeed),
+        None => {}
+    }
+    if let Some(threads) = args.threads {
+        builder = builder.threads(threads as usize);
+    }
+    let filesystem = match builder.build() {
+        Ok(filesystem) => filesystem,
+        Err(e) => Args::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit(),
+    };
+    let threads = filesystem.threads();
+    let options = args.mount_options();
+    let options = options.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
+
+    // Run the session in the background so that SIGINT/SIGTERM can unmount cleanly rather than
+    // leaving a stale mount behind that needs a manual `fusermount -u`.
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let session = fuse_mt::spawn_mount(
+        fuse_mt::FuseMT::new(filesystem, threads),
+        &args.mountpoint,
+        &options,
+    )?;
+    while !session.guard.is_finished() {
+        if let Some(signal) = signals.pending().next() {
+            info!(
+                "received signal {}, unmounting {:?}",
+                signal, args.mountpoint
+            );
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // Dropping the session unmounts the filesystem, if still mounted.
+    drop(session);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(["decofs", "/source", "/mnt"].iter().chain(extra))
+    }
+
+    #[test]
+    fn log_level_is_taken_from_the_command_line() {
+        for (name, level) in [
+            ("off", log::LevelFilter::Off),
+            ("error", log::LevelFilter::Error),
+            ("info", log::LevelFilter::Info),
+            ("trace", log::LevelFilter::Trace),
+        ] {
+            let args = parse(&["--log-level", name]).unwrap();
+            assert_eq!(args.log_level(), level);
+        }
+        assert!(parse(&["--log-level", "verbose"]).is_err());
+    }
+
+    #[test]
+    fn threads_must_be_at_least_one() {
+        assert_eq!(parse(&["--threads", "8"]).unwrap().threads, Some(8));
+        assert!(parse(&["--threads", "0"]).is_err());
+    }
 }
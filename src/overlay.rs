@@ -0,0 +1,117 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::libc_wrapper;
+
+// The upper layer of an overlay follows the aufs conventions. Removing an entry that exists in
+// a lower layer leaves a whiteout where it would be in the upper layer: an empty file named
+// `.wh.<name>`. A directory created in the upper layer in place of a removed lower one is marked
+// opaque by a `.wh..wh..opq` file inside it, so that the lower directory's entries don't show
+// through. Names starting `.wh.` are reserved.
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Whether `name` is reserved for whiteouts and similar markers.
+pub fn is_whiteout_name(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+fn whiteout(dir: &Path, name: &OsStr) -> PathBuf {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    dir.join(whiteout)
+}
+
+/// Whether `name` has been removed from the upper layer directory `dir`, hiding any lower entry.
+pub fn is_whited_out(dir: &Path, name: &OsStr) -> bool {
+    fs::symlink_metadata(whiteout(dir, name)).is_ok()
+}
+
+/// The names whited out in the upper layer directory `dir`.
+pub fn whited_out_names(dir: &Path) -> Vec<OsString> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name();
+            let name = name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes())?;
+            Some(OsStr::from_bytes(name).to_os_string())
+        })
+        .collect()
+}
+
+pub fn create_whiteout(dir: &Path, name: &OsStr) -> io::Result<()> {
+    File::create(whiteout(dir, name)).map(|_| ())
+}
+
+/// Remove any whiteout for `name` in `dir`, returning whether there was one.
+pub fn remove_whiteout(dir: &Path, name: &OsStr) -> io::Result<bool> {
+    match fs::remove_file(whiteout(dir, name)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the upper layer directory `dir` hides the entries of the lower directories it covers.
+pub fn is_opaque(dir: &Path) -> bool {
+    fs::symlink_metadata(dir.join(OPAQUE_MARKER)).is_ok()
+}
+
+pub fn set_opaque(dir: &Path) -> io::Result<()> {
+    File::create(dir.join(OPAQUE_MARKER)).map(|_| ())
+}
+
+/// Remove the whiteouts and opaque marker from the upper layer directory `dir`, so that it can
+/// itself be removed once it appears empty.
+pub fn clear_markers(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if is_whiteout_name(&entry.file_name()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy `source`, from a lower layer, to `target` in the upper layer. Directories are created
+/// empty; files are copied to a reserved name first, so they never appear half-written.
+/// Ownership is kept where the process has the privilege to do so.
+pub fn copy_up(source: &Path, target: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(target)?;
+    } else if file_type.is_symlink() {
+        symlink(fs::read_link(source)?, target)?;
+    } else if file_type.is_file() {
+        let mut staging = OsString::from(WHITEOUT_PREFIX);
+        staging.push(".copyup.");
+        staging.push(target.file_name().unwrap_or_default());
+        let staging = target.with_file_name(staging);
+        fs::copy(source, &staging)?;
+        if let Err(e) = fs::rename(&staging, target) {
+            let _ = fs::remove_file(&staging);
+            return Err(e);
+        }
+    } else {
+        libc_wrapper::mknod(
+            &target.to_path_buf(),
+            metadata.mode() as libc::mode_t,
+            metadata.rdev() as libc::dev_t,
+        )?;
+    }
+
+    let _ = lchown(target, Some(metadata.uid()), Some(metadata.gid()));
+    if !file_type.is_symlink() {
+        // Changing the owner may have cleared setuid and setgid bits, so restore them after.
+        fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+    }
+    Ok(())
+}
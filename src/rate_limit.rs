@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting throughput to a number of bytes a second, shared by all threads.
+/// Up to a second's worth may be taken at once after a quiet spell.
+pub struct RateLimiter {
+    rate: f64,
+    /// The tokens available as of the instant, which go negative while callers wait.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket, sleeping until they would have been available. Each caller
+    /// reserves its share under the lock and sleeps outside it, so waiting threads queue up
+    /// behind one another in the order they arrived without blocking each other.
+    pub fn take(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, since) = &mut *state;
+            let now = Instant::now();
+            *tokens =
+                (*tokens + now.duration_since(*since).as_secs_f64() * self.rate).min(self.rate);
+            *since = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::libc_wrapper;
+
+/// Reads `buf.len()` bytes at an offset from a descriptor, as far as end-of-file.
+pub type ReadAt = fn(u64, &mut [u8], u64) -> io::Result<usize>;
+
+/// What is known about the reads on one handle.
+struct Stream {
+    real: PathBuf,
+    /// Where the next read starts if the client is reading sequentially.
+    next: u64,
+    /// Data read ahead, and the offset it starts at.
+    buffered: Option<(u64, Vec<u8>)>,
+    /// A read ahead still in progress, and the offset it starts at.
+    pending: Option<(u64, Receiver<io::Result<Vec<u8>>>)>,
+}
+
+/// Prefetching for handles being read sequentially: once a read starts where the last one on
+/// its handle ended, the next `window` bytes are read in the background, so the reads that
+/// follow are served from memory. At most two windows are held per handle, one read and one
+/// being read.
+pub struct Readahead {
+    window: usize,
+    read_at: ReadAt,
+    streams: Mutex<HashMap<u64, Arc<Mutex<Stream>>>>,
+}
+
+impl Readahead {
+    pub fn new(window: usize, read_at: ReadAt) -> Self {
+        Self {
+            window,
+            read_at,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read up to `size` bytes at `offset` from `fh`, open on the backing path `real`, from data
+    /// read ahead where possible, and start reading further ahead if the reads are sequential.
+    /// A read ahead still in progress is waited for for at most `wait`, if given, before the
+    /// data is read from the file instead, so that a hung one can't hold the handle forever.
+    pub fn read(
+        &self,
+        fh: u64,
+        real: &Path,
+        offset: u64,
+        size: usize,
+        wait: Option<Duration>,
+    ) -> io::Result<Vec<u8>> {
+        let stream = self
+            .streams
+            .lock()
+            .unwrap()
+            .entry(fh)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Stream {
+                    real: real.to_path_buf(),
+                    next: 0,
+                    buffered: None,
+                    pending: None,
+                }))
+            })
+            .clone();
+        let mut stream = stream.lock().unwrap();
+        let sequential = offset == stream.next;
+        if !sequential {
+            // Anything read ahead is for somewhere else.
+            stream.buffered = None;
+            stream.pending = None;
+        }
+
+        let mut data = Vec::with_capacity(size);
+        let mut pos = offset;
+        while data.len() < size {
+            if let Some((start, buffered)) = &stream.buffered {
+                let end = start + buffered.len() as u64;
+                if *start <= pos && pos < end {
+                    let from = (pos - start) as usize;
+                    let n = (size - data.len()).min(buffered.len() - from);
+                    data.extend_from_slice(&buffered[from..from + n]);
+                    pos += n as u64;
+                    continue;
+                }
+            }
+            if let Some((start, pending)) = stream.pending.take() {
+                if start == pos {
+                    let received = match wait {
+                        Some(wait) => pending.recv_timeout(wait).ok(),
+                        None => pending.recv().ok(),
+                    };
+                    if let Some(Ok(buffered)) = received {
+                        if !buffered.is_empty() {
+                            stream.buffered = Some((start, buffered));
+                            continue;
+                        }
+                    }
+                }
+            }
+            // Whatever is left, including end-of-file and errors, comes from the file itself.
+            let mut rest = vec![0u8; size - data.len()];
+            match (self.read_at)(fh, &mut rest, pos) {
+                Ok(n) => {
+                    data.extend_from_slice(&rest[..n]);
+                    pos += n as u64;
+                }
+                Err(e) if data.is_empty() => return Err(e),
+                Err(_) => {}
+            }
+            break;
+        }
+        stream.next = pos;
+
+        if let Some((start, buffered)) = &stream.buffered {
+            if pos >= start + buffered.len() as u64 {
+                stream.buffered = None;
+            }
+        }
+        if sequential && data.len() == size && stream.pending.is_none() {
+            let from = match &stream.buffered {
+                Some((start, buffered)) => start + buffered.len() as u64,
+                None => pos,
+            };
+            stream.pending = self.prefetch(fh, from);
+        }
+        Ok(data)
+    }
+
+    /// Start reading a window at `offset` from `fh` in the background. The read goes through
+    /// a duplicate of the descriptor, which stays open until it is done even if `fh` is released
+    /// first and its number reused.
+    fn prefetch(&self, fh: u64, offset: u64) -> Option<(u64, Receiver<io::Result<Vec<u8>>>)> {
+        let fd = libc_wrapper::dup(fh).ok()?;
+        let (sender, receiver) = mpsc::channel();
+        let (window, read_at) = (self.window, self.read_at);
+        let spawned = thread::Builder::new()
+            .name("readahead".into())
+            .spawn(move || {
+                let mut data = vec![0u8; window];
+                let result = read_at(fd, &mut data, offset).map(|n| {
+                    data.truncate(n);
+                    data
+                });
+                let _ = libc_wrapper::close(fd);
+                // The stream may have moved on, and no longer want the data.
+                let _ = sender.send(result);
+            });
+        match spawned {
+            Ok(_) => Some((offset, receiver)),
+            Err(_) => {
+                let _ = libc_wrapper::close(fd);
+                None
+            }
+        }
+    }
+
+    /// Forget anything read ahead from the backing path `real`, after it is written to.
+    pub fn invalidate(&self, real: &Path) {
+        for stream in self.streams.lock().unwrap().values() {
+            let mut stream = stream.lock().unwrap();
+            if stream.real == real {
+                stream.buffered = None;
+                stream.pending = None;
+            }
+        }
+    }
+
+    /// Forget everything read ahead, for when the source tree has been changed other than through
+    /// the mount. Where each handle's reads are up to is kept.
+    pub fn clear(&self) {
+        for stream in self.streams.lock().unwrap().values() {
+            let mut stream = stream.lock().unwrap();
+            stream.buffered = None;
+            stream.pending = None;
+        }
+    }
+
+    /// Forget `fh` once it is released.
+    pub fn release(&self, fh: u64) {
+        self.streams.lock().unwrap().remove(&fh);
+    }
+}
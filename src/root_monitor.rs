@@ -0,0 +1,77 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether something other than the directory beneath is mounted at `path`. The root of the
+/// whole tree always counts as mounted.
+fn is_mount_point(path: &Path) -> bool {
+    match (
+        fs::symlink_metadata(path),
+        path.parent().map(fs::symlink_metadata),
+    ) {
+        (Ok(meta), Some(Ok(parent))) => meta.dev() != parent.dev(),
+        _ => true,
+    }
+}
+
+/// Notices the source roots going away from under the mount, such as a removable drive being
+/// pulled or a network filesystem disconnecting, so that every failure while they are gone can
+/// be reported with the same errno rather than whatever each backing call happened to hit.
+///
+/// A root is gone if it can't be looked up, or it was a mount point when the filesystem was
+/// built and no longer is, leaving the empty directory beneath. The roots are only checked once
+/// an operation has failed, so it costs nothing while everything works, and coming back is
+/// noticed at the first failure after it.
+pub struct RootMonitor {
+    errno: libc::c_int,
+    /// Each root, and whether it was a mount point to begin with.
+    roots: Vec<(PathBuf, bool)>,
+    missing: AtomicBool,
+}
+
+impl RootMonitor {
+    pub fn new(roots: &[PathBuf], errno: libc::c_int) -> Self {
+        Self {
+            errno,
+            roots: roots
+                .iter()
+                .map(|root| (root.clone(), is_mount_point(root)))
+                .collect(),
+            missing: AtomicBool::new(false),
+        }
+    }
+
+    /// The root that is gone, if any is.
+    fn missing_root(&self) -> Option<&Path> {
+        self.roots
+            .iter()
+            .find(|(root, mounted)| match fs::symlink_metadata(root) {
+                Ok(meta) => !meta.is_dir() || (*mounted && !is_mount_point(root)),
+                Err(_) => true,
+            })
+            .map(|(root, _)| root.as_path())
+    }
+
+    /// The errno to report for an operation that failed with `errno`: the chosen one if a root
+    /// is gone. Going and coming back are each logged once.
+    pub fn failed(&self, errno: libc::c_int) -> libc::c_int {
+        match self.missing_root() {
+            Some(root) => {
+                if !self.missing.swap(true, Ordering::Relaxed) {
+                    error!(
+                        "source root {:?} is unavailable; failing operations until it returns",
+                        root
+                    );
+                }
+                self.errno
+            }
+            None => {
+                if self.missing.swap(false, Ordering::Relaxed) {
+                    info!("source roots are available again");
+                }
+                errno
+            }
+        }
+    }
+}
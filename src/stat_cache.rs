@@ -0,0 +1,130 @@
+use fuse_mt::FileAttr;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// A bounded, time-limited cache of attributes, keyed on real path.
+pub struct StatCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: RwLock<HashMap<PathBuf, (Instant, FileAttr)>>,
+}
+
+impl StatCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, real: &Path) -> Option<FileAttr> {
+        let entries = self.entries.read().unwrap();
+        match entries.get(real) {
+            Some((cached, attr)) if cached.elapsed() < self.ttl => Some(*attr),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, real: PathBuf, attr: FileAttr) {
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&real) {
+            // Make room by dropping expired entries, then the oldest if that wasn't enough.
+            let ttl = self.ttl;
+            entries.retain(|_, (cached, _)| cached.elapsed() < ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (cached, _))| *cached)
+                    .map(|(path, _)| path.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(real, (Instant::now(), attr));
+    }
+
+    pub fn invalidate(&self, real: &Path) {
+        self.entries.write().unwrap().remove(real);
+    }
+
+    /// Forget `real` and everything beneath it, for when a directory is renamed or removed.
+    pub fn invalidate_tree(&self, real: &Path) {
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|path, _| !path.starts_with(real));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse_mt::FileType;
+    use std::thread;
+    use std::time::SystemTime;
+
+    fn attr(size: u64) -> FileAttr {
+        FileAttr {
+            size,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn size(cache: &StatCache, path: &str) -> Option<u64> {
+        cache.get(Path::new(path)).map(|attr| attr.size)
+    }
+
+    #[test]
+    fn entries_are_returned_until_they_expire() {
+        let cache = StatCache::new(Duration::from_millis(50), 16);
+        cache.insert("/a".into(), attr(1));
+        assert_eq!(size(&cache, "/a"), Some(1));
+        assert_eq!(size(&cache, "/b"), None);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(size(&cache, "/a"), None);
+    }
+
+    #[test]
+    fn invalidating_drops_the_path_or_the_tree_beneath_it() {
+        let cache = StatCache::new(Duration::from_secs(60), 16);
+        for path in ["/dir", "/dir/a", "/dir/sub/b", "/dirt", "/other"] {
+            cache.insert(path.into(), attr(1));
+        }
+        cache.invalidate(Path::new("/other"));
+        assert_eq!(size(&cache, "/other"), None);
+        cache.invalidate_tree(Path::new("/dir"));
+        for path in ["/dir", "/dir/a", "/dir/sub/b"] {
+            assert_eq!(size(&cache, path), None, "{}", path);
+        }
+        assert_eq!(size(&cache, "/dirt"), Some(1));
+    }
+
+    #[test]
+    fn capacity_is_kept_by_dropping_the_oldest() {
+        let cache = StatCache::new(Duration::from_secs(60), 3);
+        for (i, path) in ["/a", "/b", "/c"].iter().enumerate() {
+            cache.insert(path.into(), attr(i as u64));
+            thread::sleep(Duration::from_millis(2));
+        }
+        cache.insert("/b".into(), attr(10));
+        cache.insert("/d".into(), attr(3));
+        assert_eq!(size(&cache, "/a"), None);
+        assert_eq!(size(&cache, "/b"), Some(10));
+        assert_eq!(size(&cache, "/c"), Some(2));
+        assert_eq!(size(&cache, "/d"), Some(3));
+        assert_eq!(cache.entries.read().unwrap().len(), 3);
+    }
+}
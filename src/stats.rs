@@ -0,0 +1,155 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The FUSE operations counted by `Counters`.
+#[derive(Clone, Copy)]
+pub enum Op {
+    Getattr,
+    Statfs,
+    Opendir,
+    Readdir,
+    Releasedir,
+    Open,
+    Release,
+    Read,
+    Write,
+    Flush,
+    Fsync,
+    Fsyncdir,
+    Create,
+    Mkdir,
+    Mknod,
+    Unlink,
+    Rmdir,
+    Rename,
+    Readlink,
+    Symlink,
+    Link,
+    Setxattr,
+    Getxattr,
+    Listxattr,
+    Removexattr,
+    Access,
+    Chown,
+    Truncate,
+    Utimens,
+}
+
+impl Op {
+    const ALL: [Op; 29] = [
+        Op::Getattr,
+        Op::Statfs,
+        Op::Opendir,
+        Op::Readdir,
+        Op::Releasedir,
+        Op::Open,
+        Op::Release,
+        Op::Read,
+        Op::Write,
+        Op::Flush,
+        Op::Fsync,
+        Op::Fsyncdir,
+        Op::Create,
+        Op::Mkdir,
+        Op::Mknod,
+        Op::Unlink,
+        Op::Rmdir,
+        Op::Rename,
+        Op::Readlink,
+        Op::Symlink,
+        Op::Link,
+        Op::Setxattr,
+        Op::Getxattr,
+        Op::Listxattr,
+        Op::Removexattr,
+        Op::Access,
+        Op::Chown,
+        Op::Truncate,
+        Op::Utimens,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Getattr => "getattr",
+            Op::Statfs => "statfs",
+            Op::Opendir => "opendir",
+            Op::Readdir => "readdir",
+            Op::Releasedir => "releasedir",
+            Op::Open => "open",
+            Op::Release => "release",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Flush => "flush",
+            Op::Fsync => "fsync",
+            Op::Fsyncdir => "fsyncdir",
+            Op::Create => "create",
+            Op::Mkdir => "mkdir",
+            Op::Mknod => "mknod",
+            Op::Unlink => "unlink",
+            Op::Rmdir => "rmdir",
+            Op::Rename => "rename",
+            Op::Readlink => "readlink",
+            Op::Symlink => "symlink",
+            Op::Link => "link",
+            Op::Setxattr => "setxattr",
+            Op::Getxattr => "getxattr",
+            Op::Listxattr => "listxattr",
+            Op::Removexattr => "removexattr",
+            Op::Access => "access",
+            Op::Chown => "chown",
+            Op::Truncate => "truncate",
+            Op::Utimens => "utimens",
+        }
+    }
+}
+
+/// Running totals of the operations served, and of how many failed.
+pub struct Counters {
+    calls: [AtomicU64; Op::ALL.len()],
+    errors: AtomicU64,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            calls: Default::default(),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn call(&self, op: Op) {
+        self.calls[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            calls: Op::ALL
+                .iter()
+                .map(|&op| (op.name(), self.calls[op as usize].load(Ordering::Relaxed)))
+                .collect(),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of `Counters`.
+pub struct Stats {
+    /// The number of calls of each operation, by name.
+    pub calls: Vec<(&'static str, u64)>,
+    /// The number of calls of any operation that returned an error.
+    pub errors: u64,
+}
+
+impl fmt::Display for Stats {
+    /// One `name count` line per operation, then the error count.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, count) in &self.calls {
+            writeln!(f, "{} {}", name, count)?;
+        }
+        writeln!(f, "errors {}", self.errors)
+    }
+}
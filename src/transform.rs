@@ -0,0 +1,104 @@
+use std::io;
+use std::path::Path;
+
+use crate::gzip;
+
+/// A transformation applied to file content as it is read through the mount.
+///
+/// Reads arrive in arbitrary blocks rather than whole files, so an implementation must be able
+/// to decode any block given only its offset within the file; it cannot carry state from one
+/// read to the next.
+pub trait ContentTransform: Send + Sync {
+    /// Decode `data`, which was read from `path` (relative to the mount root) at `offset`.
+    fn decode_read(&self, path: &Path, offset: u64, data: &mut Vec<u8>);
+}
+
+/// Passes content through unchanged.
+pub struct Identity;
+
+impl ContentTransform for Identity {
+    fn decode_read(&self, _path: &Path, _offset: u64, _data: &mut Vec<u8>) {}
+}
+
+/// XORs content with a repeating key, aligned to the start of the file.
+pub struct Xor {
+    key: Vec<u8>,
+}
+
+impl Xor {
+    pub fn new(key: Vec<u8>) -> Self {
+        assert!(!key.is_empty(), "XOR key must not be empty");
+        Self { key }
+    }
+}
+
+impl ContentTransform for Xor {
+    fn decode_read(&self, _path: &Path, offset: u64, data: &mut Vec<u8>) {
+        let len = self.key.len() as u64;
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= self.key[((offset + i as u64) % len) as usize];
+        }
+    }
+}
+
+/// A transformation that can only decode a file as a whole, such as decompression, where data
+/// at one offset can't be decoded without everything before it.
+pub trait FileTransform: Send + Sync {
+    /// Decode `data`, the whole content of `path` (relative to the mount root).
+    fn decode_file(&self, path: &Path, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Decompresses gzip files, failing with EFBIG for any that would decompress to more than its
+/// limit.
+pub struct Gunzip {
+    max_size: usize,
+}
+
+impl Gunzip {
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+}
+
+impl FileTransform for Gunzip {
+    fn decode_file(&self, _path: &Path, data: &[u8]) -> io::Result<Vec<u8>> {
+        gzip::decompress(data, self.max_size)
+    }
+}
+
+/// A transformation for the files with some extension.
+pub enum Transform {
+    /// Decoded a block at a time, as each is read.
+    Blocks(Box<dyn ContentTransform>),
+    /// Decoded in full when opened.
+    WholeFile(Box<dyn FileTransform>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_leaves_content_alone() {
+        let mut data = b"content".to_vec();
+        Identity.decode_read(Path::new("/file"), 3, &mut data);
+        assert_eq!(data, b"content");
+    }
+
+    #[test]
+    fn xor_decodes_any_block_from_its_offset() {
+        let xor = Xor::new(b"key".to_vec());
+        let plain = b"some plain text to encode".to_vec();
+        let mut encoded = plain.clone();
+        xor.decode_read(Path::new("/file"), 0, &mut encoded);
+        assert_ne!(encoded, plain);
+
+        for start in 0..plain.len() {
+            for end in start..=plain.len() {
+                let mut block = encoded[start..end].to_vec();
+                xor.decode_read(Path::new("/file"), start as u64, &mut block);
+                assert_eq!(block, &plain[start..end]);
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+
+use crate::libc_wrapper;
 
 /// A file that is not closed upon leaving scope.
 pub struct UnmanagedFile {
@@ -8,47 +10,97 @@ pub struct UnmanagedFile {
 }
 
 impl UnmanagedFile {
-    pub unsafe fn new(fd: u64) -> Self {
-        Self {
-            inner: Some(File::from_raw_fd(fd as i32)),
+    /// Wrap `fd`, failing with EBADF if it is not an open file descriptor.
+    ///
+    /// Unsafe because nothing stops the descriptor being closed elsewhere while in use.
+    pub unsafe fn new(fd: u64) -> io::Result<Self> {
+        if fd > libc::c_int::MAX as u64 {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
         }
+        libc_wrapper::fcntl_getfd(fd)?;
+        Ok(Self {
+            inner: Some(File::from_raw_fd(fd as i32)),
+        })
+    }
+
+    fn file(&self) -> io::Result<&File> {
+        self.inner
+            .as_ref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        libc_wrapper::fsync(self.file()?.as_raw_fd() as u64)
+    }
+    pub fn sync_data(&self) -> io::Result<()> {
+        libc_wrapper::fdatasync(self.file()?.as_raw_fd() as u64)
     }
-    // pub fn sync_all(&self) -> io::Result<()> {
-    //     self.inner.as_ref().unwrap().sync_all()
-    // }
-    // pub fn sync_data(&self) -> io::Result<()> {
-    //     self.inner.as_ref().unwrap().sync_data()
-    // }
 }
 
 impl Drop for UnmanagedFile {
     fn drop(&mut self) {
         // Release control of the file descriptor so it is not closed.
-        let file = self.inner.take().unwrap();
-        file.into_raw_fd();
+        if let Some(file) = self.inner.take() {
+            let _ = file.into_raw_fd();
+        }
     }
 }
 
 impl Read for UnmanagedFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.as_ref().unwrap().read(buf)
+        self.file()?.read(buf)
     }
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
-        self.inner.as_ref().unwrap().read_to_end(buf)
+        self.file()?.read_to_end(buf)
     }
 }
 
 impl Write for UnmanagedFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.as_ref().unwrap().write(buf)
+        self.file()?.write(buf)
     }
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.as_ref().unwrap().flush()
+        self.file()?.flush()
     }
 }
 
 impl Seek for UnmanagedFile {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.inner.as_ref().unwrap().seek(pos)
+        self.file()?.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errno(result: io::Result<UnmanagedFile>) -> Option<i32> {
+        result.err().and_then(|e| e.raw_os_error())
+    }
+
+    #[test]
+    fn unopened_or_impossible_descriptors_fail_with_ebadf() {
+        // A closed descriptor could be reused by another test at any moment, so use one far
+        // beyond any that will be opened.
+        let unopened = libc::c_int::MAX as u64;
+        assert_eq!(
+            errno(unsafe { UnmanagedFile::new(unopened) }),
+            Some(libc::EBADF)
+        );
+        assert_eq!(
+            errno(unsafe { UnmanagedFile::new(u64::MAX) }),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn open_descriptors_are_used_and_left_open() {
+        let file = File::open("/proc/self/stat").unwrap();
+        let fd = file.as_raw_fd() as u64;
+        let mut unmanaged = unsafe { UnmanagedFile::new(fd) }.unwrap();
+        let mut content = vec![];
+        assert!(unmanaged.read_to_end(&mut content).unwrap() > 0);
+        drop(unmanaged);
+        assert!(libc_wrapper::fcntl_getfd(fd).is_ok());
     }
 }
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+
+use crate::libc_wrapper;
+
+/// The events that can leave something cached about a path out of date.
+const WATCH_MASK: u32 = libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVE_SELF
+    | libc::IN_ONLYDIR;
+const HEADER_LEN: usize = mem::size_of::<libc::inotify_event>();
+
+/// An inotify instance watching every directory of some trees. inotify only watches single
+/// directories, so one watch is needed per directory, and new directories are watched as they
+/// appear.
+struct Watcher {
+    fd: u64,
+    roots: Vec<PathBuf>,
+    dirs: HashMap<libc::c_int, PathBuf>,
+}
+
+impl Watcher {
+    /// Watch `dir` and every directory beneath it. Directories that can't be watched, such as
+    /// once the system's limit on watches is reached, are skipped with a warning.
+    fn watch_tree(&mut self, dir: &Path) {
+        match libc_wrapper::inotify_add_watch(self.fd, &dir.to_path_buf(), WATCH_MASK) {
+            // Watching a directory again gives the same descriptor, so a moved directory's
+            // path is updated here.
+            Ok(wd) => {
+                self.dirs.insert(wd, dir.to_path_buf());
+            }
+            Err(e) => {
+                if e.raw_os_error() != Some(libc::ENOENT) && e.raw_os_error() != Some(libc::ENOTDIR)
+                {
+                    warn!("watch {:?}: {}", dir, e);
+                }
+                return;
+            }
+        }
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.watch_tree(&entry.path());
+            }
+        }
+    }
+
+    /// Read events forever, calling `on_change` with each path that may have changed.
+    fn run(mut self, on_change: impl Fn(&Path)) {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match libc_wrapper::read(self.fd, &mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("watcher stopped: {}", e);
+                    return;
+                }
+            };
+            let mut offset = 0;
+            while offset + HEADER_LEN <= n {
+                let event: libc::inotify_event =
+                    unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const _) };
+                let name_start = offset + HEADER_LEN;
+                let name_end = (name_start + event.len as usize).min(n);
+                offset = name_end;
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    // Events were lost, so anything could have changed.
+                    warn!("watcher: event queue overflowed");
+                    for root in &self.roots {
+                        on_change(root);
+                    }
+                    continue;
+                }
+                let dir = match self.dirs.get(&event.wd) {
+                    Some(dir) => dir.clone(),
+                    None => continue,
+                };
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                // The name is padded with NULs to the event's length.
+                let name = &buf[name_start..name_end];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                let path = if name.is_empty() {
+                    dir
+                } else {
+                    dir.join(OsStr::from_bytes(name))
+                };
+                trace!("watcher: {:?} (mask = {:#x})", path, event.mask);
+
+                let appeared = libc::IN_CREATE | libc::IN_MOVED_TO;
+                if event.mask & libc::IN_ISDIR != 0 && event.mask & appeared != 0 {
+                    self.watch_tree(&path);
+                }
+                on_change(&path);
+            }
+        }
+    }
+}
+
+/// Watch the trees under `roots` from a background thread, calling `on_change` with each path
+/// that is created, removed, renamed or modified in them, or with a root if events were lost.
+/// Paths may be reported more than once, and may no longer exist by the time they are.
+pub fn spawn(roots: Vec<PathBuf>, on_change: impl Fn(&Path) + Send + 'static) -> io::Result<()> {
+    let mut watcher = Watcher {
+        fd: libc_wrapper::inotify_init()?,
+        roots: roots.clone(),
+        dirs: HashMap::new(),
+    };
+    for root in &roots {
+        watcher.watch_tree(root);
+    }
+    info!("watching {} directories", watcher.dirs.len());
+    thread::Builder::new()
+        .name("watcher".into())
+        .spawn(move || watcher.run(on_change))?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::libc_wrapper;
+
+/// Bytes written to a handle but not yet passed to the backing file.
+struct Pending {
+    real: PathBuf,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl Pending {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+
+    fn write_out(&self, fh: u64) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.data.len() {
+            let n = libc_wrapper::pwrite(fh, &self.data[written..], self.offset + written as u64)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            written += n;
+        }
+        Ok(())
+    }
+}
+
+/// Per-handle buffers coalescing contiguous writes, so a run of small sequential writes costs
+/// one pwrite rather than one each.
+///
+/// A buffer is written out once it reaches the size limit, when a write to the same handle isn't
+/// contiguous with it, or when explicitly flushed. Errors from writing it out are reported by
+/// whichever call triggered that, possibly long after the write that supplied the data. As that
+/// data is then lost, which might not be noticed by the caller that saw the error, every later
+/// `sync` and the `release` of the handle report the error too.
+pub struct WriteBuffers {
+    limit: usize,
+    pending: Mutex<HashMap<u64, Pending>>,
+    /// The errno from the first failure to write out a buffer, by handle.
+    failed: Mutex<HashMap<u64, i32>>,
+}
+
+impl WriteBuffers {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            pending: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Write out `buffer` for `fh`, noting any failure against the handle.
+    fn write_out(&self, fh: u64, buffer: Pending) -> io::Result<()> {
+        let result = buffer.write_out(fh);
+        if let Err(e) = &result {
+            let errno = e.raw_os_error().unwrap_or(libc::EIO);
+            self.failed.lock().unwrap().entry(fh).or_insert(errno);
+        }
+        result
+    }
+
+    /// Buffer `data` for writing to `fh`, which refers to `real`, at `offset`.
+    pub fn write(&self, fh: u64, real: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(buffer) = pending.get_mut(&fh) {
+            if buffer.end() == offset {
+                buffer.data.extend_from_slice(data);
+                if buffer.data.len() >= self.limit {
+                    let buffer = pending.remove(&fh).unwrap();
+                    self.write_out(fh, buffer)?;
+                }
+                return Ok(());
+            }
+            let buffer = pending.remove(&fh).unwrap();
+            self.write_out(fh, buffer)?;
+        }
+
+        let buffer = Pending {
+            real: real.to_path_buf(),
+            offset,
+            data: data.to_vec(),
+        };
+        if buffer.data.len() >= self.limit {
+            // Written straight through, so a failure is reported here and needn't be kept.
+            buffer.write_out(fh)
+        } else {
+            pending.insert(fh, buffer);
+            Ok(())
+        }
+    }
+
+    /// Write out anything buffered for `fh`.
+    pub fn flush(&self, fh: u64) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.remove(&fh) {
+            Some(buffer) => self.write_out(fh, buffer),
+            None => Ok(()),
+        }
+    }
+
+    /// Write out anything buffered for `fh`, failing if any data written through it has been
+    /// lost, for flush and fsync.
+    pub fn sync(&self, fh: u64) -> io::Result<()> {
+        self.flush(fh)?;
+        match self.failed.lock().unwrap().get(&fh) {
+            Some(&errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => Ok(()),
+        }
+    }
+
+    /// As `sync`, for the last time before `fh` is closed.
+    pub fn release(&self, fh: u64) -> io::Result<()> {
+        let flushed = self.flush(fh);
+        match self.failed.lock().unwrap().remove(&fh) {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => flushed,
+        }
+    }
+
+    /// Write out anything buffered for `real` through any handle, so that it can be read or
+    /// have its size checked.
+    pub fn flush_path(&self, real: &Path) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let handles: Vec<u64> = pending
+            .iter()
+            .filter(|(_, buffer)| buffer.real == real)
+            .map(|(fh, _)| *fh)
+            .collect();
+        let mut result = Ok(());
+        for fh in handles {
+            let buffer = pending.remove(&fh).unwrap();
+            if let Err(e) = self.write_out(fh, buffer) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Follow `from` being renamed to `to`, so buffers for anything at or beneath it can still
+    /// be found by path.
+    pub fn renamed(&self, from: &Path, to: &Path) {
+        let mut pending = self.pending.lock().unwrap();
+        for buffer in pending.values_mut() {
+            if let Ok(rest) = buffer.real.strip_prefix(from) {
+                buffer.real = to.join(rest);
+            }
+        }
+    }
+}
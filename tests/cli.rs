@@ -0,0 +1,42 @@
+use std::process::Command;
+
+#[test]
+fn unknown_mount_options_are_rejected_before_mounting() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-decofs-mt"))
+        .args(["/", "/nonexistent", "-o", "nosuid,bogus"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unknown mount option \"bogus\""),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn known_mount_settings_are_accepted_with_numbers_of_bytes() {
+    for option in ["max_read=65536", "max_write=65536"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-decofs-mt"))
+            .args(["/", "/nonexistent", "-o", option])
+            .output()
+            .unwrap();
+        // Getting as far as the mount point, which doesn't exist.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("NotFound"), "{}: {}", option, stderr);
+    }
+    for option in ["max_read=lots", "max_write=lots"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-decofs-mt"))
+            .args(["/", "/nonexistent", "-o", option])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("must be a number of bytes"),
+            "{}: {}",
+            option,
+            stderr
+        );
+    }
+}
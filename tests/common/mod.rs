@@ -0,0 +1,169 @@
+//! A harness mounting decofs over a scratch source tree for tests to exercise through
+//! `std::fs`, by running the built binary: the crate has no library target to mount in-process.
+//!
+//! Mounting needs `/dev/fuse` and the privilege to use it, so where it can't be opened
+//! `Mount::new` returns None, and the test should return early rather than failing. Once it can,
+//! decofs failing to mount is a test failure.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the mount to appear, or to go once asked to.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running decofs mount, unmounted and cleaned up when dropped.
+pub struct Mount {
+    child: Child,
+    dir: PathBuf,
+}
+
+/// A fresh, empty directory for one mount's source tree and mountpoint.
+fn scratch_dir() -> io::Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "decofs-test-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(dir.join("source"))?;
+    fs::create_dir_all(dir.join("mnt"))?;
+    Ok(dir)
+}
+
+/// Whether something other than the directory beneath is mounted at `path`.
+fn is_mounted(path: &Path) -> bool {
+    match (fs::metadata(path), path.parent().map(fs::metadata)) {
+        (Ok(meta), Some(Ok(parent))) => meta.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+/// Mount a tmpfs with the options `options` at `dir`, returning whether that could be done,
+/// having said why not if it couldn't.
+pub fn mount_tmpfs(dir: &Path, options: &str) -> bool {
+    let mounted = Command::new("mount")
+        .args(["-t", "tmpfs", "-o", options, "tmpfs"])
+        .arg(dir)
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !mounted {
+        eprintln!("skipping: can't mount a tmpfs with {:?}", options);
+    }
+    mounted
+}
+
+/// Run decofs over the source tree and mountpoint in `dir`, with the command-line options `args`.
+fn spawn(dir: &Path, args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_rust-decofs-mt"))
+        .arg(dir.join("source"))
+        .arg(dir.join("mnt"))
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .expect("decofs runs")
+}
+
+impl Mount {
+    /// Mount an empty source tree with the command-line options `args`, once `setup` has filled
+    /// it in. Relative paths in `args` are taken from the scratch directory, so the source tree
+    /// is `source`. None, having said why, if FUSE isn't available.
+    pub fn new(args: &[&str], setup: impl FnOnce(&Path)) -> Option<Mount> {
+        if let Err(e) = OpenOptions::new().read(true).write(true).open("/dev/fuse") {
+            eprintln!("skipping: can't open /dev/fuse: {}", e);
+            return None;
+        }
+        let dir = scratch_dir().expect("scratch directory");
+        setup(&dir.join("source"));
+        let child = spawn(&dir, args);
+        let mut mount = Mount { child, dir };
+        mount.wait_until_mounted();
+        Some(mount)
+    }
+
+    fn wait_until_mounted(&mut self) {
+        let started = Instant::now();
+        while !is_mounted(&self.mountpoint()) {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("decofs exited without mounting: {}", status);
+            }
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "timed out waiting for the mount"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Send decofs `signal` and wait for it to exit, returning how it did. The mount is expected
+    /// to have gone by then.
+    pub fn stop(&mut self, signal: libc::c_int) -> ExitStatus {
+        unsafe { libc::kill(self.child.id() as libc::pid_t, signal) };
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(started.elapsed() < TIMEOUT, "timed out waiting for exit");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Mount decofs again over the same source tree and mountpoint, once it has stopped.
+    pub fn restart(&mut self, args: &[&str]) {
+        self.child = spawn(&self.dir, args);
+        self.wait_until_mounted();
+    }
+
+    /// Whether the mount is still in place.
+    pub fn is_mounted(&self) -> bool {
+        is_mounted(&self.mountpoint())
+    }
+
+    /// The root of the mount.
+    pub fn mountpoint(&self) -> PathBuf {
+        self.dir.join("mnt")
+    }
+
+    /// The root of the source tree, for checking what reached it.
+    pub fn source(&self) -> PathBuf {
+        self.dir.join("source")
+    }
+}
+
+impl Drop for Mount {
+    /// Stop decofs as Ctrl-C would, which unmounts, and remove the scratch directories. If it
+    /// won't stop it is killed, and the mount detached.
+    fn drop(&mut self) {
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+        let started = Instant::now();
+        while self.child.try_wait().ok().flatten().is_none() {
+            if started.elapsed() > TIMEOUT {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        if is_mounted(&self.mountpoint()) {
+            let _ = Command::new("umount")
+                .arg("-l")
+                .arg(self.mountpoint())
+                .status();
+        }
+        // A test may have mounted a filesystem of its own as the source tree.
+        let source = self.dir.join("source");
+        if is_mounted(&source) {
+            let _ = Command::new("umount").arg("-l").arg(&source).status();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,2363 @@
+mod common;
+
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    symlink, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::path::Path;
+use std::process::Command;
+
+use common::{mount_tmpfs, Mount};
+
+#[test]
+fn reads_a_file_back_through_the_mount() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("hello.txt"), "hello, world\n").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let content = fs::read_to_string(mount.mountpoint().join("hello.txt")).unwrap();
+    assert_eq!(content, "hello, world\n");
+}
+
+#[test]
+fn umask_masks_created_modes_unless_forced() {
+    let mount = match Mount::new(&["--umask", "077", "--dir-mode", "755"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = mount.mountpoint().join("file");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o666)
+        .open(&file)
+        .unwrap();
+    assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o600);
+
+    let dir = mount.mountpoint().join("dir");
+    fs::create_dir(&dir).unwrap();
+    assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o755);
+}
+
+// Errors from the source tree reach the client as they are. EACCES isn't covered: decofs
+// normally runs as root, for which the source tree's permissions are never a problem.
+
+#[test]
+fn read_only_source_fails_with_erofs() {
+    let mut tmpfs = false;
+    let mount = match Mount::new(&[], |source| {
+        tmpfs = mount_tmpfs(source, "size=1m");
+        fs::write(source.join("file"), "content").unwrap();
+        tmpfs = tmpfs && mount_tmpfs(source, "remount,ro");
+    }) {
+        Some(mount) if tmpfs => mount,
+        _ => return,
+    };
+
+    let root = mount.mountpoint();
+    let errno = |result: io::Result<()>| result.unwrap_err().raw_os_error();
+    assert_eq!(errno(fs::write(root.join("new"), "x")), Some(libc::EROFS));
+    assert_eq!(errno(fs::create_dir(root.join("dir"))), Some(libc::EROFS));
+    assert_eq!(errno(fs::remove_file(root.join("file"))), Some(libc::EROFS));
+    assert_eq!(
+        errno(fs::rename(root.join("file"), root.join("renamed"))),
+        Some(libc::EROFS)
+    );
+    assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "content");
+}
+
+#[test]
+fn full_source_fails_with_enospc() {
+    let mut tmpfs = false;
+    let mount = match Mount::new(&[], |source| tmpfs = mount_tmpfs(source, "size=64k")) {
+        Some(mount) if tmpfs => mount,
+        _ => return,
+    };
+
+    let result = fs::write(mount.mountpoint().join("big"), vec![0u8; 1 << 20]);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+}
+
+/// The counter `name` in the stats file of the mount at `root`.
+fn counter(root: &Path, name: &str) -> u64 {
+    let stats = fs::read_to_string(root.join(".decofs-stats")).unwrap();
+    stats
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+        .unwrap_or_else(|| panic!("no {} counter in {:?}", name, stats))
+}
+
+#[test]
+fn large_writes_arrive_in_requests_over_128k() {
+    const LEN: u64 = 4 << 20;
+    for max_write in [None, Some(LEN / 4), Some(64 << 10)] {
+        let mut args = vec!["--stats-file".to_string()];
+        if let Some(max_write) = max_write {
+            args.extend(["--max-write".to_string(), max_write.to_string()]);
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let mount = match Mount::new(&args, |_| {}) {
+            Some(mount) => mount,
+            None => return,
+        };
+
+        let data = vec![7u8; LEN as usize];
+        fs::write(mount.mountpoint().join("big"), &data).unwrap();
+        let writes = counter(&mount.mountpoint(), "write");
+        // The kernel may send fewer bytes in a request than it is allowed to, but never more.
+        let fewest = LEN / max_write.unwrap_or(1 << 20);
+        assert!(writes >= fewest, "{} writes, {:?}", writes, max_write);
+        if max_write.is_none_or(|max_write| max_write > 128 << 10) {
+            assert!(
+                writes < LEN / (128 << 10),
+                "{} writes, {:?}",
+                writes,
+                max_write
+            );
+        }
+        assert_eq!(fs::read(mount.mountpoint().join("big")).unwrap(), data);
+    }
+}
+
+/// The errno of opening `path` with `flags`, or None if it could be opened.
+fn open_errno(path: &Path, flags: libc::c_int) -> Option<i32> {
+    match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(flags)
+        .open(path)
+    {
+        Ok(_) => None,
+        Err(e) => e.raw_os_error(),
+    }
+}
+
+#[test]
+fn open_flags_about_the_entry_behave_as_posix_says() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+        symlink("file", source.join("link")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    assert_eq!(
+        open_errno(&root.join("link"), libc::O_NOFOLLOW),
+        Some(libc::ELOOP)
+    );
+    assert_eq!(open_errno(&root.join("file"), libc::O_NOFOLLOW), None);
+    assert_eq!(
+        open_errno(&root.join("file"), libc::O_DIRECTORY),
+        Some(libc::ENOTDIR)
+    );
+    assert_eq!(open_errno(&root, libc::O_DIRECTORY), None);
+}
+
+#[test]
+fn followed_symlinks_open_with_o_nofollow() {
+    let mount = match Mount::new(&["--follow-symlinks"], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+        symlink("file", source.join("link")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    assert_eq!(
+        open_errno(&mount.mountpoint().join("link"), libc::O_NOFOLLOW),
+        None
+    );
+}
+
+#[test]
+fn unlinked_files_go_to_the_trash() {
+    let mount = match Mount::new(&["--trash-dir", "source/.trash"], |source| {
+        fs::create_dir(source.join(".trash")).unwrap();
+        fs::write(source.join("file"), "first").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    fs::remove_file(root.join("file")).unwrap();
+    fs::write(root.join("file"), "second").unwrap();
+    fs::remove_file(root.join("file")).unwrap();
+    assert!(!root.join("file").exists());
+
+    let trashed = || {
+        let mut entries = fs::read_dir(root.join(".trash"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    };
+    let entries = trashed();
+    let contents = entries
+        .iter()
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(contents.len(), 2);
+    assert!(contents.contains(&"first".to_string()) && contents.contains(&"second".to_string()));
+    for path in &entries {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("file."), "{:?}", name);
+    }
+
+    // Files already in the trash are deleted for good.
+    fs::remove_file(&entries[0]).unwrap();
+    assert_eq!(trashed().len(), 1);
+}
+
+/// "hello\n", gzipped.
+const HELLO_GZ: [u8; 26] = [
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xe7,
+    0x02, 0x00, 0x20, 0x30, 0x3a, 0x36, 0x06, 0x00, 0x00, 0x00,
+];
+
+#[test]
+fn gunzip_shows_gz_files_decompressed() {
+    let mount = match Mount::new(&["--gunzip", "--gunzip-max-size", "4"], |source| {
+        fs::write(source.join("hello.gz"), HELLO_GZ).unwrap();
+        // "hi\n", gzipped.
+        fs::write(
+            source.join("hi.gz"),
+            [
+                0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0xc8, 0xe4, 0x02,
+                0x00, 0x7a, 0x7a, 0x6f, 0xed, 0x03, 0x00, 0x00, 0x00,
+            ],
+        )
+        .unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let hi = mount.mountpoint().join("hi.gz");
+    let output = Command::new("cat").arg(&hi).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"hi\n");
+    assert_eq!(fs::metadata(&hi).unwrap().len(), 3);
+
+    // Decompressed, hello.gz is over the limit.
+    let hello = mount.mountpoint().join("hello.gz");
+    let e = fs::read(hello).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EFBIG));
+}
+
+/// The space `du` reports `path` as taking up, in bytes.
+fn du(path: &Path) -> String {
+    let output = Command::new("du").arg("-B1").arg(path).output().unwrap();
+    assert!(output.status.success(), "du {:?}", path);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn du_agrees_with_the_source_tree() {
+    let mount = match Mount::new(&["--gunzip"], |source| {
+        fs::write(source.join("dense"), vec![7u8; 100_000]).unwrap();
+        let sparse = fs::File::create(source.join("sparse")).unwrap();
+        sparse.set_len(10 << 20).unwrap();
+        sparse.write_all_at(b"data", 5 << 20).unwrap();
+        // Decoded, it is smaller than the file holding it.
+        fs::write(source.join("hello.gz"), HELLO_GZ).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let source = mount.source();
+    for name in ["dense", "sparse", "hello.gz"] {
+        let through = mount.mountpoint().join(name);
+        assert_eq!(du(&through), du(&source.join(name)), "{}", name);
+    }
+    assert_eq!(
+        fs::read(mount.mountpoint().join("hello.gz")).unwrap(),
+        b"hello\n"
+    );
+}
+
+#[test]
+fn missing_source_root_fails_consistently_until_it_returns() {
+    let mount = match Mount::new(&["--missing-root", "enotconn", "--ttl", "0"], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let source = mount.source();
+    let moved = root.parent().unwrap().join("moved");
+    assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "content");
+
+    fs::rename(&source, &moved).unwrap();
+    let errno = |result: io::Result<()>| result.unwrap_err().raw_os_error();
+    let enotconn = Some(libc::ENOTCONN);
+    assert_eq!(errno(fs::read(root.join("file")).map(drop)), enotconn);
+    assert_eq!(errno(fs::metadata(root.join("absent")).map(drop)), enotconn);
+    assert_eq!(errno(fs::write(root.join("new"), "")), enotconn);
+    assert_eq!(errno(fs::read_dir(&root).map(drop)), enotconn);
+
+    fs::rename(&moved, &source).unwrap();
+    assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "content");
+    assert_eq!(
+        errno(fs::metadata(root.join("absent")).map(drop)),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn case_insensitive_names_reach_the_same_file_for_every_operation() {
+    let args = ["--case-insensitive", "--stat-cache", "60000"];
+    let mount = match Mount::new(&args, |source| {
+        fs::write(source.join("readme.txt"), "hello").unwrap();
+        fs::create_dir(source.join("docs")).unwrap();
+        fs::write(source.join("docs").join("note.txt"), "note").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let source = mount.source();
+    assert_eq!(
+        fs::read_to_string(root.join("README.TXT")).unwrap(),
+        "hello"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("DOCS").join("NOTE.TXT")).unwrap(),
+        "note"
+    );
+
+    // The attributes cached under the name found must not outlive a write through another.
+    assert_eq!(fs::metadata(root.join("readme.txt")).unwrap().len(), 5);
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(root.join("README.TXT"))
+        .unwrap();
+    file.write_all(b", world").unwrap();
+    drop(file);
+    assert_eq!(fs::metadata(root.join("README.TXT")).unwrap().len(), 12);
+    assert_eq!(
+        fs::read_to_string(source.join("readme.txt")).unwrap(),
+        "hello, world"
+    );
+
+    fs::rename(
+        root.join("DOCS").join("NOTE.TXT"),
+        root.join("docs").join("moved.txt"),
+    )
+    .unwrap();
+    assert!(source.join("docs").join("moved.txt").exists());
+    fs::remove_file(root.join("README.TXT")).unwrap();
+    assert!(!source.join("readme.txt").exists());
+    fs::remove_file(root.join("DOCS").join("MOVED.TXT")).unwrap();
+    fs::remove_dir(root.join("DOCS")).unwrap();
+    assert!(!source.join("docs").exists());
+}
+
+#[test]
+fn mkdir_makes_a_directory_listed_as_one() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    fs::create_dir(mount.mountpoint().join("dir")).unwrap();
+    assert!(fs::metadata(mount.source().join("dir")).unwrap().is_dir());
+    let entry = fs::read_dir(mount.mountpoint())
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.file_name() == "dir")
+        .expect("dir is listed");
+    assert!(entry.file_type().unwrap().is_dir());
+
+    let e = fs::create_dir(mount.mountpoint().join("dir")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+    let e = fs::create_dir(mount.mountpoint().join("absent").join("dir")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+}
+
+#[test]
+fn removed_entries_are_gone() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+        fs::create_dir_all(source.join("dir").join("child")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    fs::remove_file(root.join("file")).unwrap();
+    let e = fs::metadata(root.join("file")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    assert!(!mount.source().join("file").exists());
+
+    let e = fs::remove_dir(root.join("dir")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOTEMPTY));
+    fs::remove_dir(root.join("dir").join("child")).unwrap();
+    fs::remove_dir(root.join("dir")).unwrap();
+    let e = fs::metadata(root.join("dir")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    assert!(!mount.source().join("dir").exists());
+}
+
+#[test]
+fn renames_move_and_replace_entries() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("a"), "a").unwrap();
+        fs::write(source.join("b"), "b").unwrap();
+        fs::write(source.join("c"), "c").unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let (root, source) = (mount.mountpoint(), mount.source());
+    fs::rename(root.join("a"), root.join("renamed")).unwrap();
+    assert!(!root.join("a").exists());
+    assert_eq!(fs::read_to_string(root.join("renamed")).unwrap(), "a");
+
+    fs::rename(root.join("b"), root.join("c")).unwrap();
+    assert!(!root.join("b").exists());
+    assert_eq!(fs::read_to_string(root.join("c")).unwrap(), "b");
+
+    fs::rename(root.join("c"), root.join("dir").join("moved")).unwrap();
+    assert_eq!(
+        fs::read_to_string(root.join("dir").join("moved")).unwrap(),
+        "b"
+    );
+    let mut names = fs::read_dir(&source)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["dir", "renamed"]);
+    assert!(source.join("dir").join("moved").exists());
+}
+
+#[test]
+fn symlinks_are_created_and_read_back() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    symlink("target", root.join("short")).unwrap();
+    assert_eq!(
+        fs::read_link(root.join("short")).unwrap(),
+        Path::new("target")
+    );
+    assert!(fs::symlink_metadata(root.join("short"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(fs::symlink_metadata(mount.source().join("short"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+
+    // Longer than readlink's first buffer, so it has to grow.
+    let long = "a/".repeat(1000) + "target";
+    symlink(&long, root.join("long")).unwrap();
+    assert_eq!(fs::read_link(root.join("long")).unwrap(), Path::new(&long));
+
+    let e = symlink("target", root.join("short")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+}
+
+#[test]
+fn hard_links_share_their_content() {
+    // Each name has an inode of its own through the mount, so the kernel would otherwise go on
+    // caching the link count it last saw for the other.
+    let mount = match Mount::new(&["--ttl", "0"], |source| {
+        fs::write(source.join("file"), "before").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    fs::hard_link(root.join("file"), root.join("link")).unwrap();
+    assert_eq!(fs::metadata(root.join("file")).unwrap().nlink(), 2);
+    assert_eq!(fs::metadata(root.join("link")).unwrap().nlink(), 2);
+    let source = mount.source();
+    assert_eq!(
+        fs::metadata(source.join("file")).unwrap().ino(),
+        fs::metadata(source.join("link")).unwrap().ino()
+    );
+
+    fs::write(root.join("link"), "after").unwrap();
+    assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "after");
+
+    let e = fs::hard_link(root.join("file"), root.join("link")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+}
+
+/// 1 MiB in which every 4-byte word holds its own index, so any block read from the wrong offset
+/// stands out.
+fn pattern() -> Vec<u8> {
+    (0..1u32 << 18).flat_map(u32::to_le_bytes).collect()
+}
+
+#[test]
+fn concurrent_reads_on_one_handle_get_their_own_offsets() {
+    let expected = pattern();
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), pattern()).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let (file, expected) = (&file, &expected);
+            scope.spawn(move || {
+                let mut buf = vec![0; 4096];
+                for round in 0..64 {
+                    let offset =
+                        ((thread * 64 + round) * 4096 + thread * 4) % (expected.len() - 4096);
+                    file.read_exact_at(&mut buf, offset as u64).unwrap();
+                    assert!(buf == expected[offset..offset + buf.len()]);
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn concurrent_writes_on_one_handle_land_at_their_offsets() {
+    let expected = pattern();
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = fs::File::create(mount.mountpoint().join("file")).unwrap();
+    let chunk = expected.len() / 8;
+    std::thread::scope(|scope| {
+        for region in expected.chunks(chunk).enumerate() {
+            let file = &file;
+            scope.spawn(move || {
+                let (index, data) = region;
+                for (block, part) in data.chunks(4096).enumerate() {
+                    file.write_all_at(part, (index * chunk + block * 4096) as u64)
+                        .unwrap();
+                }
+            });
+        }
+    });
+    drop(file);
+    assert!(fs::read(mount.source().join("file")).unwrap() == expected);
+}
+
+/// The names listed in `dir`, each with its type, in name order.
+fn listing(dir: &Path) -> Vec<(String, fs::FileType)> {
+    let mut entries = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (
+                entry.file_name().into_string().unwrap(),
+                entry.file_type().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+#[test]
+fn readdir_lists_each_entry_with_its_type() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "").unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+        symlink("file", source.join("link")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let entries = listing(&mount.mountpoint());
+    let names = entries.iter().map(|(name, _)| name).collect::<Vec<_>>();
+    assert_eq!(names, ["dir", "file", "link"]);
+    assert!(entries[0].1.is_dir());
+    assert!(entries[1].1.is_file());
+    assert!(entries[2].1.is_symlink());
+}
+
+#[test]
+fn directory_handles_are_listed_independently() {
+    let mount = match Mount::new(&[], |source| {
+        for i in 0..500 {
+            fs::write(source.join(format!("file-{:03}", i)), "").unwrap();
+        }
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let (mut first, mut second) = (fs::read_dir(&root).unwrap(), fs::read_dir(&root).unwrap());
+    let (mut first_names, mut second_names) = (vec![], vec![]);
+    loop {
+        let (a, b) = (first.next(), second.next());
+        if a.is_none() && b.is_none() {
+            break;
+        }
+        first_names.extend(a.map(|entry| entry.unwrap().file_name()));
+        second_names.extend(b.map(|entry| entry.unwrap().file_name()));
+    }
+    first_names.sort();
+    second_names.sort();
+    assert_eq!(first_names.len(), 500);
+    assert_eq!(first_names, second_names);
+}
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let name = CString::new(name).unwrap();
+    let result = unsafe {
+        libc::lsetxattr(
+            c_path(path).as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The value of the attribute `name`, read the way most callers do: asking its size first.
+fn get_xattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    let (path, name) = (c_path(path), CString::new(name).unwrap());
+    let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut value = vec![0u8; size as usize];
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if len == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    value.truncate(len as usize);
+    Ok(value)
+}
+
+/// The attribute names on `path`, each with its terminating NUL.
+fn list_xattrs(path: &Path) -> io::Result<Vec<u8>> {
+    let path = c_path(path);
+    let mut names = vec![0u8; 4096];
+    let len = unsafe {
+        libc::llistxattr(
+            path.as_ptr(),
+            names.as_mut_ptr() as *mut libc::c_char,
+            names.len(),
+        )
+    };
+    if len == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(len as usize);
+    Ok(names)
+}
+
+#[test]
+fn extended_attributes_round_trip() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+    if let Err(e) = set_xattr(&mount.source().join("file"), "user.probe", b"") {
+        eprintln!(
+            "skipping: the source tree can't hold user attributes: {}",
+            e
+        );
+        return;
+    }
+
+    let file = mount.mountpoint().join("file");
+    set_xattr(&file, "user.test", b"value").unwrap();
+    assert_eq!(get_xattr(&file, "user.test").unwrap(), b"value");
+    assert_eq!(
+        get_xattr(&mount.source().join("file"), "user.test").unwrap(),
+        b"value"
+    );
+    let names = list_xattrs(&file).unwrap();
+    assert!(names.split(|&b| b == 0).any(|name| name == b"user.test"));
+
+    let name = CString::new("user.test").unwrap();
+    assert_eq!(
+        unsafe { libc::lremovexattr(c_path(&file).as_ptr(), name.as_ptr()) },
+        0
+    );
+    let e = get_xattr(&file, "user.test").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENODATA));
+}
+
+#[test]
+fn times_before_1970_read_back_exactly() {
+    let mtime = std::time::UNIX_EPOCH - std::time::Duration::new(86_400 * 365, 250_000_000);
+    let mount = match Mount::new(&[], |source| {
+        let file = fs::File::create(source.join("old")).unwrap();
+        file.set_modified(mtime).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let meta = fs::metadata(mount.mountpoint().join("old")).unwrap();
+    assert_eq!(meta.modified().unwrap(), mtime);
+    assert_eq!(meta.mtime(), -86_400 * 365 - 1);
+    assert_eq!(meta.mtime_nsec(), 750_000_000);
+
+    let earlier = mtime - std::time::Duration::from_millis(500);
+    let file = fs::File::options()
+        .write(true)
+        .open(mount.mountpoint().join("old"))
+        .unwrap();
+    file.set_modified(earlier).unwrap();
+    let meta = fs::metadata(mount.source().join("old")).unwrap();
+    assert_eq!(meta.modified().unwrap(), earlier);
+}
+
+/// The source and options of the mount at `mountpoint`, as the kernel lists them.
+fn mount_entry(mountpoint: &Path) -> (String, Vec<String>) {
+    let mounts = fs::read_to_string("/proc/self/mounts").unwrap();
+    let mountpoint = mountpoint.to_str().unwrap();
+    let fields = mounts
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .find(|fields| fields[1] == mountpoint)
+        .expect("the mount is listed");
+    (
+        fields[0].to_string(),
+        fields[3].split(',').map(String::from).collect(),
+    )
+}
+
+#[test]
+fn mount_options_come_from_the_command_line() {
+    let mount = match Mount::new(
+        &["--fsname", "testfs", "--read-only", "-o", "nosuid"],
+        |_| {},
+    ) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let (fsname, options) = mount_entry(&mount.mountpoint());
+    assert_eq!(fsname, "testfs");
+    assert!(options.iter().any(|option| option == "ro"), "{:?}", options);
+    assert!(
+        options.iter().any(|option| option == "nosuid"),
+        "{:?}",
+        options
+    );
+}
+
+#[test]
+fn signals_unmount_so_the_next_mount_succeeds() {
+    let mut mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let status = mount.stop(signal);
+        assert!(status.success(), "{}", status);
+        assert!(!mount.is_mounted());
+        mount.restart(&[]);
+        assert_eq!(
+            fs::read_to_string(mount.mountpoint().join("file")).unwrap(),
+            "content"
+        );
+    }
+}
+
+#[test]
+fn fsynced_data_is_in_the_source_tree() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let mut file = fs::File::create(mount.mountpoint().join("file")).unwrap();
+    file.write_all(b"first").unwrap();
+    file.sync_all().unwrap();
+    assert_eq!(fs::read(mount.source().join("file")).unwrap(), b"first");
+    file.write_all(b" second").unwrap();
+    file.sync_data().unwrap();
+    assert_eq!(
+        fs::read(mount.source().join("file")).unwrap(),
+        b"first second"
+    );
+}
+
+fn access_errno(path: &Path, mode: libc::c_int) -> Option<i32> {
+    match unsafe { libc::access(c_path(path).as_ptr(), mode) } {
+        0 => None,
+        _ => io::Error::last_os_error().raw_os_error(),
+    }
+}
+
+#[test]
+fn access_is_decided_by_the_source_tree() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "").unwrap();
+        let script = source.join("script");
+        fs::write(&script, "").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    assert_eq!(access_errno(&root.join("file"), libc::F_OK), None);
+    assert_eq!(
+        access_errno(&root.join("file"), libc::R_OK | libc::W_OK),
+        None
+    );
+    assert_eq!(access_errno(&root.join("script"), libc::X_OK), None);
+    // Even root can only execute a file some execute bit is set on.
+    assert_eq!(
+        access_errno(&root.join("file"), libc::X_OK),
+        Some(libc::EACCES)
+    );
+    assert_eq!(
+        access_errno(&root.join("absent"), libc::F_OK),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn xor_key_decodes_reads_at_any_offset() {
+    let mount = match Mount::new(&["--xor-key", "key"], |source| {
+        let encoded = b"plain text"
+            .iter()
+            .zip(b"key".iter().cycle())
+            .map(|(byte, key)| byte ^ key)
+            .collect::<Vec<_>>();
+        fs::write(source.join("file"), encoded).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let path = mount.mountpoint().join("file");
+    assert_eq!(fs::read(&path).unwrap(), b"plain text");
+    let mut buf = [0; 4];
+    fs::File::open(&path)
+        .unwrap()
+        .read_exact_at(&mut buf, 6)
+        .unwrap();
+    assert_eq!(&buf, b"text");
+}
+
+#[test]
+fn hidden_names_vanish_from_listings_and_lookups() {
+    let mount = match Mount::new(&["--hide", "*.log"], |source| {
+        fs::write(source.join("keep.txt"), "").unwrap();
+        fs::write(source.join("debug.log"), "").unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("dir").join("nested.log"), "").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let names = |dir: &Path| {
+        listing(dir)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&root), ["dir", "keep.txt"]);
+    assert!(names(&root.join("dir")).is_empty());
+    for hidden in [root.join("debug.log"), root.join("dir").join("nested.log")] {
+        let e = fs::File::open(&hidden).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    }
+}
+
+#[test]
+fn union_roots_are_merged_with_the_first_shadowing() {
+    let mount = match Mount::new(&["--union", "second"], |source| {
+        let second = source.parent().unwrap().join("second");
+        fs::create_dir(&second).unwrap();
+        fs::write(source.join("both"), "first").unwrap();
+        fs::write(second.join("both"), "second").unwrap();
+        fs::write(second.join("only-second"), "second").unwrap();
+        fs::create_dir(second.join("dir")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let names = listing(&root)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["both", "dir", "only-second"]);
+    assert_eq!(fs::read_to_string(root.join("both")).unwrap(), "first");
+    assert_eq!(
+        fs::read_to_string(root.join("only-second")).unwrap(),
+        "second"
+    );
+
+    // New entries go alongside their parent, in whichever root that is.
+    fs::write(root.join("dir").join("new"), "new").unwrap();
+    let second = mount.source().parent().unwrap().join("second");
+    assert!(second.join("dir").join("new").exists());
+}
+
+#[test]
+fn copy_file_range_within_the_mount_falls_back_to_reads_and_writes() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), pattern()).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // decofs can't implement copy_file_range, so the kernel copies with reads and writes.
+    let root = mount.mountpoint();
+    let from = fs::File::open(root.join("file")).unwrap();
+    let to = fs::File::create(root.join("copy")).unwrap();
+    let len = pattern().len();
+    let mut copied = 0;
+    while copied < len {
+        let n = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                std::ptr::null_mut(),
+                to.as_raw_fd(),
+                std::ptr::null_mut(),
+                len - copied,
+                0,
+            )
+        };
+        assert!(n > 0, "{}", io::Error::last_os_error());
+        copied += n as usize;
+    }
+    drop(to);
+    assert!(fs::read(mount.source().join("copy")).unwrap() == pattern());
+}
+
+#[test]
+fn sparse_files_seek_as_though_fully_allocated() {
+    let mount = match Mount::new(&[], |source| {
+        let file = fs::File::create(source.join("sparse")).unwrap();
+        file.write_all_at(b"end", 1 << 20).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // Without lseek reaching decofs, the kernel sees no holes.
+    let file = fs::File::open(mount.mountpoint().join("sparse")).unwrap();
+    let seek = |offset, whence| unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    let len = (1 << 20) + 3;
+    assert_eq!(seek(4096, libc::SEEK_DATA), 4096);
+    assert_eq!(seek(4096, libc::SEEK_HOLE), len);
+    assert_eq!(seek(len, libc::SEEK_DATA), -1);
+    assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ENXIO));
+}
+
+#[test]
+fn fallocate_is_not_supported() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mount.mountpoint().join("file"))
+        .unwrap();
+    let fallocate = |mode, offset, len| unsafe {
+        match libc::fallocate(file.as_raw_fd(), mode, offset, len) {
+            0 => None,
+            _ => io::Error::last_os_error().raw_os_error(),
+        }
+    };
+    assert_eq!(fallocate(0, 0, 1 << 20), Some(libc::EOPNOTSUPP));
+    let punch = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    assert_eq!(fallocate(punch, 0, 4), Some(libc::EOPNOTSUPP));
+    assert_eq!(
+        fs::read_to_string(mount.source().join("file")).unwrap(),
+        "content"
+    );
+}
+
+#[test]
+fn created_files_have_the_requested_mode() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o640)
+        .open(mount.mountpoint().join("file"))
+        .unwrap();
+    file.write_all(b"content").unwrap();
+    drop(file);
+    let meta = fs::metadata(mount.source().join("file")).unwrap();
+    assert_eq!(meta.mode() & 0o777, 0o640);
+    assert_eq!(
+        fs::read_to_string(mount.source().join("file")).unwrap(),
+        "content"
+    );
+}
+
+#[test]
+fn mknod_makes_fifos() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let fifo = mount.mountpoint().join("fifo");
+    assert_eq!(
+        unsafe { libc::mkfifo(c_path(&fifo).as_ptr(), 0o644) },
+        0,
+        "{}",
+        io::Error::last_os_error()
+    );
+    assert!(fs::symlink_metadata(&fifo).unwrap().file_type().is_fifo());
+    assert!(fs::symlink_metadata(mount.source().join("fifo"))
+        .unwrap()
+        .file_type()
+        .is_fifo());
+    let entries = listing(&mount.mountpoint());
+    assert!(entries[0].1.is_fifo());
+}
+
+#[test]
+fn appends_from_two_handles_never_overwrite_each_other() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("log"), "start\n").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let path = mount.mountpoint().join("log");
+    let open = || fs::OpenOptions::new().append(true).open(&path).unwrap();
+    let (mut first, mut second) = (open(), open());
+    std::thread::scope(|scope| {
+        for (file, line) in [(&mut first, "first\n"), (&mut second, "second\n")] {
+            scope.spawn(move || {
+                for _ in 0..100 {
+                    file.write_all(line.as_bytes()).unwrap();
+                }
+            });
+        }
+    });
+    drop((first, second));
+
+    let content = fs::read_to_string(mount.source().join("log")).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 201);
+    assert_eq!(lines[0], "start");
+    assert_eq!(lines.iter().filter(|&&line| line == "first").count(), 100);
+    assert_eq!(lines.iter().filter(|&&line| line == "second").count(), 100);
+}
+
+fn statvfs(path: &Path) -> libc::statvfs {
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    assert_eq!(
+        unsafe { libc::statvfs(c_path(path).as_ptr(), stat.as_mut_ptr()) },
+        0,
+        "{}",
+        io::Error::last_os_error()
+    );
+    unsafe { stat.assume_init() }
+}
+
+#[test]
+fn statfs_is_the_same_from_every_directory() {
+    let mount = match Mount::new(&[], |source| {
+        fs::create_dir_all(source.join("a").join("b")).unwrap();
+        fs::write(source.join("a").join("file"), "").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let expected = statvfs(&root);
+    assert!(expected.f_bsize > 0 && expected.f_blocks > 0);
+    for path in [
+        root.join("a"),
+        root.join("a").join("b"),
+        root.join("a").join("file"),
+    ] {
+        let stat = statvfs(&path);
+        assert_eq!(
+            (stat.f_bsize, stat.f_frsize, stat.f_blocks, stat.f_files),
+            (
+                expected.f_bsize,
+                expected.f_frsize,
+                expected.f_blocks,
+                expected.f_files
+            ),
+            "{:?}",
+            path
+        );
+    }
+}
+
+#[test]
+fn buffered_writes_are_read_back_before_they_reach_the_source() {
+    let mount = match Mount::new(&["--write-buffer", "65536"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(mount.mountpoint().join("file"))
+        .unwrap();
+    for i in 0..100u32 {
+        file.write_all(&i.to_le_bytes()).unwrap();
+    }
+    assert!(fs::read(mount.source().join("file")).unwrap().len() < 400);
+    let mut start = [0; 8];
+    file.read_exact_at(&mut start, 0).unwrap();
+    assert_eq!(start, [0, 0, 0, 0, 1, 0, 0, 0]);
+
+    drop(file);
+    let written = fs::read(mount.source().join("file")).unwrap();
+    assert_eq!(written.len(), 400);
+    assert_eq!(&written[396..], 99u32.to_le_bytes());
+}
+
+#[test]
+fn fibmap_through_the_mount_reports_no_physical_block() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), vec![1; 8192]).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // The kernel answers FIBMAP itself for a mount not backed by a block device, without asking
+    // decofs, so block 0 of the file maps to block 0: unmapped.
+    const FIBMAP: libc::c_ulong = 1;
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    let mut block: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), FIBMAP as _, &mut block) };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    assert_eq!(block, 0);
+}
+
+#[test]
+fn file_mode_overrides_the_requested_mode() {
+    let mount = match Mount::new(&["--file-mode", "664", "--umask", "077"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = mount.mountpoint().join("file");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&file)
+        .unwrap();
+    assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o664);
+    let meta = fs::metadata(mount.source().join("file")).unwrap();
+    assert_eq!(meta.mode() & 0o777, 0o664);
+}
+
+#[test]
+fn many_threads_serve_parallel_reads_of_many_files() {
+    let mount = match Mount::new(&["--threads", "8"], |source| {
+        for i in 0..32 {
+            fs::write(source.join(i.to_string()), i.to_string().repeat(10_000)).unwrap();
+        }
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    std::thread::scope(|scope| {
+        for i in 0..32 {
+            let path = root.join(i.to_string());
+            scope.spawn(move || {
+                let content = fs::read_to_string(path).unwrap();
+                assert!(content == i.to_string().repeat(10_000));
+            });
+        }
+    });
+}
+
+#[test]
+fn stats_file_counts_operations() {
+    let mount = match Mount::new(&["--stats-file"], |source| {
+        for name in ["a", "b", "c"] {
+            fs::write(source.join(name), "content").unwrap();
+        }
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let (reads, mkdirs) = (counter(&root, "read"), counter(&root, "mkdir"));
+    // Separate files, so that no read is answered from the page cache.
+    for name in ["a", "b", "c"] {
+        assert_eq!(fs::read_to_string(root.join(name)).unwrap(), "content");
+    }
+    fs::create_dir(root.join("dir")).unwrap();
+    assert!(counter(&root, "read") >= reads + 3);
+    assert_eq!(counter(&root, "mkdir"), mkdirs + 1);
+}
+
+#[test]
+fn closing_a_written_file_flushes_it_to_the_source() {
+    let mount = match Mount::new(&["--stats-file"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // Each close is flushed, and the flush is where written data is synced, since the kernel
+    // doesn't ask for it on release.
+    let root = mount.mountpoint();
+    let flushes = counter(&root, "flush");
+    let mut file = fs::File::create(root.join("file")).unwrap();
+    file.write_all(b"durable").unwrap();
+    drop(file);
+    assert!(counter(&root, "flush") > flushes);
+    assert_eq!(fs::read(mount.source().join("file")).unwrap(), b"durable");
+}
+
+#[test]
+fn max_read_is_passed_to_the_kernel() {
+    let mount = match Mount::new(&["--max-read", "65536"], |source| {
+        fs::write(source.join("file"), vec![0; 1 << 20]).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let (_, options) = mount_entry(&mount.mountpoint());
+    assert!(
+        options.iter().any(|option| option == "max_read=65536"),
+        "{:?}",
+        options
+    );
+    let content = fs::read(mount.mountpoint().join("file")).unwrap();
+    assert_eq!(content.len(), 1 << 20);
+}
+
+#[test]
+fn audit_reports_success_without_touching_the_source() {
+    let mount = match Mount::new(&["--audit"], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(root.join("file"))
+        .unwrap();
+    assert_eq!(file.write_at(b"changed", 0).unwrap(), 7);
+    drop(file);
+    fs::create_dir(root.join("dir")).unwrap();
+    fs::remove_file(root.join("file")).unwrap();
+
+    let source = mount.source();
+    assert_eq!(fs::read_to_string(source.join("file")).unwrap(), "content");
+    assert!(!source.join("dir").exists());
+}
+
+#[test]
+fn audit_can_reply_with_erofs() {
+    let mount = match Mount::new(&["--audit=erofs"], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let e = fs::remove_file(mount.mountpoint().join("file")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+    assert!(mount.source().join("file").exists());
+}
+
+#[test]
+fn upper_layer_takes_every_change_and_leaves_the_lower_untouched() {
+    let mount = match Mount::new(&["--upper", "upper"], |source| {
+        fs::create_dir(source.parent().unwrap().join("upper")).unwrap();
+        fs::write(source.join("file"), "lower").unwrap();
+        fs::write(source.join("doomed"), "lower").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let (root, lower) = (mount.mountpoint(), mount.source());
+    let upper = lower.parent().unwrap().join("upper");
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(root.join("file"))
+        .unwrap();
+    file.write_all_at(b"UP", 0).unwrap();
+    drop(file);
+    assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "UPwer");
+    assert_eq!(fs::read_to_string(upper.join("file")).unwrap(), "UPwer");
+    assert_eq!(fs::read_to_string(lower.join("file")).unwrap(), "lower");
+
+    fs::write(root.join("new"), "new").unwrap();
+    assert!(upper.join("new").exists());
+    assert!(!lower.join("new").exists());
+
+    fs::remove_file(root.join("doomed")).unwrap();
+    assert!(!root.join("doomed").exists());
+    assert!(upper.join(".wh.doomed").exists());
+    assert_eq!(fs::read_to_string(lower.join("doomed")).unwrap(), "lower");
+    let names = listing(&root)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["file", "new"]);
+}
+
+#[test]
+fn id_maps_translate_owners_both_ways() {
+    let mount = match Mount::new(&["--map-uid", "1000:0", "--map-gid", "1000:0"], |source| {
+        for (name, id) in [("mapped", 1000), ("unmapped", 2000)] {
+            let path = source.join(name);
+            fs::write(&path, "").unwrap();
+            std::os::unix::fs::chown(&path, Some(id), Some(id)).unwrap();
+        }
+        fs::write(source.join("root-owned"), "").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let owner = |path: &Path| {
+        let meta = fs::metadata(path).unwrap();
+        (meta.uid(), meta.gid())
+    };
+    assert_eq!(owner(&root.join("mapped")), (0, 0));
+    assert_eq!(owner(&root.join("unmapped")), (2000, 2000));
+
+    std::os::unix::fs::chown(root.join("root-owned"), Some(0), Some(0)).unwrap();
+    assert_eq!(owner(&mount.source().join("root-owned")), (1000, 1000));
+    fs::write(root.join("created"), "").unwrap();
+    assert_eq!(owner(&mount.source().join("created")), (1000, 1000));
+}
+
+#[test]
+fn large_directories_are_listed_in_full() {
+    const ENTRIES: usize = 20_000;
+    let mount = match Mount::new(&[], |source| {
+        for i in 0..ENTRIES {
+            fs::File::create(source.join(format!("entry-with-a-longish-name-{:05}", i))).unwrap();
+        }
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // Far more than one readdir reply holds, so the kernel pages through the listing by offset.
+    let mut names = fs::read_dir(mount.mountpoint())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    assert_eq!(names.len(), ENTRIES);
+    assert_eq!(names[0], "entry-with-a-longish-name-00000");
+    assert_eq!(names[ENTRIES - 1], "entry-with-a-longish-name-19999");
+}
+
+#[test]
+fn prefix_exposes_only_the_directory_within() {
+    let mount = match Mount::new(&["--prefix", "/data"], |source| {
+        fs::create_dir(source.join("data")).unwrap();
+        fs::write(source.join("data").join("inside"), "inside").unwrap();
+        fs::write(source.join("outside"), "outside").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    assert_eq!(fs::read_to_string(root.join("inside")).unwrap(), "inside");
+    let names = listing(&root)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["inside"]);
+    let e = fs::metadata(root.join("outside")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    let e = fs::File::open(root.join("data").join("inside")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    fs::write(root.join("new"), "").unwrap();
+    assert!(mount.source().join("data").join("new").exists());
+}
+
+#[test]
+fn fsyncdir_syncs_a_directory() {
+    let mount = match Mount::new(&[], |source| {
+        fs::create_dir(source.join("dir")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let dir = mount.mountpoint().join("dir");
+    fs::write(dir.join("file"), "content").unwrap();
+    let handle = fs::File::open(&dir).unwrap();
+    handle.sync_all().unwrap();
+    handle.sync_data().unwrap();
+}
+
+#[test]
+fn reads_are_only_short_at_end_of_file() {
+    let expected = pattern();
+    let mount = match Mount::new(&["--direct-io"], |source| {
+        fs::write(source.join("file"), pattern()).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // Direct, so that each read reaches decofs with the size asked for.
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    let mut buf = vec![0; 256 << 10];
+    assert_eq!(file.read_at(&mut buf, 4096).unwrap(), buf.len());
+    assert!(buf == expected[4096..4096 + buf.len()]);
+    let tail = expected.len() - 1000;
+    assert_eq!(file.read_at(&mut buf, tail as u64).unwrap(), 1000);
+    assert!(buf[..1000] == expected[tail..]);
+    assert_eq!(file.read_at(&mut buf, expected.len() as u64).unwrap(), 0);
+}
+
+#[test]
+fn watched_changes_to_the_source_tree_are_seen_despite_the_stat_cache() {
+    let mount = match Mount::new(
+        &["--watch", "--stat-cache", "60000", "--ttl", "0"],
+        |source| {
+            fs::write(source.join("file"), "old").unwrap();
+        },
+    ) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = mount.mountpoint().join("file");
+    assert_eq!(fs::metadata(&file).unwrap().len(), 3);
+    fs::write(mount.source().join("file"), "changed").unwrap();
+    let mut len = 0;
+    for _ in 0..50 {
+        len = fs::metadata(&file).unwrap().len();
+        if len == 7 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(len, 7);
+}
+
+#[test]
+fn inode_flags_ioctl_fails_with_enotty() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    let mut flags: libc::c_long = 0;
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    assert_eq!(result, -1);
+    assert_eq!(
+        io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENOTTY)
+    );
+}
+
+#[test]
+fn unsupported_operations_report_their_real_cause() {
+    let mount = match Mount::new(&["--stats-file"], |source| {
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let errno = |result: io::Result<()>| result.unwrap_err().raw_os_error();
+    let e = fs::OpenOptions::new().write(true).open(root.join("dir"));
+    assert_eq!(errno(e.map(drop)), Some(libc::EISDIR));
+
+    // No filesystem supports an attribute outside the known namespaces.
+    let path = c_path(&root.join("file"));
+    let name = CString::new("unknown.attribute").unwrap();
+    let result =
+        unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"x".as_ptr().cast(), 1, 0) };
+    assert_eq!(result, -1);
+    assert_eq!(
+        io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENOTSUP)
+    );
+
+    assert_eq!(
+        errno(fs::remove_file(root.join(".decofs-stats"))),
+        Some(libc::EPERM)
+    );
+    assert!(root.join(".decofs-stats").exists());
+}
+
+#[test]
+fn aligned_o_direct_reads_pass_through() {
+    let expected = pattern();
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), pattern()).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let open_direct = |path: &Path| {
+        fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+    };
+    if open_direct(&mount.source().join("file")).is_err() {
+        eprintln!("skipping: the source tree doesn't support O_DIRECT");
+        return;
+    }
+    let file = open_direct(&mount.mountpoint().join("file")).unwrap();
+    let mut storage = vec![0u8; 3 * 4096];
+    let start = storage.as_ptr().align_offset(4096);
+    let buf = &mut storage[start..start + 2 * 4096];
+    assert_eq!(file.read_at(buf, 4096).unwrap(), buf.len());
+    assert!(*buf == expected[4096..3 * 4096]);
+}
+
+#[test]
+fn opening_beyond_max_handles_fails_with_emfile() {
+    let mount = match Mount::new(&["--max-handles", "3"], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let mut handles = vec![fs::File::open(root.join("file")).unwrap()];
+    handles.push(fs::File::open(root.join("file")).unwrap());
+    let dir = fs::read_dir(&root).unwrap();
+    assert_eq!(open_errno(&root.join("file"), 0), Some(libc::EMFILE));
+    let created = fs::File::create(root.join("other"));
+    assert_eq!(created.unwrap_err().raw_os_error(), Some(libc::EMFILE));
+
+    // Closing one makes room for the next.
+    drop(dir);
+    handles.push(fs::File::open(root.join("file")).unwrap());
+}
+
+#[test]
+fn times_are_set_to_the_nanosecond() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+    let atime = std::time::UNIX_EPOCH + std::time::Duration::new(1_500_000_000, 987_654_321);
+    let file = fs::File::options()
+        .write(true)
+        .open(mount.mountpoint().join("file"))
+        .unwrap();
+    file.set_times(fs::FileTimes::new().set_modified(mtime).set_accessed(atime))
+        .unwrap();
+    for path in [mount.mountpoint().join("file"), mount.source().join("file")] {
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.modified().unwrap(), mtime, "{:?}", path);
+        assert_eq!(metadata.accessed().unwrap(), atime, "{:?}", path);
+    }
+
+    // Setting only one leaves the other alone.
+    let later = mtime + std::time::Duration::from_nanos(1);
+    file.set_modified(later).unwrap();
+    let metadata = fs::metadata(mount.source().join("file")).unwrap();
+    assert_eq!(metadata.modified().unwrap(), later);
+    assert_eq!(metadata.accessed().unwrap(), atime);
+}
+
+#[test]
+fn symlinks_swapped_in_after_lookup_cannot_redirect_an_open() {
+    let mount = match Mount::new(&["--ttl", "60"], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+        fs::write(source.parent().unwrap().join("secret"), "secret").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // The kernel keeps the entry it looked up as a regular file, so sends the open on as one.
+    let file = mount.mountpoint().join("file");
+    assert!(fs::metadata(&file).unwrap().is_file());
+    let real = mount.source().join("file");
+    fs::remove_file(&real).unwrap();
+    symlink(mount.source().parent().unwrap().join("secret"), &real).unwrap();
+    let result = fs::read_to_string(&file);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ELOOP));
+}
+
+#[test]
+fn buffered_writes_lost_to_a_full_source_fail_fsync_and_close() {
+    let mut tmpfs = false;
+    let mount = match Mount::new(&["--write-buffer", "1048576"], |source| {
+        tmpfs = mount_tmpfs(source, "size=64k")
+    }) {
+        Some(mount) if tmpfs => mount,
+        _ => return,
+    };
+
+    let mut file = fs::File::create(mount.mountpoint().join("big")).unwrap();
+    for _ in 0..128 {
+        file.write_all(&[0u8; 4096]).unwrap();
+    }
+    for _ in 0..2 {
+        let result = file.sync_all();
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+    }
+    let fd = file.into_raw_fd();
+    assert_eq!(unsafe { libc::close(fd) }, -1);
+    assert_eq!(
+        io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENOSPC)
+    );
+}
+
+#[test]
+fn closing_a_buffered_file_leaves_its_data_in_the_source_tree() {
+    let mount = match Mount::new(&["--write-buffer", "65536"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let mut file = fs::File::create(mount.mountpoint().join("file")).unwrap();
+    file.write_all(b"written ").unwrap();
+    file.write_all(b"then closed").unwrap();
+    let fd = file.into_raw_fd();
+    assert_eq!(unsafe { libc::close(fd) }, 0);
+    let content = fs::read_to_string(mount.source().join("file")).unwrap();
+    assert_eq!(content, "written then closed");
+}
+
+#[test]
+fn info_file_describes_the_mount_and_is_listed_on_request() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let info = fs::read_to_string(root.join(".decofs-info")).unwrap();
+    let source = mount.source().canonicalize().unwrap();
+    assert!(info.contains(source.to_str().unwrap()), "{}", info);
+    assert!(!mount.source().join(".decofs-info").exists());
+    let names = |dir: &Path| {
+        listing(dir)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&root), ["file"]);
+    drop(mount);
+
+    let mount = match Mount::new(&["--list-info"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+    assert_eq!(names(&mount.mountpoint()), [".decofs-info"]);
+}
+
+#[test]
+fn device_nodes_are_listed_but_not_opened() {
+    let mut made = false;
+    let mount = match Mount::new(&[], |source| {
+        let path = c_path(&source.join("null"));
+        made =
+            unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o666, libc::makedev(1, 3)) } == 0;
+    }) {
+        Some(mount) if made => mount,
+        Some(_) => {
+            eprintln!("skipping: can't make a device node");
+            return;
+        }
+        None => return,
+    };
+
+    let null = mount.mountpoint().join("null");
+    let metadata = fs::symlink_metadata(&null).unwrap();
+    assert!(metadata.file_type().is_char_device());
+    assert_eq!(metadata.rdev(), libc::makedev(1, 3));
+    let result = fs::File::open(&null);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EACCES));
+}
+
+#[test]
+fn sorted_listings_are_in_byte_order() {
+    let names = ["m", "B", "z", "a", "é", "Z", "0", "mm"];
+    let mount = match Mount::new(&["--sort-entries"], |source| {
+        for name in names {
+            fs::write(source.join(name), name).unwrap();
+        }
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let listed = fs::read_dir(mount.mountpoint())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    let mut sorted = names.map(String::from).to_vec();
+    sorted.sort();
+    assert_eq!(listed, sorted);
+}
+
+#[test]
+fn reads_are_throttled_to_the_read_limit() {
+    let limit = 256 << 10;
+    let mount = match Mount::new(&["--read-limit", &limit.to_string()], |source| {
+        fs::write(source.join("file"), vec![1u8; limit * 5 / 2]).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // A second's worth can be read at once; the rest has to wait for the limit.
+    let started = std::time::Instant::now();
+    let content = fs::read(mount.mountpoint().join("file")).unwrap();
+    assert_eq!(content.len(), limit * 5 / 2);
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= std::time::Duration::from_millis(1400),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn names_and_paths_too_long_for_the_source_fail_with_enametoolong() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let result = fs::File::create(root.join("n".repeat(300)));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENAMETOOLONG));
+
+    // Descend relative to each new directory, so that only the path in the source tree, and not
+    // the one the client passes, grows beyond PATH_MAX.
+    let name = CString::new("d".repeat(200)).unwrap();
+    let mut dir = fs::File::open(&root).unwrap();
+    let errno = loop {
+        if unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755) } == -1 {
+            break io::Error::last_os_error().raw_os_error();
+        }
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), libc::O_RDONLY) };
+        assert!(fd >= 0);
+        dir = unsafe { fs::File::from_raw_fd(fd) };
+    };
+    assert_eq!(errno, Some(libc::ENAMETOOLONG));
+}
+
+#[test]
+fn sockets_are_listed_and_fail_to_open_without_stalling_the_mount() {
+    let mount = match Mount::new(&["--threads", "1"], |source| {
+        // The socket stays in the source tree once the listener is dropped.
+        std::os::unix::net::UnixListener::bind(source.join("socket")).unwrap();
+        fs::write(source.join("file"), "content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let listed = listing(&root);
+    assert_eq!(listed[1].0, "socket");
+    assert!(listed[1].1.is_socket());
+    let result = fs::File::open(root.join("socket"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENXIO));
+    assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "content");
+}
+
+#[test]
+fn read_ahead_data_matches_the_file() {
+    let expected = pattern();
+    let mount = match Mount::new(&["--readahead", "65536", "--direct-io"], |source| {
+        fs::write(source.join("file"), pattern()).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // Direct, so that each read reaches decofs and those after the first are served from what
+    // was read ahead, then a jump back that isn't.
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    let mut buf = vec![0; 4096];
+    for offset in (0..expected.len()).step_by(buf.len()).chain([8192, 0]) {
+        let n = file.read_at(&mut buf, offset as u64).unwrap();
+        assert!(buf[..n] == expected[offset..offset + n], "at {}", offset);
+    }
+}
+
+/// Whether `nobody` can read `path`, or None if commands can't be run as another user.
+fn readable_by_nobody(path: &Path) -> Option<bool> {
+    let status = Command::new("setpriv")
+        .args(["--reuid=65534", "--regid=65534", "--clear-groups", "cat"])
+        .arg(path)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .ok()?;
+    Some(status.success())
+}
+
+#[test]
+fn default_permissions_deny_non_owners_what_the_mode_denies() {
+    let mount = match Mount::new(&["--default-permissions", "--allow-other"], |source| {
+        for (name, mode) in [("open", 0o644), ("closed", 0o000)] {
+            fs::write(source.join(name), "content").unwrap();
+            fs::set_permissions(source.join(name), fs::Permissions::from_mode(mode)).unwrap();
+        }
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    // decofs itself reads as root, so without the flag both would be readable.
+    if readable_by_nobody(&root.join("open")) != Some(true) {
+        eprintln!("skipping: can't read through the mount as nobody");
+        return;
+    }
+    assert_eq!(readable_by_nobody(&root.join("closed")), Some(false));
+}
+
+#[test]
+fn batched_fsyncs_each_succeed_with_their_data_written() {
+    let mount = match Mount::new(&["--fsync-batch", "50", "--threads", "4"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let path = root.join(format!("file{}", i));
+            scope.spawn(move || {
+                let mut file = fs::File::create(path).unwrap();
+                file.write_all(format!("content {}", i).as_bytes()).unwrap();
+                file.sync_all().unwrap();
+            });
+        }
+    });
+    for i in 0..8 {
+        let content = fs::read_to_string(mount.source().join(format!("file{}", i))).unwrap();
+        assert_eq!(content, format!("content {}", i));
+    }
+}
+
+#[test]
+fn opening_a_directory_that_replaced_a_file_fails_with_eisdir() {
+    let mount = match Mount::new(&["--ttl", "60"], |source| {
+        fs::write(source.join("entry"), "content").unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let result = fs::OpenOptions::new().write(true).open(root.join("dir"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EISDIR));
+
+    // The kernel keeps the entry it looked up as a file, so sends the open on as one.
+    assert!(fs::metadata(root.join("entry")).unwrap().is_file());
+    let real = mount.source().join("entry");
+    fs::remove_file(&real).unwrap();
+    fs::create_dir(&real).unwrap();
+    let result = fs::File::open(root.join("entry"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EISDIR));
+}
+
+#[test]
+fn sizes_follow_small_sequential_writes() {
+    let mount = match Mount::new(&["--write-buffer", "65536"], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // With the writes coalesced within decofs in place of the kernel's writeback cache.
+    let path = mount.mountpoint().join("file");
+    let mut file = fs::File::create(&path).unwrap();
+    for i in 1..=50u64 {
+        file.write_all(&[i as u8; 100]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), i * 100);
+        assert_eq!(file.metadata().unwrap().len(), i * 100);
+    }
+    let content = fs::read(mount.source().join("file")).unwrap();
+    assert_eq!(content.len(), 5000);
+    assert!(content
+        .chunks(100)
+        .zip(1u8..)
+        .all(|(chunk, i)| chunk.iter().all(|&b| b == i)));
+}
+
+#[test]
+fn relative_symlinks_resolve_and_absolute_ones_are_rewritten_on_request() {
+    let setup = |source: &Path| {
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("b"), "target").unwrap();
+        symlink("../b", source.join("dir/relative")).unwrap();
+        symlink(source.join("b"), source.join("dir/absolute")).unwrap();
+    };
+    let mount = match Mount::new(&[], setup) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let dir = mount.mountpoint().join("dir");
+    assert_eq!(
+        fs::read_link(dir.join("relative")).unwrap(),
+        Path::new("../b")
+    );
+    assert_eq!(fs::read_to_string(dir.join("relative")).unwrap(), "target");
+    assert_eq!(
+        fs::read_link(dir.join("absolute")).unwrap(),
+        mount.source().join("b")
+    );
+    drop(mount);
+
+    let mount = match Mount::new(&["--relative-links"], setup) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let dir = mount.mountpoint().join("dir");
+    assert_eq!(
+        fs::read_link(dir.join("relative")).unwrap(),
+        Path::new("../b")
+    );
+    assert_eq!(
+        fs::read_link(dir.join("absolute")).unwrap(),
+        Path::new("../b")
+    );
+    assert_eq!(fs::read_to_string(dir.join("absolute")).unwrap(), "target");
+}
+
+#[test]
+fn reads_from_a_slow_source_time_out() {
+    // Another mount, throttled to a few KiB a second, stands in for a slow network filesystem.
+    let slow = match Mount::new(&["--read-limit", "4096", "--direct-io"], |source| {
+        fs::write(source.join("file"), vec![1u8; 16 << 10]).unwrap();
+    }) {
+        Some(slow) => slow,
+        None => return,
+    };
+    let lower = slow.mountpoint();
+    let args = ["--op-timeout", "200", "--direct-io", "--readahead", "65536"];
+    let mount = match Mount::new(
+        &[&args[..], &["--union", lower.to_str().unwrap()]].concat(),
+        |_| {},
+    ) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // The first read is within the throttled mount's burst; the next waits on the read ahead,
+    // then on the source itself, which both take far longer than the timeout.
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    let mut buf = vec![0; 4096];
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 4096);
+    let started = std::time::Instant::now();
+    let result = file.read_at(&mut buf, 4096);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ETIMEDOUT));
+    assert!(started.elapsed() < std::time::Duration::from_millis(900));
+}
+
+#[test]
+fn df_sizes_match_the_source_filesystem() {
+    let mount = match Mount::new(&[], |_| {}) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let (stat, source) = (statvfs(&mount.mountpoint()), statvfs(&mount.source()));
+    assert!(stat.f_frsize > 0 && stat.f_bsize > 0);
+    assert_eq!(stat.f_namemax, source.f_namemax);
+    assert_eq!(
+        stat.f_blocks * stat.f_frsize,
+        source.f_blocks * source.f_frsize
+    );
+
+    let df = |path: &Path| {
+        let output = Command::new("df")
+            .args(["-B1", "--output=size"])
+            .arg(path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let size = df(&mount.mountpoint());
+    assert_eq!(size, df(&mount.source()));
+    let bytes = size.lines().nth(1).unwrap().trim().parse::<u64>().unwrap();
+    assert!(bytes > 0, "{}", size);
+}
+
+#[test]
+fn control_socket_answers_commands() {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+
+    let args = [
+        "--control-socket",
+        "control",
+        "--readahead",
+        "65536",
+        "--direct-io",
+        "--case-insensitive",
+        "--ttl",
+        "60000",
+    ];
+    let mut mount = match Mount::new(&args, |source| {
+        fs::write(source.join("file"), "content").unwrap();
+        fs::write(source.join("streamed"), vec![b'a'; 1 << 20]).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    fs::read(mount.mountpoint().join("file")).unwrap();
+    // Sequential reads start a read ahead, and a lookup by case lists the directory.
+    let streamed = fs::File::open(mount.mountpoint().join("streamed")).unwrap();
+    let mut block = [0u8; 4096];
+    for offset in [0, 4096] {
+        streamed.read_exact_at(&mut block, offset).unwrap();
+    }
+    assert!(!mount.mountpoint().join("NEW").exists());
+    let socket = mount.source().parent().unwrap().join("control");
+    let stream = UnixStream::connect(&socket).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut lines = BufReader::new(stream).lines().map(Result::unwrap);
+    let mut reply = |command: &str| {
+        writeln!(writer, "{}", command).unwrap();
+        let mut output = vec![];
+        loop {
+            let line = lines.next().unwrap();
+            if line == "ok" || line.starts_with("error: ") {
+                return (output, line);
+            }
+            output.push(line);
+        }
+    };
+
+    let (stats, status) = reply("stats");
+    assert_eq!(status, "ok");
+    let opens: u64 = stats
+        .iter()
+        .find_map(|line| line.strip_prefix("open ")?.parse().ok())
+        .unwrap_or_else(|| panic!("no open counter in {:?}", stats));
+    assert!(opens >= 1);
+    // Changes made behind the mount are only seen once the caches are flushed.
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    fs::write(mount.source().join("streamed"), vec![b'b'; 1 << 20]).unwrap();
+    fs::write(mount.source().join("new"), "").unwrap();
+    assert_eq!(reply("flush-cache"), (vec![], "ok".to_string()));
+    streamed.read_exact_at(&mut block, 8192).unwrap();
+    assert_eq!(block, [b'b'; 4096]);
+    assert!(mount.mountpoint().join("NEW").exists());
+    drop(streamed);
+    assert!(reply("reload-config").1.starts_with("error: "));
+    assert!(reply("no-such-command").1.starts_with("error: "));
+
+    // Unmounting isn't held up by a client still connected.
+    assert!(mount.stop(libc::SIGTERM).success());
+    assert!(!socket.exists());
+}
+
+#[test]
+fn truncating_opens_and_ftruncate_set_the_length() {
+    let mount = match Mount::new(&[], |source| {
+        fs::write(source.join("file"), "some content").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let path = mount.mountpoint().join("file");
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    assert_eq!(fs::metadata(mount.source().join("file")).unwrap().len(), 0);
+
+    file.set_len(100).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 100);
+    file.set_len(3).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 3);
+    assert_eq!(fs::read(mount.source().join("file")).unwrap(), [0; 3]);
+}
+
+#[test]
+fn parallel_reads_are_passed_back_in_order() {
+    let expected = pattern();
+    let mount = match Mount::new(&["--parallel-read", "4096", "--direct-io"], |source| {
+        fs::write(source.join("file"), pattern()).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    // Unaligned and running past end-of-file, so that the pieces are uneven and the last short.
+    let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+    let mut buf = vec![0; 128 << 10];
+    for offset in [0, 4097, expected.len() - 10_000] {
+        let n = file.read_at(&mut buf, offset as u64).unwrap();
+        assert_eq!(n, buf.len().min(expected.len() - offset));
+        assert!(buf[..n] == expected[offset..offset + n], "at {}", offset);
+    }
+}
+
+#[test]
+fn cached_directory_listings_are_not_read_again() {
+    let readdirs_per_listing = |args: &[&str]| {
+        let args = [args, &["--stats-file"]].concat();
+        let mount = Mount::new(&args, |source| {
+            for i in 0..10 {
+                fs::write(source.join(i.to_string()), "").unwrap();
+            }
+        })?;
+        let root = mount.mountpoint();
+        listing(&root);
+        let before = counter(&root, "readdir");
+        // The ten files and the stats file itself.
+        for _ in 0..5 {
+            assert_eq!(listing(&root).len(), 11);
+        }
+        Some(counter(&root, "readdir") - before)
+    };
+
+    let uncached = match readdirs_per_listing(&[]) {
+        Some(readdirs) => readdirs,
+        None => return,
+    };
+    assert!(uncached >= 5, "{} readdirs", uncached);
+    assert_eq!(readdirs_per_listing(&["--cache-dirs"]), Some(0));
+}
+
+#[test]
+fn find_xdev_descends_into_every_subdirectory() {
+    let mount = match Mount::new(&[], |source| {
+        fs::create_dir_all(source.join("a/b/c")).unwrap();
+        fs::write(source.join("a/file"), "").unwrap();
+        fs::write(source.join("a/b/c/file"), "").unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let found = |path: &Path| {
+        let output = Command::new("find")
+            .arg(path)
+            .arg("-xdev")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output
+            .stdout
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .count()
+    };
+    // The roots, a, a/file, a/b, a/b/c and a/b/c/file.
+    assert_eq!(found(&mount.mountpoint()), 6);
+    assert_eq!(found(&mount.source()), 6);
+}
+
+#[test]
+fn shared_handle_reads_are_correct_however_they_are_served() {
+    let expected = pattern();
+    for args in [
+        &["--direct-io", "--readahead", "65536"][..],
+        &["--direct-io", "--parallel-read", "1024"][..],
+    ] {
+        let mount = match Mount::new(args, |source| {
+            fs::write(source.join("file"), pattern()).unwrap();
+        }) {
+            Some(mount) => mount,
+            None => return,
+        };
+
+        // Each thread reads its own stretch sequentially, so that on the one handle sequential
+        // runs are interleaved with jumps.
+        let file = fs::File::open(mount.mountpoint().join("file")).unwrap();
+        let stretch = expected.len() / 8;
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let (file, expected) = (&file, &expected);
+                scope.spawn(move || {
+                    let mut buf = vec![0; 4000];
+                    for offset in (thread * stretch..(thread + 1) * stretch).step_by(buf.len()) {
+                        let n = file.read_at(&mut buf, offset as u64).unwrap();
+                        let end = (offset + buf.len()).min(expected.len());
+                        assert_eq!(n, end - offset, "{:?} at {}", args, offset);
+                        assert!(
+                            buf[..n] == expected[offset..end],
+                            "{:?} at {}",
+                            args,
+                            offset
+                        );
+                    }
+                });
+            }
+        });
+    }
+}
+
+#[test]
+fn followed_symlinks_show_as_their_targets() {
+    let mount = match Mount::new(&["--follow-symlinks"], |source| {
+        fs::write(source.join("file"), "target").unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+        symlink("file", source.join("to-file")).unwrap();
+        symlink("dir", source.join("to-dir")).unwrap();
+        symlink("absent", source.join("dangling")).unwrap();
+    }) {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    let root = mount.mountpoint();
+    let listed = listing(&root);
+    let names = listed
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["dir", "file", "to-dir", "to-file"]);
+    assert!(listed[2].1.is_dir() && listed[3].1.is_file());
+    let metadata = fs::symlink_metadata(root.join("to-file")).unwrap();
+    assert!(metadata.file_type().is_file());
+    assert_eq!(metadata.len(), 6);
+    assert_eq!(fs::read_to_string(root.join("to-file")).unwrap(), "target");
+    let result = fs::symlink_metadata(root.join("dangling"));
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+
+    // Removing acts on the link, not its target.
+    fs::remove_file(root.join("to-file")).unwrap();
+    assert!(fs::symlink_metadata(mount.source().join("to-file")).is_err());
+    assert!(mount.source().join("file").exists());
+}
+
+#[test]
+fn generated_files_can_be_fsynced() {
+    for args in [&[][..], &["--fsync-batch", "10", "--threads", "2"][..]] {
+        let mount = match Mount::new(&[args, &["--stats-file"]].concat(), |_| {}) {
+            Some(mount) => mount,
+            None => return,
+        };
+        for name in [".decofs-info", ".decofs-stats"] {
+            let file = fs::File::open(mount.mountpoint().join(name)).unwrap();
+            file.sync_all().unwrap();
+            file.sync_data().unwrap();
+        }
+    }
+}
+
+#[test]
+fn decoded_files_can_be_fsynced_and_have_their_times_set() {
+    for args in [&[][..], &["--fsync-batch", "10", "--threads", "2"][..]] {
+        let mount = match Mount::new(&[args, &["--gunzip"]].concat(), |source| {
+            fs::write(source.join("hello.gz"), HELLO_GZ).unwrap();
+        }) {
+            Some(mount) => mount,
+            None => return,
+        };
+        let file = fs::File::open(mount.mountpoint().join("hello.gz")).unwrap();
+        file.sync_all().unwrap();
+        file.sync_data().unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 5);
+        file.set_modified(mtime).unwrap();
+        let metadata = fs::metadata(mount.source().join("hello.gz")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), mtime);
+    }
+}